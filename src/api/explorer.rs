@@ -0,0 +1,714 @@
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::block::{Block, BlockHeader};
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{SignedTransaction, UTXO_input, UTXO_output};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Read-only views of the chain for the block explorer endpoints. Everything is computed from the
+// blockchain alone, so the handlers only take its read lock; mempool transactions are copied out
+// by the caller and passed in. Hashes and addresses are strings in the format `Display` writes.
+
+pub const DEFAULT_PAGE_SIZE: usize = 25;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Reasons an explorer query has no answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExplorerError {
+    BlockNotFound(String),
+    HeightNotFound(u64),
+    TransactionNotFound(String),
+    InvalidHash(String),
+    InvalidAddress(String),
+    InvalidHeight(String),
+    InvalidPage(String),
+}
+
+impl std::fmt::Display for ExplorerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExplorerError::BlockNotFound(h) => write!(f, "block {} not found", h),
+            ExplorerError::HeightNotFound(n) => {
+                write!(f, "no block at height {} in the longest chain", n)
+            }
+            ExplorerError::TransactionNotFound(h) => write!(f, "transaction {} not found", h),
+            ExplorerError::InvalidHash(e) => write!(f, "error parsing hash: {}", e),
+            ExplorerError::InvalidAddress(e) => write!(f, "error parsing address: {}", e),
+            ExplorerError::InvalidHeight(e) => write!(f, "error parsing height: {}", e),
+            ExplorerError::InvalidPage(e) => write!(f, "error parsing page: {}", e),
+        }
+    }
+}
+
+impl ExplorerError {
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            ExplorerError::BlockNotFound(_)
+                | ExplorerError::HeightNotFound(_)
+                | ExplorerError::TransactionNotFound(_)
+        )
+    }
+}
+
+pub fn parse_hash(s: &str) -> Result<H256, ExplorerError> {
+    s.parse::<H256>()
+        .map_err(|e| ExplorerError::InvalidHash(e.to_string()))
+}
+
+/// Parse an address of the network whose addresses start with `prefix`
+pub fn parse_address(s: &str, prefix: &str) -> Result<Address, ExplorerError> {
+    Address::decode(s, prefix)
+        .map_err(|e| ExplorerError::InvalidAddress(e.to_string()))
+}
+
+/// Which items of a list to return, from the `offset` and `limit` query parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+impl Page {
+    /// Every item at once
+    pub fn all() -> Self {
+        Page {
+            offset: 0,
+            limit: usize::MAX,
+        }
+    }
+
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, ExplorerError> {
+        let mut page = Page::default();
+        if let Some(v) = params.get("offset") {
+            page.offset = v
+                .parse()
+                .map_err(|e| ExplorerError::InvalidPage(format!("offset: {}", e)))?;
+        }
+        if let Some(v) = params.get("limit") {
+            page.limit = v
+                .parse()
+                .map_err(|e| ExplorerError::InvalidPage(format!("limit: {}", e)))?;
+        }
+        page.limit = page.limit.clamp(1, MAX_PAGE_SIZE);
+        Ok(page)
+    }
+
+    fn apply<T>(&self, items: Vec<T>) -> Paginated<T> {
+        let total = items.len();
+        Paginated {
+            total,
+            offset: self.offset,
+            limit: self.limit,
+            items: items
+                .into_iter()
+                .skip(self.offset)
+                .take(self.limit)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Paginated<T> {
+    /// number of items on every page together
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub items: Vec<T>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HeaderView {
+    pub hash: String,
+    pub parent: String,
+    pub nonce: u32,
+    pub difficulty: String,
+    pub timestamp: u128,
+    pub merkle_root: String,
+}
+
+impl From<&BlockHeader> for HeaderView {
+    fn from(header: &BlockHeader) -> Self {
+        HeaderView {
+            hash: header.hash().to_string(),
+            parent: header.parent.to_string(),
+            nonce: header.nonce,
+            difficulty: header.difficulty.to_string(),
+            timestamp: header.timestamp,
+            merkle_root: header.merkle_root.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BlockView {
+    pub hash: String,
+    pub height: u64,
+    /// blocks from it to the tip, itself included; 0 for blocks off the longest chain
+    pub confirmations: u64,
+    pub in_longest_chain: bool,
+    /// bytes serialized
+    pub size: u64,
+    pub header: HeaderView,
+    /// fees of its transactions whose inputs are all known
+    pub fees: u64,
+    pub transactions: Paginated<TransactionView>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct InputView {
+    pub prev_tx_hash: String,
+    pub index: u8,
+    /// of the output spent, when it is in the longest chain
+    pub address: Option<String>,
+    pub value: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OutputView {
+    pub index: u8,
+    pub address: String,
+    pub value: u64,
+    /// hex encoded locking script, if not a plain payment to the address
+    pub script: Option<String>,
+    /// whether the longest chain spends it, unknown for transactions off it
+    pub spent: Option<bool>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TransactionView {
+    pub hash: String,
+    /// block holding it in the longest chain, none while in the mempool
+    pub block: Option<String>,
+    pub height: Option<u64>,
+    /// position in its block
+    pub position: Option<usize>,
+    pub confirmations: u64,
+    pub size: u64,
+    /// none for transactions without inputs, or whose inputs are not all known
+    pub fee: Option<u64>,
+    /// creates coins without inputs: coinbases and genesis allocations
+    pub coinbase: bool,
+    pub lock_time: u64,
+    pub inputs: Vec<InputView>,
+    pub outputs: Vec<OutputView>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AddressTransactionView {
+    pub hash: String,
+    pub block: String,
+    pub height: u64,
+    pub confirmations: u64,
+    /// paid to the address by this transaction
+    pub received: u64,
+    /// spent from the address by this transaction
+    pub sent: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct AddressView {
+    pub address: String,
+    /// unspent outputs in the longest chain
+    pub balance: u64,
+    pub utxo_count: usize,
+    pub total_received: u64,
+    pub total_sent: u64,
+    /// newest first
+    pub transactions: Paginated<AddressTransactionView>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChainInfo {
+    pub network: String,
+    pub height: u64,
+    pub tip: String,
+    pub genesis: String,
+    pub target: String,
+    pub median_time_past: u128,
+    /// blocks stored, forks included
+    pub known_blocks: usize,
+    pub mempool_transactions: usize,
+}
+
+/// Where a transaction of the longest chain is
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    block: H256,
+    height: u64,
+    position: usize,
+}
+
+/// The longest chain, with its transactions by hash to resolve the outputs inputs spend unless
+/// the blockchain keeps an index of them
+struct Chain<'a> {
+    blockchain: &'a Blockchain,
+    tip_height: u64,
+    transactions: HashMap<H256, Location>,
+}
+
+impl<'a> Chain<'a> {
+    fn new(blockchain: &'a Blockchain) -> Self {
+        let mut transactions = HashMap::new();
+        if blockchain.index.is_none() {
+            for hash in longest_chain(blockchain) {
+                let height = blockchain.lengths[&hash] as u64;
+                let content = &blockchain.blocks[&hash].content.content;
+                for (position, tx) in content.iter().enumerate() {
+                    let location = Location {
+                        block: hash,
+                        height,
+                        position,
+                    };
+                    transactions.insert(tx.hash(), location);
+                }
+            }
+        }
+        Chain {
+            blockchain,
+            tip_height: blockchain.tip_state().height,
+            transactions,
+        }
+    }
+
+    /// Where a transaction is in the longest chain, if it is
+    fn locate(&self, hash: &H256) -> Option<Location> {
+        match &self.blockchain.index {
+            Some(index) => index.transaction(hash).map(|l| Location {
+                block: l.block,
+                height: self.blockchain.lengths[&l.block] as u64,
+                position: l.position,
+            }),
+            None => self.transactions.get(hash).cloned(),
+        }
+    }
+
+    fn transaction(&self, location: &Location) -> &'a SignedTransaction {
+        &self.blockchain.blocks[&location.block].content.content[location.position]
+    }
+
+    /// Output spent by `input`, if created in the longest chain
+    fn spent_output(&self, input: &UTXO_input) -> Option<&'a UTXO_output> {
+        let location = self.locate(&input.prev_tx_hash)?;
+        self.transaction(&location)
+            .transcation
+            .output
+            .get(input.index as usize)
+    }
+
+    fn address_transaction(
+        &self,
+        hash: H256,
+        location: Location,
+        received: u64,
+        sent: u64,
+    ) -> AddressTransactionView {
+        AddressTransactionView {
+            hash: hash.to_string(),
+            block: location.block.to_string(),
+            height: location.height,
+            confirmations: self.confirmations(location.height),
+            received,
+            sent,
+        }
+    }
+
+    fn confirmations(&self, height: u64) -> u64 {
+        self.tip_height + 1 - height
+    }
+
+    fn in_longest_chain(&self, block: &H256) -> bool {
+        if *block == self.blockchain.genesis {
+            return true;
+        }
+        let height = self.blockchain.lengths[block] as usize;
+        self.blockchain.all_blocks_in_longest_chain().get(height - 1) == Some(block)
+    }
+
+    fn fee(&self, tx: &SignedTransaction) -> Option<u64> {
+        if tx.transcation.input.is_empty() {
+            return None;
+        }
+        let mut input = 0u64;
+        for i in tx.transcation.input.iter() {
+            input = input.saturating_add(self.spent_output(i)?.value);
+        }
+        let output = tx
+            .transcation
+            .output
+            .iter()
+            .fold(0u64, |acc, o| acc.saturating_add(o.value));
+        Some(input.saturating_sub(output))
+    }
+
+    /// `location` is where the transaction is in the longest chain, if it is
+    fn view(&self, tx: &SignedTransaction, location: Option<Location>) -> TransactionView {
+        let hash = tx.hash();
+        let prefix = self.blockchain.params.address_prefix;
+        let inputs = tx
+            .transcation
+            .input
+            .iter()
+            .map(|input| {
+                let spent = self.spent_output(input);
+                InputView {
+                    prev_tx_hash: input.prev_tx_hash.to_string(),
+                    index: input.index,
+                    address: spent.map(|o| o.receipient_address.encode(prefix)),
+                    value: spent.map(|o| o.value),
+                }
+            })
+            .collect();
+        let state = self.blockchain.tip_state();
+        let outputs = tx
+            .transcation
+            .output
+            .iter()
+            .enumerate()
+            .map(|(index, output)| {
+                let outpoint = UTXO_input {
+                    prev_tx_hash: hash,
+                    index: index as u8,
+                };
+                OutputView {
+                    index: index as u8,
+                    address: output.receipient_address.encode(prefix),
+                    value: output.value,
+                    script: output.script.as_ref().map(|s| hex::encode(&s.0)),
+                    spent: location.map(|_| !state.utxos.contains_key(&outpoint)),
+                }
+            })
+            .collect();
+        TransactionView {
+            hash: hash.to_string(),
+            block: location.map(|l| l.block.to_string()),
+            height: location.map(|l| l.height),
+            position: location.map(|l| l.position),
+            confirmations: location.map_or(0, |l| self.confirmations(l.height)),
+            size: tx.size(),
+            fee: self.fee(tx),
+            coinbase: tx.transcation.input.is_empty(),
+            lock_time: tx.transcation.lock_time,
+            inputs,
+            outputs,
+        }
+    }
+
+    fn block(&self, block: &Block, page: Page) -> BlockView {
+        let hash = block.hash();
+        let height = self.blockchain.lengths[&hash] as u64;
+        let in_longest_chain = self.in_longest_chain(&hash);
+        let transactions: Vec<TransactionView> = block
+            .content
+            .content
+            .iter()
+            .enumerate()
+            .map(|(position, tx)| {
+                let location = Location {
+                    block: hash,
+                    height,
+                    position,
+                };
+                self.view(tx, Some(location).filter(|_| in_longest_chain))
+            })
+            .collect();
+        let fees = transactions.iter().filter_map(|tx| tx.fee).sum();
+        BlockView {
+            hash: hash.to_string(),
+            height,
+            confirmations: if in_longest_chain {
+                self.confirmations(height)
+            } else {
+                0
+            },
+            in_longest_chain,
+            size: bincode::serialize(block).unwrap().len() as u64,
+            header: HeaderView::from(&block.header),
+            fees,
+            transactions: page.apply(transactions),
+        }
+    }
+}
+
+/// A block by hash, longest chain or not
+pub fn block(blockchain: &Blockchain, hash: H256, page: Page) -> Result<BlockView, ExplorerError> {
+    let block = blockchain
+        .blocks
+        .get(&hash)
+        .ok_or_else(|| ExplorerError::BlockNotFound(hash.to_string()))?;
+    Ok(Chain::new(blockchain).block(block, page))
+}
+
+/// The block of the longest chain at `height`, the genesis block being at 0
+pub fn block_at_height(
+    blockchain: &Blockchain,
+    height: u64,
+    page: Page,
+) -> Result<BlockView, ExplorerError> {
+    let hash = if height == 0 {
+        blockchain.genesis
+    } else {
+        *blockchain
+            .all_blocks_in_longest_chain()
+            .get(height as usize - 1)
+            .ok_or(ExplorerError::HeightNotFound(height))?
+    };
+    block(blockchain, hash, page)
+}
+
+/// A transaction of the longest chain
+pub fn transaction(blockchain: &Blockchain, hash: H256) -> Result<TransactionView, ExplorerError> {
+    let chain = Chain::new(blockchain);
+    let location = chain
+        .locate(&hash)
+        .ok_or_else(|| ExplorerError::TransactionNotFound(hash.to_string()))?;
+    Ok(chain.view(chain.transaction(&location), Some(location)))
+}
+
+/// A transaction of the longest chain as it is stored
+pub fn raw_transaction(blockchain: &Blockchain, hash: H256) -> Option<&SignedTransaction> {
+    let chain = Chain::new(blockchain);
+    let location = chain.locate(&hash)?;
+    Some(chain.transaction(&location))
+}
+
+/// A transaction of the mempool, with the fee the mempool recorded for it
+pub fn unconfirmed_transaction(
+    blockchain: &Blockchain,
+    tx: &SignedTransaction,
+    fee: u64,
+) -> TransactionView {
+    let mut view = Chain::new(blockchain).view(tx, None);
+    view.fee = Some(fee);
+    view
+}
+
+/// Balance and history of an address in the longest chain
+pub fn address(
+    blockchain: &Blockchain,
+    address: Address,
+    page: Page,
+) -> Result<AddressView, ExplorerError> {
+    let chain = Chain::new(blockchain);
+    let (mut history, balance, utxo_count) = match &blockchain.index {
+        Some(index) => {
+            // amounts received and sent by each transaction, from the outputs paying the address
+            let mut amounts: HashMap<H256, (u64, u64)> = HashMap::new();
+            let (mut balance, mut utxo_count) = (0u64, 0);
+            for (outpoint, output) in index.outputs_of(&address) {
+                let received = &mut amounts.entry(outpoint.prev_tx_hash).or_default().0;
+                *received = received.saturating_add(output.value);
+                match output.spent_by {
+                    Some(tx) => {
+                        let sent = &mut amounts.entry(tx).or_default().1;
+                        *sent = sent.saturating_add(output.value);
+                    }
+                    None => {
+                        balance = balance.saturating_add(output.value);
+                        utxo_count += 1;
+                    }
+                }
+            }
+            let mut history: Vec<(Location, AddressTransactionView)> = amounts
+                .into_iter()
+                .filter_map(|(hash, (received, sent))| {
+                    let location = chain.locate(&hash)?;
+                    Some((
+                        location,
+                        chain.address_transaction(hash, location, received, sent),
+                    ))
+                })
+                .collect();
+            history.sort_by_key(|(l, _)| (l.height, l.position));
+            let history = history.into_iter().map(|(_, view)| view).collect();
+            (history, balance, utxo_count)
+        }
+        None => {
+            let mut history = Vec::new();
+            for hash in longest_chain(blockchain) {
+                let height = blockchain.lengths[&hash] as u64;
+                let content = &blockchain.blocks[&hash].content.content;
+                for (position, tx) in content.iter().enumerate() {
+                    let received = tx
+                        .transcation
+                        .output
+                        .iter()
+                        .filter(|o| o.receipient_address == address)
+                        .fold(0u64, |acc, o| acc.saturating_add(o.value));
+                    let sent = tx
+                        .transcation
+                        .input
+                        .iter()
+                        .filter_map(|i| chain.spent_output(i))
+                        .filter(|o| o.receipient_address == address)
+                        .fold(0u64, |acc, o| acc.saturating_add(o.value));
+                    if received > 0 || sent > 0 {
+                        let location = Location {
+                            block: hash,
+                            height,
+                            position,
+                        };
+                        let view = chain.address_transaction(tx.hash(), location, received, sent);
+                        history.push(view);
+                    }
+                }
+            }
+            let utxos = blockchain.tip_state().utxos_of(&address);
+            let balance = utxos
+                .iter()
+                .fold(0u64, |acc, (_, o)| acc.saturating_add(o.value));
+            (history, balance, utxos.len())
+        }
+    };
+    history.reverse();
+    Ok(AddressView {
+        address: address.encode(blockchain.params.address_prefix),
+        balance,
+        utxo_count,
+        total_received: history.iter().map(|t| t.received).sum(),
+        total_sent: history.iter().map(|t| t.sent).sum(),
+        transactions: page.apply(history),
+    })
+}
+
+/// Blocks of the longest chain, from the genesis block to the tip
+fn longest_chain(blockchain: &Blockchain) -> impl Iterator<Item = H256> {
+    std::iter::once(blockchain.genesis).chain(blockchain.all_blocks_in_longest_chain())
+}
+
+pub fn chain_info(blockchain: &Blockchain, mempool_transactions: usize) -> ChainInfo {
+    let state = blockchain.tip_state();
+    ChainInfo {
+        network: blockchain.params.name.to_string(),
+        height: state.height,
+        tip: blockchain.tip().to_string(),
+        genesis: blockchain.genesis.to_string(),
+        target: blockchain.target().to_string(),
+        median_time_past: state.median_time_past,
+        known_blocks: blockchain.blocks.len(),
+        mempool_transactions,
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use crate::blockchain::params::ChainParams;
+    use crate::miner;
+    use crate::types::transaction::Mempool;
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn explore_blocks_transactions_and_addresses() {
+        let mut wallet = Wallet::from_seed(&[9u8; 32]);
+        let payer = wallet.generate_key("payer").unwrap();
+        let payee: Address = [4u8; 20].into();
+        let miner_address: Address = [5u8; 20].into();
+        let mut params = ChainParams::regtest();
+        params.genesis.allocations = vec![Allocation {
+            address: payer.encode(params.address_prefix),
+            value: 10_000,
+        }];
+        let mut blockchain = Blockchain::with_params(params).unwrap();
+
+        // one block paying the payee with a fee, on top of an empty one
+        let mut mempool = Mempool::new();
+        let state = blockchain.tip_state().clone();
+        let tx = wallet
+            .create_transaction(&state, &mempool, payee, 3000, 50)
+            .unwrap();
+        mempool.insert(&tx, state.fee(&tx)).unwrap();
+        for _ in 0..2 {
+            let parent = blockchain.tip();
+            let block = miner::template(
+                parent,
+                blockchain.tip_state(),
+                &blockchain.params,
+                blockchain.target(),
+                &mempool,
+                Some(miner_address),
+                0,
+            );
+            blockchain.insert(&block);
+            mempool.prune(blockchain.tip_state());
+        }
+        let first = blockchain.all_blocks_in_longest_chain()[0];
+
+        let view = block_at_height(&blockchain, 1, Page::default()).unwrap();
+        assert_eq!(view.hash, first.to_string());
+        assert_eq!(view.confirmations, 2);
+        assert_eq!(view.fees, 50);
+        assert_eq!(view.transactions.total, 2);
+        assert!(view.transactions.items[0].coinbase);
+        assert_eq!(view.transactions.items[0].outputs[0].value, 1_000_050);
+        let page = Page {
+            offset: 1,
+            limit: 1,
+        };
+        let view = block(&blockchain, first, page).unwrap();
+        assert_eq!(view.transactions.items.len(), 1);
+        assert_eq!(view.transactions.items[0].hash, tx.hash().to_string());
+        assert!(matches!(
+            block_at_height(&blockchain, 3, Page::default()),
+            Err(ExplorerError::HeightNotFound(3))
+        ));
+
+        let view = transaction(&blockchain, tx.hash()).unwrap();
+        assert_eq!(view.fee, Some(50));
+        assert_eq!(view.position, Some(1));
+        assert_eq!(view.confirmations, 2);
+        assert_eq!(
+            view.inputs[0].address,
+            Some(payer.encode(blockchain.params.address_prefix))
+        );
+        assert_eq!(view.inputs[0].value, Some(10_000));
+        assert_eq!(view.outputs[0].spent, Some(false));
+        assert!(transaction(&blockchain, H256::default()).is_err());
+
+        let view = address(&blockchain, payer, Page::default()).unwrap();
+        // the change of the payment comes back to the payer
+        let change = 10_000 - 3000 - 50;
+        assert_eq!(view.total_received, 10_000 + change);
+        assert_eq!(view.total_sent, 10_000);
+        assert_eq!(view.balance, change);
+        // the payment first, then the allocation
+        assert_eq!(view.transactions.total, 2);
+        assert_eq!(view.transactions.items[0].hash, tx.hash().to_string());
+        assert_eq!(view.transactions.items[1].height, 0);
+
+        let info = chain_info(&blockchain, 0);
+        assert_eq!(info.height, 2);
+        assert_eq!(info.network, "regtest");
+        assert_eq!(parse_hash(&info.tip).unwrap(), blockchain.tip());
+
+        // the indexes give the same answers as scanning the chain
+        let views = |blockchain: &Blockchain| {
+            serde_json::to_string(&(
+                block(blockchain, first, Page::default()).unwrap(),
+                transaction(blockchain, tx.hash()).unwrap(),
+                address(blockchain, payer, Page::default()).unwrap(),
+                address(blockchain, miner_address, Page::default()).unwrap(),
+            ))
+            .unwrap()
+        };
+        let scanned = views(&blockchain);
+        blockchain.enable_index();
+        assert_eq!(views(&blockchain), scanned);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,349 @@
+use super::explorer::{self, ExplorerError, TransactionView};
+use crate::blockchain::Blockchain;
+use crate::events::EventBus;
+use crate::network::message::Message;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::types::hash::{Hashable, H256};
+use crate::types::state::TransactionError;
+use crate::types::transaction::{Mempool, MempoolError, SignedTransaction, MAX_PACKAGE_SIZE};
+use log::debug;
+use serde::Serialize;
+use std::sync::{Mutex, RwLock};
+
+// Handlers shared by the REST routes and the JSON-RPC methods. They take the shared state of the
+// node and lock it themselves, never holding the blockchain and mempool locks at the same time.
+
+/// Largest request body a transaction is read from. The mempool never takes a transaction larger
+/// than a package, and its JSON encoding takes up to four characters per byte.
+pub const MAX_TRANSACTION_BODY: u64 = 4 * MAX_PACKAGE_SIZE + 1024;
+
+/// Reasons a transaction handed to the node is turned down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    /// not a transaction in the encoding given
+    Decode(String),
+    /// sent in a body larger than `MAX_TRANSACTION_BODY`, it is not read
+    TooLarge,
+    /// not valid on top of the longest chain and the mempool
+    Invalid(TransactionError),
+    /// valid, but the mempool does not take it
+    Rejected(MempoolError),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Decode(e) => write!(f, "error decoding transaction: {}", e),
+            SubmitError::TooLarge => write!(
+                f,
+                "transaction body larger than {} bytes",
+                MAX_TRANSACTION_BODY
+            ),
+            SubmitError::Invalid(e) => write!(f, "invalid transaction: {}", e),
+            SubmitError::Rejected(e) => write!(f, "transaction rejected: {}", e),
+        }
+    }
+}
+
+impl SubmitError {
+    /// Short name of the reason, for scripts to tell rejections apart without parsing messages
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SubmitError::Decode(_) => "decode",
+            SubmitError::TooLarge => "too_large",
+            SubmitError::Invalid(e) => match e {
+                TransactionError::InvalidSignature(_) => "invalid_signature",
+                TransactionError::NoInputs => "no_inputs",
+                TransactionError::TooManyOutputs(_) => "too_many_outputs",
+                TransactionError::DuplicateInput(_) => "duplicate_input",
+                TransactionError::MissingInput(_) => "missing_input",
+                TransactionError::WrongOwner(_) => "wrong_owner",
+                TransactionError::Overspend { .. } => "overspend",
+                TransactionError::WitnessCount { .. } => "witness_count",
+                TransactionError::NotEnoughSignatures { .. } => "not_enough_signatures",
+                TransactionError::InvalidScript(_) => "invalid_script",
+                TransactionError::Script { .. } => "script_failed",
+                TransactionError::SequenceCount { .. } => "sequence_count",
+                TransactionError::NonFinal(_) => "non_final",
+                TransactionError::SequenceLock(_) => "sequence_lock",
+                TransactionError::InvalidCoinbase => "invalid_coinbase",
+            },
+            SubmitError::Rejected(e) => match e {
+                MempoolError::AlreadyKnown => "already_known",
+                MempoolError::Conflict { .. } => "conflict",
+                MempoolError::InsufficientFee { .. } => "insufficient_fee",
+                MempoolError::InsufficientFeeRate { .. } => "insufficient_fee_rate",
+                MempoolError::AncestorLimit { .. } => "ancestor_limit",
+                MempoolError::DescendantLimit { .. } => "descendant_limit",
+            },
+        }
+    }
+}
+
+/// How a transaction handed to the node is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// bincode serialization, as relayed between peers
+    Bincode,
+    /// hex encoding of the bincode serialization
+    Hex,
+    /// serde JSON of `SignedTransaction`
+    Json,
+}
+
+impl Encoding {
+    /// Guess the encoding of a request body from its content type, then from its first byte
+    pub fn detect(content_type: Option<&str>, body: &[u8]) -> Self {
+        match content_type.map(|t| t.split(';').next().unwrap_or("").trim()) {
+            Some("application/octet-stream") => Encoding::Bincode,
+            Some("application/json") => Encoding::Json,
+            _ => match body.iter().find(|b| !b.is_ascii_whitespace()) {
+                Some(b'{') => Encoding::Json,
+                _ => Encoding::Hex,
+            },
+        }
+    }
+}
+
+pub fn decode_transaction(
+    bytes: &[u8],
+    encoding: Encoding,
+) -> Result<SignedTransaction, SubmitError> {
+    let decode_error = |e: &dyn std::fmt::Display| SubmitError::Decode(e.to_string());
+    match encoding {
+        Encoding::Bincode => bincode::deserialize(bytes).map_err(|e| decode_error(&e)),
+        Encoding::Hex => {
+            let s = std::str::from_utf8(bytes).map_err(|e| decode_error(&e))?;
+            let bytes = hex::decode(s.trim()).map_err(|e| decode_error(&e))?;
+            bincode::deserialize(&bytes).map_err(|e| decode_error(&e))
+        }
+        Encoding::Json => serde_json::from_slice(bytes).map_err(|e| decode_error(&e)),
+    }
+}
+
+/// Validate a transaction the way the network workers validate relayed ones, add it to the
+/// mempool and announce it to the peers and to event subscribers. Time locked transactions are
+/// held aside, and only added and announced once their lock passes. Transactions spending
+/// unknown outputs are not kept as orphans, since there is no peer to ask for their parents.
+pub fn submit_transaction(
+    blockchain: &RwLock<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    network: &NetworkServerHandle,
+    events: &EventBus,
+    tx: &SignedTransaction,
+) -> Result<H256, SubmitError> {
+    let unconfirmed = mempool
+        .lock()
+        .unwrap()
+        .unconfirmed_outputs(tx.transcation.input.iter());
+    let blockchain = blockchain.read().unwrap();
+    let state = blockchain.tip_state();
+    match state.validate_with(tx, &unconfirmed) {
+        Ok(()) => {}
+        Err(e) if e.is_non_final() => {
+            drop(blockchain);
+            debug!("Hold transaction {}: {}", tx.hash(), e);
+            mempool.lock().unwrap().hold(tx);
+            return Ok(tx.hash());
+        }
+        Err(e) => return Err(SubmitError::Invalid(e)),
+    }
+    let fee = state.fee_with(tx, &unconfirmed);
+    drop(blockchain);
+
+    let hash = tx.hash();
+    let evicted = mempool
+        .lock()
+        .unwrap()
+        .insert(tx, fee)
+        .map_err(SubmitError::Rejected)?;
+    for h in evicted.iter() {
+        debug!("Transaction {} replaced by {}", h, hash);
+    }
+    events.transaction_accepted(hash, fee, &evicted);
+    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+    Ok(hash)
+}
+
+/// A transaction of the longest chain or of the mempool, as stored and as viewed
+pub fn find_transaction(
+    blockchain: &RwLock<Blockchain>,
+    mempool: &Mutex<Mempool>,
+    hash: H256,
+) -> Result<(SignedTransaction, TransactionView), ExplorerError> {
+    {
+        let blockchain = blockchain.read().unwrap();
+        if let Some(tx) = explorer::raw_transaction(&blockchain, hash) {
+            let view = explorer::transaction(&blockchain, hash)?;
+            return Ok((tx.clone(), view));
+        }
+    }
+    // not confirmed yet, copy it out so the locks are not nested
+    let pending = {
+        let mempool = mempool.lock().unwrap();
+        mempool
+            .tx_map
+            .get(&hash)
+            .map(|tx| (tx.clone(), mempool.fees.get(&hash).cloned().unwrap_or(0)))
+    };
+    let (tx, fee) = pending.ok_or_else(|| ExplorerError::TransactionNotFound(hash.to_string()))?;
+    let blockchain = blockchain.read().unwrap();
+    let view = explorer::unconfirmed_transaction(&blockchain, &tx, fee);
+    Ok((tx, view))
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MempoolInfo {
+    pub transactions: usize,
+    /// size of its transactions together
+    pub bytes: u64,
+    pub total_fee: u64,
+    /// transactions waiting for an unknown parent, not in the mempool yet
+    pub orphans: usize,
+}
+
+pub fn mempool_info(mempool: &Mutex<Mempool>) -> MempoolInfo {
+    let mempool = mempool.lock().unwrap();
+    MempoolInfo {
+        transactions: mempool.tx_map.len(),
+        bytes: mempool.sizes.values().sum(),
+        total_fee: mempool
+            .fees
+            .values()
+            .fold(0u64, |acc, f| acc.saturating_add(*f)),
+        orphans: mempool.orphans.len(),
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PeerInfo {
+    pub address: String,
+}
+
+pub fn peer_info(network: &NetworkServerHandle) -> Vec<PeerInfo> {
+    network
+        .peers()
+        .into_iter()
+        .map(|addr| PeerInfo {
+            address: addr.to_string(),
+        })
+        .collect()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use crate::blockchain::params::ChainParams;
+    use crate::network::server::TestReceiver;
+    use crate::types::address::Address;
+    use crate::types::transaction::UTXO_output;
+    use crate::wallet::{PaymentOptions, Wallet};
+
+    fn node(
+        value: u64,
+    ) -> (
+        Wallet,
+        RwLock<Blockchain>,
+        NetworkServerHandle,
+        TestReceiver,
+    ) {
+        let mut wallet = Wallet::from_seed(&[6u8; 32]);
+        let payer = wallet.generate_key("payer").unwrap();
+        let mut params = ChainParams::regtest();
+        params.genesis.allocations = vec![Allocation {
+            address: payer.encode(params.address_prefix),
+            value,
+        }];
+        let blockchain = RwLock::new(Blockchain::with_params(params).unwrap());
+        let (network, receiver) = NetworkServerHandle::new_for_test();
+        (wallet, blockchain, network, receiver)
+    }
+
+    #[test]
+    fn submit_in_every_encoding() {
+        let (wallet, blockchain, network, receiver) = node(1000);
+        let state = blockchain.read().unwrap().tip_state().clone();
+        let tx = wallet
+            .create_transaction(&state, &Mempool::new(), Address::default(), 400, 5)
+            .unwrap();
+        let bytes = bincode::serialize(&tx).unwrap();
+        let encodings = [
+            (bytes.clone(), Encoding::Bincode),
+            (hex::encode(&bytes).into_bytes(), Encoding::Hex),
+            (serde_json::to_vec(&tx).unwrap(), Encoding::Json),
+        ];
+        for (body, encoding) in encodings.iter() {
+            let decoded = decode_transaction(body, *encoding).unwrap();
+            assert_eq!(decoded.hash(), tx.hash());
+        }
+        assert_eq!(Encoding::detect(None, &encodings[1].0), Encoding::Hex);
+        assert_eq!(Encoding::detect(None, &encodings[2].0), Encoding::Json);
+        let octets = Some("application/octet-stream");
+        assert_eq!(Encoding::detect(octets, &bytes), Encoding::Bincode);
+
+        let mempool = Mutex::new(Mempool::new());
+        let events = EventBus::new();
+        let submit = |tx: &SignedTransaction| {
+            submit_transaction(&blockchain, &mempool, &network, &events, tx)
+        };
+        let hash = submit(&tx).unwrap();
+        assert_eq!(hash, tx.hash());
+        assert_eq!(mempool.lock().unwrap().fees[&hash], 5);
+        assert!(matches!(
+            receiver.recv(),
+            Some(Message::NewTransactionHashes(v)) if v == vec![hash]
+        ));
+
+        // rejections are typed
+        let again = submit(&tx).unwrap_err();
+        assert_eq!(again.reason(), "already_known");
+        let mut forged = tx.clone();
+        forged.transcation.output[0].value += 1000;
+        let forged = submit(&forged).unwrap_err();
+        assert!(matches!(forged, SubmitError::Invalid(_)));
+        let garbage = decode_transaction(b"zz", Encoding::Hex).unwrap_err();
+        assert_eq!(garbage.reason(), "decode");
+    }
+
+    #[test]
+    fn hold_time_locked() {
+        let (wallet, blockchain, network, receiver) = node(1000);
+        let state = blockchain.read().unwrap().tip_state().clone();
+        let output = UTXO_output {
+            receipient_address: Address::default(),
+            value: 400,
+            script: None,
+        };
+        let options = PaymentOptions {
+            fee: 5,
+            lock_time: state.height + 10,
+            ..Default::default()
+        };
+        let locked = wallet
+            .create_payment(&state, &Mempool::new(), output, &options)
+            .unwrap();
+        let tx = wallet
+            .create_transaction(&state, &Mempool::new(), Address::default(), 400, 5)
+            .unwrap();
+
+        let mempool = Mutex::new(Mempool::new());
+        let events = EventBus::new();
+        let submit = |tx: &SignedTransaction| {
+            submit_transaction(&blockchain, &mempool, &network, &events, tx)
+        };
+        // held aside, neither in the mempool nor announced before its lock passes
+        assert_eq!(submit(&locked), Ok(locked.hash()));
+        assert!(mempool.lock().unwrap().tx_map.is_empty());
+        assert!(mempool.lock().unwrap().held.contains_key(&locked.hash()));
+        assert_eq!(submit(&tx), Ok(tx.hash()));
+        assert!(matches!(
+            receiver.recv(),
+            Some(Message::NewTransactionHashes(v)) if v == vec![tx.hash()]
+        ));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,28 +1,85 @@
+use crate::blockchain::actor::Handle as ChainHandle;
 use crate::blockchain::{self, Blockchain};
-use crate::miner::Handle as MinerHandle;
+use crate::events::{Event, EventBus, Topic};
+use crate::miner::{self, Handle as MinerHandle};
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
 use crate::types::block;
+use crate::types::address::Address;
+use crate::types::fee_estimator::MAX_TARGET;
 use crate::types::hash::Hashable;
+use crate::types::multisig::MultisigPolicy;
+use crate::types::script::Script;
+use crate::types::state::State;
+use crate::types::transaction::{Mempool, SignedTransaction, UTXO_output};
 use crate::types::transaction_generate::Handle as TXGenerateHandle;
-use serde::Serialize;
+use crate::types::transaction_generate::{Mode, SpendConfig, ValueDistribution};
+use crate::wallet::{PaymentOptions, Wallet};
+use crossbeam::channel::{Receiver, RecvTimeoutError};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
 use log::{debug, info};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
 use std::ops::RangeBounds;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+pub mod explorer;
+pub mod handlers;
+pub mod rpc;
+
 pub struct Server {
     handle: HTTPServer,
-    miner: MinerHandle,
-    network: NetworkServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    tx_generator: TXGenerateHandle,
+    context: Context,
+}
+
+/// Parts of the node the routes work on, each request gets its own clone
+#[derive(Clone)]
+pub struct Context {
+    pub miner: MinerHandle,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub chain: ChainHandle,
+    pub tx_generator: TXGenerateHandle,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub events: EventBus,
+}
+
+/// How long an event stream may stay silent before a comment is sent to keep it open
+const EVENT_KEEP_ALIVE: time::Duration = time::Duration::from_secs(15);
+
+/// Largest body read from a request carrying wallet secrets
+const MAX_SECRET_BODY: u64 = 64 * 1024;
+
+// Passphrases and keys only travel in the JSON body of POST requests, never in the url, which
+// ends up in access logs, proxies and shell history.
+#[derive(Deserialize)]
+struct UnlockRequest {
+    passphrase: String,
+    /// in seconds, without it the wallet stays unlocked
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ImportRequest {
+    /// PKCS#8 document of the key, in hex
+    key: String,
+    #[serde(default)]
+    label: String,
+}
+
+#[derive(Deserialize)]
+struct ExportRequest {
+    address: String,
 }
 
 #[derive(Serialize)]
@@ -52,28 +109,50 @@ macro_rules! respond_json {
     }};
 }
 
+/// Respond with the view of an explorer query, or its error with a 404 or 400 status
+macro_rules! respond_explorer {
+    ( $req:expr, $result:expr ) => {{
+        match $result {
+            Ok(v) => respond_json!($req, v),
+            Err(e) => {
+                let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+                let status = if e.is_not_found() { 404 } else { 400 };
+                let payload = ApiResponse {
+                    success: false,
+                    message: e.to_string(),
+                };
+                let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                    .with_header(content_type)
+                    .with_status_code(status);
+                $req.respond(resp).unwrap();
+            }
+        }
+    }};
+}
+
 impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &MinerHandle,
-        network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        tx_generator: &TXGenerateHandle,
-    ) {
+    pub fn start(addr: std::net::SocketAddr, context: &Context) -> std::net::SocketAddr {
         let handle = HTTPServer::http(&addr).unwrap();
+        // the port is picked by the system if the given one is 0
+        let addr = handle.server_addr();
+        // addresses are parsed and written with the prefix of the chain served
+        let prefix = context.blockchain.read().unwrap().params.address_prefix;
         let server = Self {
             handle,
-            miner: miner.clone(),
-            network: network.clone(),
-            blockchain: Arc::clone(blockchain),
-            tx_generator: tx_generator.clone(),
+            context: context.clone(),
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let network = server.network.clone();
-                let blockchain = Arc::clone(&server.blockchain);
-                let tx_generator = server.tx_generator.clone();
+                let Context {
+                    miner,
+                    network,
+                    blockchain,
+                    chain,
+                    tx_generator,
+                    mempool,
+                    wallet,
+                    events,
+                } = server.context.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -132,16 +211,38 @@ impl Server {
                                     return;
                                 }
                             };
-                            tx_generator.start(theta);
+                            let mode = match params.get("mode").map(|v| v.as_str()) {
+                                None | Some("random") => Mode::Random,
+                                Some("spend") => match spend_config(&params, prefix) {
+                                    Ok(config) => Mode::Spend(config),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                },
+                                Some(v) => {
+                                    respond_result!(req, false, format!("unknown mode {}", v));
+                                    return;
+                                }
+                            };
+                            tx_generator.start(theta, mode);
                             respond_result!(req, true, "ok!");
                         }
+                        "/tx-generator/addresses" => {
+                            let v: Vec<String> = tx_generator
+                                .addresses()
+                                .iter()
+                                .map(|address| address.encode(prefix))
+                                .collect();
+                            respond_json!(req, v);
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
                         "/blockchain/longest-chain" => {
                             println!("test1");
-                            let blockchain = blockchain.lock().unwrap();
+                            let blockchain = blockchain.read().unwrap();
                             println!("test2");
                             let v = blockchain.all_blocks_in_longest_chain();
                             println!("test3");
@@ -152,7 +253,7 @@ impl Server {
                             respond_json!(req, v_string);
                         }
                         "/blockchain/longest-chain-tx" => {
-                            let blockchain_mtx = blockchain.lock().unwrap();
+                            let blockchain_mtx = blockchain.read().unwrap();
                             let mut res = Vec::new();
                             // get all txs of a single block
                             let mut i = 0;
@@ -176,9 +277,702 @@ impl Server {
                             respond_json!(req, res);
                             // respond_result!(req, true, "ok");
                         }
+                        "/wallet/address" => {
+                            let wallet = wallet.lock().unwrap();
+                            let v: Vec<_> = wallet
+                                .labeled_addresses()
+                                .into_iter()
+                                .map(|(label, address)| {
+                                    serde_json::json!({
+                                        "label": label,
+                                        "address": address.encode(prefix),
+                                    })
+                                })
+                                .collect();
+                            drop(wallet);
+                            respond_json!(req, v);
+                        }
+                        "/wallet/new-address" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let label = params.get("label").map(|v| v.as_str()).unwrap_or("");
+                            let res = wallet.lock().unwrap().generate_key(label);
+                            match res {
+                                Ok(address) => respond_result!(req, true, address.encode(prefix)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/unlock" => {
+                            let mut req = req;
+                            let body: UnlockRequest = match secret_request(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            // timeout in seconds, without it the wallet stays unlocked
+                            let timeout = body.timeout.map(time::Duration::from_secs);
+                            let res = wallet.lock().unwrap().unlock(&body.passphrase, timeout);
+                            match res {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/lock" => {
+                            wallet.lock().unwrap().lock();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/wallet/import" => {
+                            let mut req = req;
+                            let body: ImportRequest = match secret_request(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let key = match hex::decode(&body.key) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing key: {}", e));
+                                    return;
+                                }
+                            };
+                            let res = wallet.lock().unwrap().import_key(&body.label, &key);
+                            match res {
+                                Ok(address) => respond_result!(req, true, address.encode(prefix)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/export" => {
+                            let mut req = req;
+                            let body: ExportRequest = match secret_request(&mut req) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let address = match Address::decode(&body.address, prefix) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let res = wallet.lock().unwrap().export_key(&address);
+                            match res {
+                                Ok(pkcs8) => respond_result!(req, true, hex::encode(pkcs8)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/export-seed" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "the seed is only sent to POSTs");
+                                return;
+                            }
+                            let res = wallet.lock().unwrap().export_seed();
+                            match res {
+                                Ok(seed) => respond_result!(req, true, hex::encode(seed)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/recover" => {
+                            // scan the longest chain for addresses derived from the wallet seed
+                            let used = blockchain.read().unwrap().used_addresses();
+                            let res = wallet.lock().unwrap().recover(&used);
+                            match res {
+                                Ok(addresses) => {
+                                    let v: Vec<String> =
+                                        addresses.iter().map(|a| a.encode(prefix)).collect();
+                                    respond_json!(req, v);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/public-key" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let address = match params.get("address") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let address = match Address::decode(address, prefix) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let res = wallet.lock().unwrap().public_key(&address);
+                            match res {
+                                Ok(key) => respond_result!(req, true, hex::encode(key)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/multisig/new" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let threshold = match params.get("threshold").map(|v| v.parse::<u8>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing threshold: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing threshold");
+                                    return;
+                                }
+                            };
+                            // comma separated hex public keys
+                            let keys = match params.get("keys") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing keys");
+                                    return;
+                                }
+                            };
+                            let keys: Result<Vec<Vec<u8>>, _> =
+                                keys.split(',').map(hex::decode).collect();
+                            let keys = match keys {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing keys: {}", e));
+                                    return;
+                                }
+                            };
+                            let label = params.get("label").map(|v| v.as_str()).unwrap_or("");
+                            let res = wallet
+                                .lock()
+                                .unwrap()
+                                .add_multisig(label, MultisigPolicy::new(threshold, keys));
+                            match res {
+                                Ok(address) => respond_result!(req, true, address.encode(prefix)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/multisig/send" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mut addresses = Vec::new();
+                            for name in ["from", "to"].iter() {
+                                let address = match params.get(*name) {
+                                    Some(v) => v,
+                                    None => {
+                                        respond_result!(req, false, format!("missing {}", name));
+                                        return;
+                                    }
+                                };
+                                match Address::decode(address, prefix) {
+                                    Ok(v) => addresses.push(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing {}: {}", name, e)
+                                        );
+                                        return;
+                                    }
+                                }
+                            }
+                            let amount = match params.get("amount").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing amount: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing amount");
+                                    return;
+                                }
+                            };
+                            let fee = match params.get("fee").map(|v| v.parse::<u64>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing fee: {}", e));
+                                    return;
+                                }
+                            };
+                            let state = blockchain.read().unwrap().tip_state().clone();
+                            let mempool_guard = mempool.lock().unwrap();
+                            let tx = wallet.lock().unwrap().create_multisig_transaction(
+                                &state,
+                                &mempool_guard,
+                                addresses[0],
+                                addresses[1],
+                                amount,
+                                fee,
+                            );
+                            drop(mempool_guard);
+                            match tx {
+                                Ok(tx) => {
+                                    let res =
+                                        finish_signing(tx, &state, &mempool, &network, &events);
+                                    respond_json!(req, res);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/sign" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // hex of the bincode encoded transaction, e.g. from multisig/send
+                            let tx = match params.get("tx") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing tx");
+                                    return;
+                                }
+                            };
+                            let tx = hex::decode(tx)
+                                .map_err(|e| e.to_string())
+                                .and_then(|v| {
+                                    bincode::deserialize::<SignedTransaction>(&v)
+                                        .map_err(|e| e.to_string())
+                                });
+                            let mut tx = match tx {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
+                                    return;
+                                }
+                            };
+                            let state = blockchain.read().unwrap().tip_state().clone();
+                            let res = wallet.lock().unwrap().sign_transaction(&state, &mut tx);
+                            match res {
+                                Ok(_) => {
+                                    let res =
+                                        finish_signing(tx, &state, &mempool, &network, &events);
+                                    respond_json!(req, res);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/wallet/balance" => {
+                            // take a snapshot of the tip state first, the two locks are never nested
+                            let state = blockchain.read().unwrap().tip_state().clone();
+                            let mempool = mempool.lock().unwrap();
+                            let balance = wallet.lock().unwrap().balance(&state, &mempool);
+                            drop(mempool);
+                            respond_json!(req, balance);
+                        }
+                        "/wallet/send" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // pay either to an address or to a hex encoded locking script
+                            let to = match (params.get("to"), params.get("script")) {
+                                (Some(to), None) => Address::decode(to, prefix)
+                                    .map(|address| (address, None))
+                                    .map_err(|e| format!("error parsing to: {}", e)),
+                                (None, Some(script)) => hex::decode(script)
+                                    .map(|v| (Script(v.clone()).address(), Some(Script(v))))
+                                    .map_err(|e| format!("error parsing script: {}", e)),
+                                _ => Err("expected exactly one of to and script".to_string()),
+                            };
+                            let (to, script) = match to {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let amount = match params.get("amount") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing amount");
+                                    return;
+                                }
+                            };
+                            let amount = match amount.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing amount: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            // fee is optional, whatever is left of the inputs goes to the miner
+                            let fee = match params.get("fee").map(|v| v.parse::<u64>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing fee: {}", e));
+                                    return;
+                                }
+                            };
+                            // optional height or time the payment is locked until
+                            let lock_time = params.get("lock_time").map(|v| v.parse::<u64>());
+                            let lock_time = match lock_time {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing lock_time: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let replaceable = params.get("replaceable").map(|v| v.parse::<bool>());
+                            let replaceable = match replaceable {
+                                None => false,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing replaceable: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let options = PaymentOptions {
+                                fee,
+                                lock_time,
+                                replaceable,
+                            };
+                            let state = blockchain.read().unwrap().tip_state().clone();
+                            let mut mempool = mempool.lock().unwrap();
+                            let output = UTXO_output {
+                                receipient_address: to,
+                                value: amount,
+                                script,
+                            };
+                            let tx = wallet
+                                .lock()
+                                .unwrap()
+                                .create_payment(&state, &mempool, output, &options);
+                            let tx = match tx {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    drop(mempool);
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let tx_hash = tx.hash();
+                            let fee = state.fee(&tx);
+                            let inserted = mempool.insert(&tx, fee);
+                            drop(mempool);
+                            match inserted {
+                                Ok(evicted) => events.transaction_accepted(tx_hash, fee, &evicted),
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            }
+                            network.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
+                            respond_result!(req, true, tx_hash);
+                        }
+                        "/regtest/generate" => {
+                            if !blockchain.read().unwrap().params.on_demand_mining {
+                                respond_result!(req, false, "only available on regtest");
+                                return;
+                            }
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks").map(|v| v.parse::<usize>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing blocks: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing blocks");
+                                    return;
+                                }
+                            };
+                            let address = params.get("address").map(|v| Address::decode(v, prefix));
+                            let address = match address {
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing address: {}", e)
+                                    );
+                                    return;
+                                }
+                                None => None,
+                            };
+                            let hashes =
+                                miner::generate(&blockchain, &mempool, &chain, blocks, address);
+                            if hashes.len() < blocks {
+                                respond_result!(
+                                    req,
+                                    false,
+                                    format!("only {} blocks were connected", hashes.len())
+                                );
+                                return;
+                            }
+                            let v: Vec<String> = hashes.iter().map(|h| h.to_string()).collect();
+                            respond_json!(req, v);
+                        }
+                        "/fees/estimate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blocks = match params.get("blocks") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing blocks");
+                                    return;
+                                }
+                            };
+                            let blocks = match blocks.parse::<usize>() {
+                                Ok(v) if v > 0 => v,
+                                Ok(_) => {
+                                    respond_result!(req, false, "blocks must be positive");
+                                    return;
+                                }
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        false,
+                                        format!("error parsing blocks: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            let estimate = mempool.lock().unwrap().estimate_fee_rate(blocks);
+                            match estimate {
+                                Some(fee_rate) => {
+                                    // fee per 1000 bytes, rounded up so it is enough
+                                    let v = serde_json::json!({
+                                        "blocks": blocks.min(MAX_TARGET),
+                                        "fee_rate": fee_rate.ceil() as u64,
+                                    });
+                                    respond_json!(req, v);
+                                }
+                                None => respond_result!(
+                                    req,
+                                    false,
+                                    "not enough data to estimate the fee rate"
+                                ),
+                            }
+                        }
                         "/blockchain/longest-chain-tx-count" => {
-                            // unimplemented!()
-                            respond_result!(req, false, "unimplemented!");
+                            let blockchain = blockchain.read().unwrap();
+                            let count: usize = blockchain
+                                .all_blocks_in_longest_chain()
+                                .iter()
+                                .map(|hash| blockchain.blocks[hash].content.content.len())
+                                .sum();
+                            drop(blockchain);
+                            respond_json!(req, count);
+                        }
+                        "/tx/submit" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "transactions must be POSTed");
+                                return;
+                            }
+                            let mut body = Vec::new();
+                            let limit = handlers::MAX_TRANSACTION_BODY;
+                            if let Err(e) = req.as_reader().take(limit + 1).read_to_end(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let content_type = req
+                                .headers()
+                                .iter()
+                                .find(|h| h.field.equiv("Content-Type"))
+                                .map(|h| h.value.as_str().to_string());
+                            let encoding =
+                                handlers::Encoding::detect(content_type.as_deref(), &body);
+                            let tx = if body.len() as u64 > limit {
+                                Err(handlers::SubmitError::TooLarge)
+                            } else {
+                                handlers::decode_transaction(&body, encoding)
+                            };
+                            let result = tx.and_then(|tx| {
+                                handlers::submit_transaction(
+                                    &blockchain,
+                                    &mempool,
+                                    &network,
+                                    &events,
+                                    &tx,
+                                )
+                            });
+                            let (payload, status) = match result {
+                                Ok(hash) => (
+                                    serde_json::json!({
+                                        "success": true,
+                                        "hash": hash.to_string(),
+                                    }),
+                                    200,
+                                ),
+                                Err(e) => (
+                                    serde_json::json!({
+                                        "success": false,
+                                        "reason": e.reason(),
+                                        "message": e.to_string(),
+                                    }),
+                                    match e {
+                                        handlers::SubmitError::TooLarge => 413,
+                                        _ => 400,
+                                    },
+                                ),
+                            };
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(payload.to_string())
+                                .with_header(content_type)
+                                .with_status_code(status);
+                            req.respond(resp).unwrap();
+                        }
+                        "/mempool/info" => {
+                            respond_json!(req, handlers::mempool_info(&mempool));
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, handlers::peer_info(&network));
+                        }
+                        "/events" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            // every topic unless some are picked, as in `?topics=tip,mempool`
+                            let topics: Result<HashSet<Topic>, String> = match params.get("topics")
+                            {
+                                Some(v) => v.split(',').map(|t| t.trim().parse()).collect(),
+                                None => Ok(HashSet::new()),
+                            };
+                            let topics = match topics {
+                                Ok(t) => t,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            stream_events(req, events.subscribe(topics));
+                        }
+                        "/rpc" => {
+                            let mut req = req;
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "JSON-RPC requests must be POSTed");
+                                return;
+                            }
+                            let mut body = Vec::new();
+                            let limit = rpc::MAX_BODY;
+                            if let Err(e) = req.as_reader().take(limit + 1).read_to_end(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            if body.len() as u64 > limit {
+                                let message = format!("request larger than {} bytes", limit);
+                                let response = rpc::error_response(rpc::INVALID_REQUEST, message);
+                                let resp = Response::from_string(response)
+                                    .with_header(content_type)
+                                    .with_status_code(413);
+                                req.respond(resp).unwrap();
+                                return;
+                            }
+                            let body = match String::from_utf8(body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    let response = rpc::error_response(rpc::PARSE_ERROR, e);
+                                    let resp =
+                                        Response::from_string(response).with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                    return;
+                                }
+                            };
+                            let rpc = rpc::Rpc {
+                                blockchain,
+                                mempool,
+                                network,
+                                miner,
+                                events,
+                            };
+                            match rpc.handle(&body) {
+                                Some(response) => {
+                                    let resp =
+                                        Response::from_string(response).with_header(content_type);
+                                    req.respond(resp).unwrap();
+                                }
+                                // only notifications, nothing to answer
+                                None => req.respond(Response::empty(204)).unwrap(),
+                            }
+                        }
+                        "/chain/info" => {
+                            let mempool_transactions = mempool.lock().unwrap().tx_map.len();
+                            let blockchain = blockchain.read().unwrap();
+                            let v = explorer::chain_info(&blockchain, mempool_transactions);
+                            drop(blockchain);
+                            respond_json!(req, v);
+                        }
+                        path if path.starts_with("/block/height/") => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let height = &path["/block/height/".len()..];
+                            let result = height
+                                .parse::<u64>()
+                                .map_err(|e| explorer::ExplorerError::InvalidHeight(e.to_string()))
+                                .and_then(|height| {
+                                    let page = explorer::Page::from_params(&params)?;
+                                    let blockchain = blockchain.read().unwrap();
+                                    explorer::block_at_height(&blockchain, height, page)
+                                });
+                            respond_explorer!(req, result);
+                        }
+                        path if path.starts_with("/block/") => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let result = explorer::parse_hash(&path["/block/".len()..])
+                                .and_then(|hash| {
+                                    let page = explorer::Page::from_params(&params)?;
+                                    let blockchain = blockchain.read().unwrap();
+                                    explorer::block(&blockchain, hash, page)
+                                });
+                            respond_explorer!(req, result);
+                        }
+                        path if path.starts_with("/tx/") => {
+                            let result = explorer::parse_hash(&path["/tx/".len()..])
+                                .and_then(|hash| {
+                                    handlers::find_transaction(&blockchain, &mempool, hash)
+                                })
+                                .map(|(_, view)| view);
+                            respond_explorer!(req, result);
+                        }
+                        path if path.starts_with("/address/") => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let result = explorer::parse_address(&path["/address/".len()..], prefix)
+                                .and_then(|address| {
+                                    let page = explorer::Page::from_params(&params)?;
+                                    let blockchain = blockchain.read().unwrap();
+                                    explorer::address(&blockchain, address, page)
+                                });
+                            respond_explorer!(req, result);
                         }
                         _ => {
                             let content_type =
@@ -199,5 +993,146 @@ impl Server {
             }
         });
         info!("API server listening at {}", &addr);
+        addr
+    }
+}
+
+/// Shape of generated transactions from the parameters of `/tx-generator/start`: `peers` is a
+/// comma separated list of addresses, values are drawn from `min_value` to `max_value`, or around
+/// `mean_value` with mostly small ones when it is given
+fn spend_config(params: &HashMap<String, String>, prefix: &str) -> Result<SpendConfig, String> {
+    let mut config = SpendConfig::default();
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        params
+            .get(name)
+            .map(|v| v.parse::<u64>())
+            .transpose()
+            .map_err(|e| format!("error parsing {}: {}", name, e))
+    };
+    if let Some(peers) = params.get("peers") {
+        for peer in peers.split(',').filter(|p| !p.is_empty()) {
+            let address = Address::decode(peer, prefix)
+                .map_err(|e| format!("error parsing peer {}: {}", peer, e))?;
+            config.peers.push(address);
+        }
+    }
+    if let Some(fanout) = number("fanout")? {
+        config.fanout = fanout as usize;
+    }
+    if let Some(fee_rate) = number("fee_rate")? {
+        config.fee_rate = fee_rate;
+    }
+    if let Some(mean) = number("mean_value")? {
+        config.values = ValueDistribution::Exponential { mean };
+    } else if let ValueDistribution::Uniform { min, max } = config.values {
+        let min = number("min_value")?.unwrap_or(min);
+        let max = number("max_value")?.unwrap_or(max);
+        if min > max {
+            return Err("min_value is above max_value".to_string());
+        }
+        config.values = ValueDistribution::Uniform { min, max };
+    }
+    Ok(config)
+}
+
+/// Report a transaction after signing. Once it has every signature it needs it is added to the
+/// mempool and announced, otherwise it is handed back to be passed to the next signer. A
+/// transaction that is only waiting for its time lock is complete, and held until it passes.
+fn finish_signing(
+    tx: SignedTransaction,
+    state: &State,
+    mempool: &Arc<Mutex<Mempool>>,
+    network: &NetworkServerHandle,
+    events: &EventBus,
+) -> serde_json::Value {
+    let encoded = hex::encode(bincode::serialize(&tx).unwrap());
+    let tx_hash = tx.hash();
+    match state.validate(&tx) {
+        Ok(()) => {}
+        Err(e) if e.is_non_final() => {
+            mempool.lock().unwrap().hold(&tx);
+            return serde_json::json!({
+                "complete": true,
+                "tx": encoded,
+                "status": e.to_string(),
+                "hash": tx_hash.to_string(),
+            });
+        }
+        Err(e) => {
+            return serde_json::json!({
+                "complete": false,
+                "tx": encoded,
+                "status": e.to_string(),
+            });
+        }
+    }
+    let fee = state.fee(&tx);
+    match mempool.lock().unwrap().insert(&tx, fee) {
+        Ok(evicted) => events.transaction_accepted(tx_hash, fee, &evicted),
+        Err(e) => {
+            return serde_json::json!({
+                "complete": true,
+                "tx": encoded,
+                "status": e.to_string(),
+            });
+        }
+    }
+    network.broadcast(Message::NewTransactionHashes(vec![tx_hash]));
+    serde_json::json!({
+        "complete": true,
+        "tx": encoded,
+        "status": "final",
+        "hash": tx_hash.to_string(),
+    })
+}
+
+/// Send events to the client as server-sent events until it goes away or falls too far behind.
+/// tiny_http buffers chunked responses, so the response is written out by hand and flushed after
+/// every event.
+fn stream_events(req: tiny_http::Request, receiver: Receiver<Event>) {
+    let mut writer = req.into_writer();
+    let head = "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                Connection: close\r\n\r\n";
+    if writer.write_all(head.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+    loop {
+        let message = match receiver.recv_timeout(EVENT_KEEP_ALIVE) {
+            Ok(event) => format!(
+                "event: {}\ndata: {}\n\n",
+                event.name(),
+                serde_json::to_string(&event).unwrap()
+            ),
+            // a comment keeps proxies from closing an idle stream, and finds out if it is closed
+            Err(RecvTimeoutError::Timeout) => ": keep-alive\n\n".to_string(),
+            // dropped by the event bus for falling behind
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if writer.write_all(message.as_bytes()).and_then(|_| writer.flush()).is_err() {
+            debug!("Event stream closed");
+            return;
+        }
+    }
+}
+
+/// Read the body of a request, failing if it is larger than `limit` bytes
+fn read_body(req: &mut tiny_http::Request, limit: u64) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    req.as_reader()
+        .take(limit + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("error reading body: {}", e))?;
+    if body.len() as u64 > limit {
+        return Err(format!("body larger than {} bytes", limit));
+    }
+    Ok(body)
+}
+
+/// The JSON body of a POST request carrying wallet secrets
+fn secret_request<T: DeserializeOwned>(req: &mut tiny_http::Request) -> Result<T, String> {
+    if *req.method() != Method::Post {
+        return Err("secrets are only sent in the body of POST requests".to_string());
     }
+    let body = read_body(req, MAX_SECRET_BODY)?;
+    serde_json::from_slice(&body).map_err(|e| format!("error parsing body: {}", e))
 }
@@ -0,0 +1,398 @@
+use super::explorer::{self, ExplorerError, Page};
+use super::handlers::{self, Encoding, SubmitError};
+use crate::blockchain::Blockchain;
+use crate::events::EventBus;
+use crate::miner::Handle as MinerHandle;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::types::transaction::Mempool;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::value::RawValue;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, RwLock};
+
+// JSON-RPC 2.0 over `POST /rpc`. A request is an object, or an array of them answered together
+// in a batch; requests without an id are notifications and get no response. Parameters are given
+// by position or by name. Methods answer through the same handlers as the REST routes, results
+// are serialized as the REST routes serialize them.
+
+/// Largest body read from a request, enough for a batch of the largest transactions
+pub const MAX_BODY: u64 = 4 * handlers::MAX_TRANSACTION_BODY;
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// errors of the methods, in the range JSON-RPC leaves to implementations
+/// no block or transaction with that hash or height
+pub const NOT_FOUND: i64 = -32001;
+/// the transaction is not valid on top of the longest chain and the mempool
+pub const TRANSACTION_INVALID: i64 = -32002;
+/// the mempool does not take the transaction
+pub const TRANSACTION_REJECTED: i64 = -32003;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<ExplorerError> for RpcError {
+    fn from(e: ExplorerError) -> Self {
+        let code = if e.is_not_found() {
+            NOT_FOUND
+        } else {
+            INVALID_PARAMS
+        };
+        RpcError::new(code, e)
+    }
+}
+
+impl From<SubmitError> for RpcError {
+    fn from(e: SubmitError) -> Self {
+        let code = match e {
+            SubmitError::Decode(_) | SubmitError::TooLarge => INVALID_PARAMS,
+            SubmitError::Invalid(_) => TRANSACTION_INVALID,
+            SubmitError::Rejected(_) => TRANSACTION_REJECTED,
+        };
+        let data = serde_json::json!({ "reason": e.reason() });
+        RpcError {
+            data: Some(data),
+            ..RpcError::new(code, e)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Box<RawValue>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Box<RawValue>, RpcError>) -> Self {
+        let (result, error) = match result {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e)),
+        };
+        Response {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        }
+    }
+}
+
+/// Parameters of a call, by position or by name
+struct Params(Value);
+
+impl Params {
+    fn get(&self, position: usize, name: &str) -> Option<&Value> {
+        let v = match &self.0 {
+            Value::Array(v) => v.get(position),
+            Value::Object(m) => m.get(name),
+            _ => None,
+        };
+        v.filter(|v| !v.is_null())
+    }
+
+    fn optional<T: DeserializeOwned>(
+        &self,
+        position: usize,
+        name: &str,
+    ) -> Result<Option<T>, RpcError> {
+        self.get(position, name)
+            .map(|v| {
+                serde_json::from_value(v.clone())
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, format!("{}: {}", name, e)))
+            })
+            .transpose()
+    }
+
+    fn required<T: DeserializeOwned>(&self, position: usize, name: &str) -> Result<T, RpcError> {
+        self.optional(position, name)?
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing {}", name)))
+    }
+}
+
+/// Response to a request that could not be read, it has no id
+pub fn error_response(code: i64, message: impl ToString) -> String {
+    let error = RpcError::new(code, message);
+    serde_json::to_string(&Response::new(Value::Null, Err(error))).unwrap()
+}
+
+fn to_raw<T: Serialize>(v: &T) -> Result<Box<RawValue>, RpcError> {
+    serde_json::value::to_raw_value(v).map_err(|e| RpcError::new(INTERNAL_ERROR, e))
+}
+
+/// Shared state of the node the methods work on
+pub struct Rpc {
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub network: NetworkServerHandle,
+    pub miner: MinerHandle,
+    pub events: EventBus,
+}
+
+impl Rpc {
+    /// Answer the body of an HTTP request, none if it only holds notifications
+    pub fn handle(&self, body: &str) -> Option<String> {
+        let request: Value = match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(e) => return Some(error_response(PARSE_ERROR, e)),
+        };
+        match request {
+            Value::Array(batch) if batch.is_empty() => {
+                Some(error_response(INVALID_REQUEST, "empty batch"))
+            }
+            Value::Array(batch) => {
+                let responses: Vec<Response> =
+                    batch.into_iter().filter_map(|r| self.call(r)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(serde_json::to_string(&responses).unwrap())
+                }
+            }
+            request => self
+                .call(request)
+                .map(|response| serde_json::to_string(&response).unwrap()),
+        }
+    }
+
+    /// Answer a single request, none if it is a notification
+    fn call(&self, request: Value) -> Option<Response> {
+        let mut request = match request {
+            Value::Object(m) => m,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "a request must be an object");
+                return Some(Response::new(Value::Null, Err(error)));
+            }
+        };
+        let id = request.remove("id");
+        // an id must be a string, a number or null
+        if let Some(Value::Array(_)) | Some(Value::Object(_)) | Some(Value::Bool(_)) = id {
+            let error = RpcError::new(INVALID_REQUEST, "invalid id");
+            return Some(Response::new(Value::Null, Err(error)));
+        }
+        let checked = match (request.remove("jsonrpc"), request.remove("method")) {
+            (Some(Value::String(v)), Some(Value::String(method))) if v == "2.0" => {
+                match request.remove("params").unwrap_or(Value::Null) {
+                    params @ (Value::Array(_) | Value::Object(_) | Value::Null) => {
+                        Ok((method, Params(params)))
+                    }
+                    _ => Err(RpcError::new(
+                        INVALID_REQUEST,
+                        "params must be an array or object",
+                    )),
+                }
+            }
+            _ => Err(RpcError::new(
+                INVALID_REQUEST,
+                "expected jsonrpc 2.0 and a method name",
+            )),
+        };
+        let (method, params) = match checked {
+            Ok(v) => v,
+            // invalid requests are answered even without an id
+            Err(e) => return Some(Response::new(id.unwrap_or(Value::Null), Err(e))),
+        };
+        let result = self.dispatch(&method, &params);
+        id.map(|id| Response::new(id, result))
+    }
+
+    fn dispatch(&self, method: &str, params: &Params) -> Result<Box<RawValue>, RpcError> {
+        match method {
+            "getbestblockhash" => {
+                let tip = self.blockchain.read().unwrap().tip();
+                to_raw(&tip.to_string())
+            }
+            "getblock" => {
+                let hash: String = params.required(0, "blockhash")?;
+                let hash = explorer::parse_hash(&hash)?;
+                // 0 for the hex encoded block, 1 for its decoded view
+                let verbosity: u8 = params.optional(1, "verbosity")?.unwrap_or(1);
+                let blockchain = self.blockchain.read().unwrap();
+                match verbosity {
+                    0 => {
+                        let block = blockchain
+                            .blocks
+                            .get(&hash)
+                            .ok_or_else(|| ExplorerError::BlockNotFound(hash.to_string()))?;
+                        to_raw(&hex::encode(bincode::serialize(block).unwrap()))
+                    }
+                    1 => to_raw(&explorer::block(&blockchain, hash, Page::all())?),
+                    _ => Err(RpcError::new(INVALID_PARAMS, "verbosity must be 0 or 1")),
+                }
+            }
+            "getrawtransaction" => {
+                let hash: String = params.required(0, "txid")?;
+                let hash = explorer::parse_hash(&hash)?;
+                let verbose: bool = params.optional(1, "verbose")?.unwrap_or(false);
+                let (tx, view) = handlers::find_transaction(&self.blockchain, &self.mempool, hash)?;
+                if verbose {
+                    to_raw(&view)
+                } else {
+                    to_raw(&hex::encode(bincode::serialize(&tx).unwrap()))
+                }
+            }
+            "sendrawtransaction" => {
+                let raw: String = params.required(0, "hexstring")?;
+                let tx = handlers::decode_transaction(raw.as_bytes(), Encoding::Hex)?;
+                let hash = handlers::submit_transaction(
+                    &self.blockchain,
+                    &self.mempool,
+                    &self.network,
+                    &self.events,
+                    &tx,
+                )?;
+                to_raw(&hash.to_string())
+            }
+            "getmempoolinfo" => to_raw(&handlers::mempool_info(&self.mempool)),
+            "getpeerinfo" => to_raw(&handlers::peer_info(&self.network)),
+            "startmining" => {
+                let lambda: u64 = params.required(0, "lambda")?;
+                self.miner.start(lambda);
+                to_raw(&Value::Null)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("method {} not found", method),
+            )),
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use crate::blockchain::params::ChainParams;
+    use crate::network::message::Message;
+    use crate::network::server::TestReceiver;
+    use crate::types::address::Address;
+    use crate::types::hash::Hashable;
+    use crate::wallet::Wallet;
+
+    fn rpc(blockchain: Blockchain) -> (Rpc, TestReceiver) {
+        let (network, receiver) = NetworkServerHandle::new_for_test();
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (_, miner, _) = crate::miner::new(&blockchain, &mempool);
+        let rpc = Rpc {
+            blockchain,
+            mempool,
+            network,
+            miner,
+            events: EventBus::new(),
+        };
+        (rpc, receiver)
+    }
+
+    fn call(rpc: &Rpc, body: &str) -> Value {
+        serde_json::from_str(&rpc.handle(body).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn calls_batches_and_errors() {
+        let mut wallet = Wallet::from_seed(&[5u8; 32]);
+        let payer = wallet.generate_key("payer").unwrap();
+        let mut params = ChainParams::regtest();
+        params.genesis.allocations = vec![Allocation {
+            address: payer.encode(params.address_prefix),
+            value: 1000,
+        }];
+        let blockchain = Blockchain::with_params(params).unwrap();
+        let genesis = blockchain.genesis;
+        let state = blockchain.tip_state().clone();
+        let (rpc, receiver) = rpc(blockchain);
+
+        let v = call(
+            &rpc,
+            r#"{"jsonrpc":"2.0","method":"getbestblockhash","id":1}"#,
+        );
+        assert_eq!(v["result"], genesis.to_string());
+        assert_eq!(v["id"], 1);
+
+        // by name, and a block that does not exist
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","method":"getblock","params":{{"blockhash":"{}"}},"id":"a"}}"#,
+            genesis
+        );
+        assert_eq!(call(&rpc, &body)["result"]["height"], 0);
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","method":"getblock","params":["{}"],"id":2}}"#,
+            crate::types::hash::H256::default()
+        );
+        assert_eq!(call(&rpc, &body)["error"]["code"], NOT_FOUND);
+
+        // a payment goes to the mempool and is announced
+        let tx = wallet
+            .create_transaction(&state, &Mempool::new(), Address::default(), 600, 10)
+            .unwrap();
+        let raw = hex::encode(bincode::serialize(&tx).unwrap());
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","method":"sendrawtransaction","params":["{}"],"id":3}}"#,
+            raw
+        );
+        assert_eq!(call(&rpc, &body)["result"], tx.hash().to_string());
+        assert!(matches!(
+            receiver.recv(),
+            Some(Message::NewTransactionHashes(_))
+        ));
+        let v = call(&rpc, &body);
+        assert_eq!(v["error"]["code"], TRANSACTION_REJECTED);
+        assert_eq!(v["error"]["data"]["reason"], "already_known");
+
+        // a batch, with a notification left unanswered
+        let body = format!(
+            r#"[
+                {{"jsonrpc":"2.0","method":"getmempoolinfo","id":4}},
+                {{"jsonrpc":"2.0","method":"getrawtransaction","params":["{}",true],"id":5}},
+                {{"jsonrpc":"2.0","method":"getbestblockhash"}},
+                {{"jsonrpc":"2.0","method":"nosuchmethod","id":6}},
+                7
+            ]"#,
+            tx.hash()
+        );
+        let v = call(&rpc, &body);
+        let v = v.as_array().unwrap();
+        assert_eq!(v.len(), 4);
+        assert_eq!(v[0]["result"]["transactions"], 1);
+        assert_eq!(v[0]["result"]["total_fee"], 10);
+        assert_eq!(v[1]["result"]["fee"], 10);
+        assert_eq!(v[1]["result"]["confirmations"], 0);
+        assert_eq!(v[2]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(v[3]["error"]["code"], INVALID_REQUEST);
+
+        assert_eq!(call(&rpc, "{")["error"]["code"], PARSE_ERROR);
+        assert_eq!(call(&rpc, "[]")["error"]["code"], INVALID_REQUEST);
+        let body = r#"{"jsonrpc":"2.0","method":"getblock","params":[],"id":8}"#;
+        assert_eq!(call(&rpc, body)["error"]["code"], INVALID_PARAMS);
+        assert!(rpc
+            .handle(r#"{"jsonrpc":"2.0","method":"getmempoolinfo"}"#)
+            .is_none());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,410 @@
+use super::Blockchain;
+use crate::events::{Event, EventBus};
+use crate::network::message::Message;
+use crate::network::server::Handle as ServerHandle;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{Mempool, SignedTransaction};
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+
+// The chain actor is the only place that writes to the blockchain. Blocks from peers and blocks
+// from our own miner are queued here and connected one batch at a time, so network workers only
+// ever need a read lock and can keep serving other messages in parallel.
+
+/// Most blocks kept waiting for their parent, later ones are dropped until there is room again
+const MAX_ORPHAN_BLOCKS: usize = 256;
+
+enum ControlSignal {
+    ConnectBlocks(Vec<Block>),
+    /// connect blocks, then tell the sender it is done
+    ConnectBlocksAndNotify(Vec<Block>, Sender<()>),
+    Exit,
+}
+
+pub struct Context {
+    /// Channel for receiving blocks to connect
+    control_chan: Receiver<ControlSignal>,
+    server: ServerHandle,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    events: EventBus,
+    orphan_buffer: OrphanBlocks,
+}
+
+/// Blocks received before their parent
+#[derive(Default)]
+struct OrphanBlocks {
+    // key: hash of the missing parent, value: the child blocks waiting for it
+    children: HashMap<H256, Vec<Block>>,
+    /// blocks kept, at most `MAX_ORPHAN_BLOCKS`
+    count: usize,
+}
+
+impl OrphanBlocks {
+    /// Keep a block until its parent arrives, unless the buffer is full
+    fn insert(&mut self, block: &Block) {
+        let hash = block.hash();
+        let siblings = self.children.entry(block.header.parent).or_default();
+        if siblings.iter().any(|b| b.hash() == hash) {
+            return;
+        }
+        if self.count >= MAX_ORPHAN_BLOCKS {
+            debug!("Drop orphan block {}, the buffer is full", hash);
+            if siblings.is_empty() {
+                self.children.remove(&block.header.parent);
+            }
+            return;
+        }
+        siblings.push(block.clone());
+        self.count += 1;
+    }
+
+    /// The blocks waiting for `parent`, which are no longer kept
+    fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = self.children.remove(parent).unwrap_or_default();
+        self.count -= children.len();
+        children
+    }
+
+    /// Drop the blocks built on top of `hash`, directly or not
+    fn discard_descendants(&mut self, hash: H256) {
+        let mut stack = vec![hash];
+        while let Some(parent) = stack.pop() {
+            stack.extend(self.take_children(&parent).iter().map(|b| b.hash()));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending blocks to the chain actor thread
+    control_chan: Sender<ControlSignal>,
+}
+
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    events: &EventBus,
+) -> (Context, Handle) {
+    let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        server: server.clone(),
+        blockchain: Arc::clone(blockchain),
+        mempool: Arc::clone(mempool),
+        events: events.clone(),
+        orphan_buffer: OrphanBlocks::default(),
+    };
+    let handle = Handle {
+        control_chan: signal_chan_sender,
+    };
+    (ctx, handle)
+}
+
+impl Handle {
+    pub fn exit(&self) {
+        self.control_chan.send(ControlSignal::Exit).unwrap();
+    }
+
+    /// Queue blocks to be validated and connected, this never blocks the caller
+    pub fn connect_blocks(&self, blocks: Vec<Block>) {
+        self.control_chan
+            .send(ControlSignal::ConnectBlocks(blocks))
+            .unwrap();
+    }
+
+    /// Connect blocks and return once the actor is done with them, whether they were accepted
+    /// or not. The caller must not hold the blockchain or mempool lock.
+    pub fn connect_blocks_and_wait(&self, blocks: Vec<Block>) {
+        let (done_sender, done_receiver) = bounded(1);
+        self.control_chan
+            .send(ControlSignal::ConnectBlocksAndNotify(blocks, done_sender))
+            .unwrap();
+        done_receiver.recv().unwrap();
+    }
+}
+
+/// Proof-of-work check shared by every block entering the chain, against the target set by the
+/// genesis block
+fn pow_valid(block: &Block, target: H256) -> bool {
+    block.hash() < block.header.difficulty && block.header.difficulty == target
+}
+
+/// Why `block`, whose parent is stored, cannot be connected
+fn check_block(blockchain: &Blockchain, block: &Block) -> Result<(), String> {
+    if !pow_valid(block, blockchain.target()) {
+        return Err("invalid proof of work".to_string());
+    }
+    if block.content.content.len() > blockchain.params.max_block_transactions {
+        return Err("too many transactions".to_string());
+    }
+    blockchain
+        .validate_block(block)
+        .map_err(|e| format!("invalid transaction: {}", e))
+}
+
+/// Transaction hashes and height of `block` if it just became the tip of the chain
+fn extended_tip(blockchain: &Blockchain, block: &Block) -> Option<(Vec<H256>, u64)> {
+    let hash = block.hash();
+    if blockchain.tip() != hash {
+        return None;
+    }
+    let hashes = block.content.content.iter().map(|tx| tx.hash()).collect();
+    Some((hashes, blockchain.states[&hash].height))
+}
+
+impl Context {
+    pub fn start(mut self) {
+        thread::Builder::new()
+            .name("chain-actor".to_string())
+            .spawn(move || {
+                self.actor_loop();
+            })
+            .unwrap();
+        info!("Chain actor initialized");
+    }
+
+    fn actor_loop(&mut self) {
+        loop {
+            match self.control_chan.recv() {
+                Ok(ControlSignal::ConnectBlocks(blocks)) => self.connect_blocks(blocks),
+                Ok(ControlSignal::ConnectBlocksAndNotify(blocks, done)) => {
+                    self.connect_blocks(blocks);
+                    // the caller may have given up waiting
+                    let _ = done.send(());
+                }
+                Ok(ControlSignal::Exit) | Err(_) => {
+                    info!("Chain actor shutting down");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn connect_blocks(&mut self, blocks: Vec<Block>) {
+        let mut new_block_hashes: Vec<H256> = Vec::new();
+        let mut unseen: Vec<H256> = Vec::new();
+        // transactions and height of each block that became the tip, for the fee estimator
+        let mut confirmed: Vec<(Vec<H256>, u64)> = Vec::new();
+
+        let mut blockchain = self.blockchain.write().unwrap();
+        let old_tip = blockchain.tip();
+        for block in blocks.iter() {
+            // judge if the block already exists in the block chain
+            if blockchain.blocks.contains_key(&block.hash()) {
+                continue;
+            }
+            // parent is unknown, keep the block until the parent arrives
+            if !blockchain.blocks.contains_key(&block.header.parent) {
+                unseen.push(block.header.parent);
+                self.orphan_buffer.insert(block);
+                continue;
+            }
+
+            // connect the block, then the orphans waiting for it
+            let mut pending = vec![block.clone()];
+            while let Some(block) = pending.pop() {
+                let hash = block.hash();
+                if let Err(reason) = check_block(&blockchain, &block) {
+                    debug!("Reject block {} with {}", hash, reason);
+                    // an invalid block also invalidates everything built on top of it
+                    self.orphan_buffer.discard_descendants(hash);
+                    continue;
+                }
+                blockchain.insert(&block);
+                new_block_hashes.push(hash);
+                if let Some(c) = extended_tip(&blockchain, &block) {
+                    confirmed.push(c);
+                }
+                pending.extend(self.orphan_buffer.take_children(&hash));
+            }
+        }
+        // how the longest chain moved, published once the locks are released
+        let (disconnected, connected) = blockchain.reorganization(old_tip);
+        let mut events = Vec::new();
+        let mut included = HashSet::new();
+        // transactions of the blocks leaving the longest chain, oldest first, but coinbases
+        let mut restored: Vec<SignedTransaction> = Vec::new();
+        for hash in disconnected.iter().rev() {
+            let block = &blockchain.blocks[hash];
+            let transactions = block.content.content.iter();
+            restored.extend(transactions.filter(|tx| !tx.transcation.input.is_empty()).cloned());
+        }
+        for hash in disconnected.iter() {
+            let height = blockchain.states[hash].height;
+            let hash = hash.to_string();
+            events.push(Event::BlockDisconnected { hash, height });
+        }
+        for hash in connected.iter() {
+            let block = &blockchain.blocks[hash];
+            included.extend(block.content.content.iter().map(|tx| tx.hash()));
+            let height = blockchain.states[hash].height;
+            let hash = hash.to_string();
+            events.push(Event::BlockConnected { hash, height });
+        }
+        if let Some(hash) = connected.last() {
+            let height = blockchain.states[hash].height;
+            let hash = hash.to_string();
+            events.push(Event::NewTip { hash, height });
+        }
+        // snapshot the new tip state so the mempool can be cleaned without holding the chain lock
+        let tip_state = if new_block_hashes.is_empty() {
+            None
+        } else {
+            Some(blockchain.tip_state().clone())
+        };
+        drop(blockchain);
+
+        let mut adopted = Vec::new();
+        if let Some(tip_state) = tip_state {
+            let mut mempool = self.mempool.lock().unwrap();
+            for (hashes, height) in confirmed.iter() {
+                mempool.record_block(hashes, *height);
+            }
+            // before pruning, so the transactions spending their outputs are kept
+            adopted = mempool.restore(&tip_state, &restored);
+            // transactions the new blocks confirmed are not evictions
+            let pruned = mempool.prune(&tip_state);
+            for hash in pruned.iter().filter(|h| !included.contains(h)) {
+                events.push(Event::TransactionEvicted {
+                    hash: hash.to_string(),
+                    reason: "invalid".to_string(),
+                });
+            }
+            // time locks may have passed, and orphans may have been waiting for the transactions
+            // confirmed or released
+            adopted.extend(mempool.release_final(&tip_state));
+            let mut parents: Vec<H256> = included.into_iter().collect();
+            parents.extend(adopted.iter().map(|(h, _, _)| *h));
+            adopted.extend(mempool.adopt_orphans(&tip_state, &parents));
+            if !confirmed.is_empty() {
+                if let Err(e) = mempool.fee_estimator.save() {
+                    warn!("Failed to save fee estimates: {}", e);
+                }
+            }
+        }
+
+        for event in events {
+            self.events.publish(event);
+        }
+        for (hash, fee, evicted) in adopted.iter() {
+            self.events.transaction_accepted(*hash, *fee, evicted);
+        }
+        if !adopted.is_empty() {
+            let hashes = adopted.iter().map(|(h, _, _)| *h).collect();
+            self.server.broadcast(Message::NewTransactionHashes(hashes));
+        }
+        if !new_block_hashes.is_empty() {
+            self.server
+                .broadcast(Message::NewBlockHashes(new_block_hashes));
+        }
+        if !unseen.is_empty() {
+            self.server.broadcast(Message::GetBlocks(unseen));
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use crate::blockchain::params::ChainParams;
+    use crate::events::EventBus;
+    use crate::miner;
+    use crate::network::server::TestReceiver;
+    use crate::wallet::Wallet;
+    use ntest::timeout;
+
+    type Node = (Arc<RwLock<Blockchain>>, Arc<Mutex<Mempool>>, Handle, TestReceiver);
+
+    fn start(blockchain: Blockchain) -> Node {
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        let (ctx, chain) = new(&server, &blockchain, &mempool, &EventBus::new());
+        ctx.start();
+        (blockchain, mempool, chain, server_receiver)
+    }
+
+    /// Block on `parent` with the transactions of `mempool` valid after the tip, told apart from
+    /// its siblings by `timestamp`
+    fn mine(blockchain: &Blockchain, parent: H256, mempool: &Mempool, timestamp: u128) -> Block {
+        let state = blockchain.tip_state();
+        let target = blockchain.target();
+        let params = &blockchain.params;
+        let mut block = miner::template(parent, state, params, target, mempool, None, timestamp);
+        while block.hash() >= target {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn sibling_orphans() {
+        let (blockchain, _, chain, _server) =
+            start(Blockchain::with_params(ChainParams::regtest()).unwrap());
+        let (parent, children) = {
+            let blockchain = blockchain.read().unwrap();
+            let empty = Mempool::new();
+            let parent = mine(&blockchain, blockchain.tip(), &empty, 1);
+            let children: Vec<Block> = (2..4)
+                .map(|t| mine(&blockchain, parent.hash(), &empty, t))
+                .collect();
+            (parent, children)
+        };
+        // both children wait for the parent, neither replaces the other
+        chain.connect_blocks_and_wait(children.clone());
+        assert_eq!(blockchain.read().unwrap().blocks.len(), 1);
+        chain.connect_blocks_and_wait(vec![parent]);
+        let blockchain = blockchain.read().unwrap();
+        assert_eq!(blockchain.blocks.len(), 4);
+        assert!(children.iter().all(|b| blockchain.blocks.contains_key(&b.hash())));
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphans_of_confirmed_transactions() {
+        let mut wallet = Wallet::from_seed(&[4u8; 32]);
+        let payer = wallet.generate_key("payer").unwrap();
+        let payee = [6u8; 20].into();
+        let mut params = ChainParams::regtest();
+        params.genesis.allocations = vec![Allocation {
+            address: payer.encode(params.address_prefix),
+            value: 5000,
+        }];
+        let (blockchain, mempool, chain, _server) = start(Blockchain::with_params(params).unwrap());
+        let state = blockchain.read().unwrap().tip_state().clone();
+        let parent = wallet
+            .create_transaction(&state, &Mempool::new(), payee, 2000, 10)
+            .unwrap();
+        let mut after = state.clone();
+        after.apply(&parent);
+        let child = wallet
+            .create_transaction(&after, &Mempool::new(), payee, 1000, 10)
+            .unwrap();
+
+        // the child came first, and its parent is only seen in a block
+        let input = child.transcation.input.clone();
+        assert!(mempool.lock().unwrap().orphans.insert(child.clone(), &input));
+        let mut confirming = Mempool::new();
+        confirming.insert(&parent, 10).unwrap();
+        let block = {
+            let blockchain = blockchain.read().unwrap();
+            mine(&blockchain, blockchain.tip(), &confirming, 1)
+        };
+        chain.connect_blocks_and_wait(vec![block]);
+        let mempool = mempool.lock().unwrap();
+        assert!(mempool.orphans.is_empty());
+        assert!(mempool.tx_map.contains_key(&child.hash()));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
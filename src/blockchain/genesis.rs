@@ -0,0 +1,223 @@
+use crate::types::address::{Address, AddressError};
+use crate::types::block::{Block, BlockContent, BlockHeader};
+use crate::types::hash::{Hashable, H256};
+use crate::types::merkle::MerkleTree;
+use crate::types::state::{State, MAX_OUTPUTS};
+use crate::types::transaction::{SignedTransaction, Transaction, UTXO_output};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+// The genesis block is described by a spec file rather than hardcoded, so that a test network can
+// be launched again with the same initial coins and every node agrees on the same first block.
+// The allocations are the outputs of transactions without inputs in the genesis block, which is
+// never validated.
+//
+// {
+//   "network": "demo",
+//   "timestamp": 0,
+//   "target": "00ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+//   "allocations": [{ "address": "bc1...", "value": 1000000 }],
+//   "hash": "..."
+// }
+
+/// Initial coins paid to an address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: String,
+    pub value: u64,
+}
+
+/// Contents of a genesis spec file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GenesisSpec {
+    /// name of the network, only for humans
+    pub network: String,
+    /// milliseconds since the epoch
+    #[serde(default)]
+    pub timestamp: u128,
+    /// hex encoded, block hashes must be below it
+    pub target: String,
+    #[serde(default)]
+    pub allocations: Vec<Allocation>,
+    /// hex encoded hash the genesis block must have, checked when given
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+/// Reasons a genesis spec cannot be used
+#[derive(Debug)]
+pub enum GenesisError {
+    Io(std::io::Error),
+    Parse(serde_json::Error),
+    InvalidTarget(String),
+    InvalidAddress { address: String, error: AddressError },
+    HashMismatch { expected: String, computed: H256 },
+}
+
+impl From<std::io::Error> for GenesisError {
+    fn from(e: std::io::Error) -> Self {
+        GenesisError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for GenesisError {
+    fn from(e: serde_json::Error) -> Self {
+        GenesisError::Parse(e)
+    }
+}
+
+impl std::fmt::Display for GenesisError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GenesisError::Io(e) => write!(f, "cannot read genesis spec: {}", e),
+            GenesisError::Parse(e) => write!(f, "invalid genesis spec: {}", e),
+            GenesisError::InvalidTarget(t) => {
+                write!(f, "target {} is not 32 hex encoded bytes", t)
+            }
+            GenesisError::InvalidAddress { address, error } => {
+                write!(f, "invalid allocation address {}: {}", address, error)
+            }
+            GenesisError::HashMismatch { expected, computed } => write!(
+                f,
+                "genesis block hashes to {}, the spec expects {}",
+                computed, expected
+            ),
+        }
+    }
+}
+
+impl Default for GenesisSpec {
+    /// A network without initial coins, mined at the default target
+    fn default() -> Self {
+        let mut target = [255u8; 32];
+        target[0] = 0;
+        GenesisSpec {
+            network: "local".to_string(),
+            timestamp: 0,
+            target: hex::encode(target),
+            allocations: Vec::new(),
+            hash: None,
+        }
+    }
+}
+
+impl GenesisSpec {
+    pub fn load(path: &Path) -> Result<Self, GenesisError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    pub fn target(&self) -> Result<H256, GenesisError> {
+        let bytes = hex::decode(&self.target)
+            .map_err(|_| GenesisError::InvalidTarget(self.target.clone()))?;
+        let bytes: [u8; 32] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| GenesisError::InvalidTarget(self.target.clone()))?;
+        Ok(bytes.into())
+    }
+
+    /// The genesis block and the state after it, checked against `hash` if the spec has one.
+    /// Allocations pay addresses of the network whose addresses start with `prefix`.
+    pub fn build(&self, prefix: &str) -> Result<(Block, State), GenesisError> {
+        let mut outputs = Vec::new();
+        for allocation in self.allocations.iter() {
+            let address = Address::decode(&allocation.address, prefix).map_err(|error| {
+                GenesisError::InvalidAddress {
+                    address: allocation.address.clone(),
+                    error,
+                }
+            })?;
+            outputs.push(UTXO_output {
+                receipient_address: address,
+                value: allocation.value,
+                script: None,
+            });
+        }
+        let transactions: Vec<SignedTransaction> = outputs
+            .chunks(MAX_OUTPUTS)
+            .map(|chunk| SignedTransaction {
+                transcation: Transaction {
+                    output: chunk.to_vec(),
+                    ..Default::default()
+                },
+                witnesses: Vec::new(),
+            })
+            .collect();
+
+        let header = BlockHeader {
+            parent: [0u8; 32].into(),
+            nonce: 0,
+            difficulty: self.target()?,
+            timestamp: self.timestamp,
+            merkle_root: MerkleTree::new(&transactions).root(),
+        };
+        let block = Block {
+            header,
+            content: BlockContent {
+                content: transactions,
+            },
+        };
+        if let Some(expected) = &self.hash {
+            let computed = block.hash();
+            if !computed.to_string().eq_ignore_ascii_case(expected) {
+                return Err(GenesisError::HashMismatch {
+                    expected: expected.clone(),
+                    computed,
+                });
+            }
+        }
+
+        let mut state = State::new();
+        for tx in block.content.content.iter() {
+            state.apply(tx);
+        }
+        // outputs missing from `created` count as created by the genesis block
+        state.created.clear();
+        state.median_time_past = self.timestamp;
+        Ok((block, state))
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::ADDRESS_PREFIX;
+
+    #[test]
+    fn genesis_from_spec() {
+        let address: Address = [7u8; 20].into();
+        let json = format!(
+            r#"{{
+                "network": "test",
+                "timestamp": 1650000000000,
+                "target": "0000ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "allocations": [{{ "address": "{}", "value": 500 }}]
+            }}"#,
+            address.encode(ADDRESS_PREFIX)
+        );
+        let mut spec: GenesisSpec = serde_json::from_str(&json).unwrap();
+        let (block, state) = spec.build(ADDRESS_PREFIX).unwrap();
+        assert_eq!(block.header.difficulty, spec.target().unwrap());
+        assert_eq!(state.utxos_of(&address).len(), 1);
+        assert_eq!(state.utxos_of(&address)[0].1.value, 500);
+        assert_eq!(state.height, 0);
+
+        // the same spec always gives the same block, and a wrong hash is caught
+        spec.hash = Some(block.hash().to_string());
+        assert_eq!(spec.build(ADDRESS_PREFIX).unwrap().0.hash(), block.hash());
+        spec.allocations[0].value = 501;
+        assert!(matches!(
+            spec.build(ADDRESS_PREFIX),
+            Err(GenesisError::HashMismatch { .. })
+        ));
+
+        spec.target = "00ff".to_string();
+        assert!(matches!(spec.target(), Err(GenesisError::InvalidTarget(_))));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
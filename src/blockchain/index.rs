@@ -0,0 +1,229 @@
+use super::Blockchain;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::UTXO_input;
+use std::collections::HashMap;
+
+// Secondary indexes over the longest chain, genesis block included, so that lookups by
+// transaction or address do not scan every block. They are optional: `Blockchain` keeps them up
+// to date while they are enabled, disconnecting the blocks a reorganization abandons and
+// connecting the ones it adopts, and they can always be rebuilt from the stored blocks.
+
+/// Where a transaction of the longest chain is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLocation {
+    pub block: H256,
+    /// position in the block
+    pub position: usize,
+}
+
+/// An output created in the longest chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedOutput {
+    pub address: Address,
+    pub value: u64,
+    /// transaction of the longest chain spending it
+    pub spent_by: Option<H256>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Index {
+    transactions: HashMap<H256, TxLocation>,
+    outputs: HashMap<UTXO_input, IndexedOutput>,
+    /// outputs paying each address, in chain order
+    addresses: HashMap<Address, Vec<UTXO_input>>,
+}
+
+impl Index {
+    /// Index the longest chain of `blockchain` from its stored blocks
+    pub fn build(blockchain: &Blockchain) -> Self {
+        let mut index = Index::default();
+        let blocks =
+            std::iter::once(blockchain.genesis).chain(blockchain.all_blocks_in_longest_chain());
+        for hash in blocks {
+            index.connect(&blockchain.blocks[&hash]);
+        }
+        index
+    }
+
+    /// Add a block extending the indexed chain
+    pub fn connect(&mut self, block: &Block) {
+        let hash = block.hash();
+        for (position, tx) in block.content.content.iter().enumerate() {
+            let tx_hash = tx.hash();
+            for input in tx.transcation.input.iter() {
+                if let Some(output) = self.outputs.get_mut(input) {
+                    output.spent_by = Some(tx_hash);
+                }
+            }
+            for (i, output) in tx.transcation.output.iter().enumerate() {
+                let outpoint = UTXO_input {
+                    prev_tx_hash: tx_hash,
+                    index: i as u8,
+                };
+                self.addresses
+                    .entry(output.receipient_address)
+                    .or_default()
+                    .push(outpoint.clone());
+                let indexed = IndexedOutput {
+                    address: output.receipient_address,
+                    value: output.value,
+                    spent_by: None,
+                };
+                self.outputs.insert(outpoint, indexed);
+            }
+            let location = TxLocation {
+                block: hash,
+                position,
+            };
+            self.transactions.insert(tx_hash, location);
+        }
+    }
+
+    /// Remove the last block of the indexed chain, undoing `connect`
+    pub fn disconnect(&mut self, block: &Block) {
+        let hash = block.hash();
+        for tx in block.content.content.iter().rev() {
+            let tx_hash = tx.hash();
+            for (i, output) in tx.transcation.output.iter().enumerate().rev() {
+                let outpoint = UTXO_input {
+                    prev_tx_hash: tx_hash,
+                    index: i as u8,
+                };
+                self.outputs.remove(&outpoint);
+                let address = output.receipient_address;
+                if let Some(outpoints) = self.addresses.get_mut(&address) {
+                    // the outputs of the last block are at the end
+                    if let Some(p) = outpoints.iter().rposition(|o| *o == outpoint) {
+                        outpoints.remove(p);
+                    }
+                    if outpoints.is_empty() {
+                        self.addresses.remove(&address);
+                    }
+                }
+            }
+            for input in tx.transcation.input.iter() {
+                if let Some(output) = self.outputs.get_mut(input) {
+                    output.spent_by = None;
+                }
+            }
+            if self.transactions.get(&tx_hash).map(|l| l.block) == Some(hash) {
+                self.transactions.remove(&tx_hash);
+            }
+        }
+    }
+
+    pub fn transaction(&self, hash: &H256) -> Option<TxLocation> {
+        self.transactions.get(hash).cloned()
+    }
+
+    pub fn output(&self, outpoint: &UTXO_input) -> Option<&IndexedOutput> {
+        self.outputs.get(outpoint)
+    }
+
+    /// Outputs paying `address`, spent or not, in chain order
+    pub fn outputs_of(&self, address: &Address) -> Vec<(UTXO_input, &IndexedOutput)> {
+        self.addresses
+            .get(address)
+            .map(|outpoints| {
+                outpoints
+                    .iter()
+                    .map(|o| (o.clone(), &self.outputs[o]))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Every address paid by an output of the longest chain
+    pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+        self.addresses.keys()
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::genesis::Allocation;
+    use crate::blockchain::params::ChainParams;
+    use crate::miner;
+    use crate::types::transaction::Mempool;
+    use crate::wallet::Wallet;
+
+    /// Mine a block on `parent`, paying the reward to `reward_to`
+    fn mine(
+        blockchain: &mut Blockchain,
+        parent: H256,
+        mempool: &Mempool,
+        reward_to: Address,
+    ) -> Block {
+        let block = miner::template(
+            parent,
+            &blockchain.states[&parent],
+            &blockchain.params,
+            blockchain.target(),
+            mempool,
+            Some(reward_to),
+            0,
+        );
+        blockchain.insert(&block);
+        block
+    }
+
+    #[test]
+    fn follows_reorganizations() {
+        let mut wallet = Wallet::from_seed(&[3u8; 32]);
+        let payer = wallet.generate_key("payer").unwrap();
+        let payee: Address = [6u8; 20].into();
+        let (miner_a, miner_b): (Address, Address) = ([7u8; 20].into(), [8u8; 20].into());
+        let mut params = ChainParams::regtest();
+        params.genesis.allocations = vec![Allocation {
+            address: payer.encode(params.address_prefix),
+            value: 5000,
+        }];
+        let mut blockchain = Blockchain::with_params(params).unwrap();
+        blockchain.enable_index();
+        let fork = blockchain.tip();
+
+        // one branch pays the payee
+        let mut mempool = Mempool::new();
+        let state = blockchain.tip_state().clone();
+        let tx = wallet
+            .create_transaction(&state, &mempool, payee, 2000, 10)
+            .unwrap();
+        mempool.insert(&tx, state.fee(&tx)).unwrap();
+        let paying = mine(&mut blockchain, fork, &mempool, miner_a);
+        let index = blockchain.index.as_ref().unwrap();
+        let location = index.transaction(&tx.hash()).unwrap();
+        assert_eq!(location.block, paying.hash());
+        assert_eq!(location.position, 1);
+        let allocation = index.outputs_of(&payer)[0].0.clone();
+        assert_eq!(index.output(&allocation).unwrap().spent_by, Some(tx.hash()));
+        assert_eq!(index.outputs_of(&payee).len(), 1);
+
+        // a longer branch without the payment replaces it
+        let empty = Mempool::new();
+        let sibling = mine(&mut blockchain, fork, &empty, miner_b);
+        assert_eq!(blockchain.tip(), paying.hash());
+        let longer = mine(&mut blockchain, sibling.hash(), &empty, miner_b);
+        let moved = (vec![paying.hash()], vec![sibling.hash(), longer.hash()]);
+        assert_eq!(blockchain.reorganization(paying.hash()), moved);
+
+        let index = blockchain.index.as_ref().unwrap();
+        assert!(index.transaction(&tx.hash()).is_none());
+        assert_eq!(index.output(&allocation).unwrap().spent_by, None);
+        assert!(index.outputs_of(&payee).is_empty());
+        assert_eq!(index.outputs_of(&miner_a).len(), 0);
+        assert_eq!(index.outputs_of(&miner_b).len(), 2);
+
+        // the same as indexing the new chain from scratch
+        let rebuilt = Index::build(&blockchain);
+        assert_eq!(rebuilt.transactions, index.transactions);
+        assert_eq!(rebuilt.outputs, index.outputs);
+        assert_eq!(rebuilt.addresses, index.addresses);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
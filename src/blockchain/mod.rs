@@ -1,64 +1,68 @@
-use crate::types::block::{Block, BlockContent, BlockHeader};
+pub mod actor;
+pub mod genesis;
+pub mod index;
+pub mod params;
+
+use crate::types::address::Address;
+use crate::types::block::Block;
 use crate::types::hash::{Hashable, H256};
-use crate::types::merkle::MerkleTree;
-use crate::types::transaction::Mempool;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use crate::types::state::{State, TransactionError, MAX_OUTPUTS};
+use crate::types::timelock::{median_time_past, MEDIAN_TIME_SPAN};
+use genesis::GenesisError;
+use index::Index;
+use params::ChainParams;
+use std::collections::{HashMap, HashSet};
 
+// The mempool used to live here behind its own mutex, which meant every user had to take the
+// blockchain lock first and then the mempool lock nested inside it. It is now shared separately
+// (see main.rs), so the two locks are never held at the same time.
 pub struct Blockchain {
     pub tip: H256,                    // tip is the last block's hash in the longest chain
     pub blocks: HashMap<H256, Block>, // mapping hashing of block and the block
     pub lengths: HashMap<H256, u32>,  // mapping hashing of block and its length index
-    pub mempool: Arc<Mutex<Mempool>>,
+    pub states: HashMap<H256, State>, // mapping hashing of block and the state after executing it
+    pub genesis: H256,                // hash of the first block, from the genesis spec
+    pub params: ChainParams,          // network the chain belongs to
+    pub index: Option<Index>,         // transaction and address indexes, if enabled
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new mainnet blockchain, only containing the genesis block
     pub fn new() -> Self {
-        // generate elements for a new block
-        let parent: H256 = [0u8; 32].into();
-
-        let nonce = 0u32;
-
-        let mut _difficulty = [0u8; 32];
-        _difficulty[0] = 16u8;
-        let difficulty: H256 = _difficulty.into();
-
-        let timestamp = 0u128;
-
-        let transactions = Vec::new();
-        let merkle_tree = MerkleTree::new(&transactions);
-        let merkle_root = merkle_tree.root();
+        Self::with_params(ChainParams::mainnet()).unwrap()
+    }
 
-        // assemble block
-        let header = BlockHeader {
-            parent,
-            nonce,
-            difficulty,
-            timestamp,
-            merkle_root,
-        };
-        let content = BlockContent {
-            content: transactions,
-        };
-        let new_block = Block { header, content };
-        let _hash = new_block.hash();
+    /// Create a new blockchain of the network described by `params`, only containing its
+    /// genesis block
+    pub fn with_params(params: ChainParams) -> Result<Self, GenesisError> {
+        let (genesis, state) = params.genesis.build(params.address_prefix)?;
+        let _hash = genesis.hash();
 
         // two hashmap store blocks and lengths
         let mut _blocks = HashMap::new();
         let mut _lengths = HashMap::new();
 
-        _blocks.insert(_hash, new_block);
+        _blocks.insert(_hash, genesis);
         _lengths.insert(_hash, 0);
 
-        // add mempool
-        let mut _mempool = Arc::new(Mutex::new(Mempool::new()));
-        Self {
+        let mut _states = HashMap::new();
+        _states.insert(_hash, state);
+
+        Ok(Self {
             tip: _hash,
+            genesis: _hash,
             blocks: _blocks,
             lengths: _lengths,
-            mempool: _mempool,
-        }
+            states: _states,
+            params,
+            index: None,
+        })
     }
 
     /// Insert a block into blockchain
@@ -74,6 +78,15 @@ impl Blockchain {
         // Parent of current block
         let cur_parent = block.header.parent;
 
+        // Execute the transactions on top of the parent's state
+        let mut state = self.states[&cur_parent].clone();
+        for tx in block.content.content.iter() {
+            state.apply(tx);
+        }
+        state.height += 1;
+        state.median_time_past = self.median_time_past(block);
+        self.states.insert(block_hash, state);
+
         // Add the cloned block into blocks map
         self.blocks.insert(block_hash, new_block);
 
@@ -83,10 +96,71 @@ impl Blockchain {
 
         // Update the tip accroding to longest sub-chain
         if self.lengths[&block_hash] > self.lengths[&self.tip] {
+            let old_tip = self.tip;
             self.tip = block_hash;
+            self.update_index(old_tip);
         }
     }
 
+    /// Keep transaction and address indexes from now on, building them from the stored blocks
+    pub fn enable_index(&mut self) {
+        self.rebuild_index();
+    }
+
+    /// Index the longest chain again from the stored blocks
+    pub fn rebuild_index(&mut self) {
+        self.index = Some(Index::build(self));
+    }
+
+    /// Move the indexes from the chain ending with `old_tip` to the longest chain
+    fn update_index(&mut self, old_tip: H256) {
+        if self.index.is_none() {
+            return;
+        }
+        let (disconnected, connected) = self.reorganization(old_tip);
+        let index = self.index.as_mut().unwrap();
+        for hash in disconnected.iter() {
+            index.disconnect(&self.blocks[hash]);
+        }
+        for hash in connected.iter() {
+            index.connect(&self.blocks[hash]);
+        }
+    }
+
+    /// Blocks that left the longest chain since it ended with `old_tip`, from the old tip down,
+    /// and blocks that joined it, in chain order
+    pub fn reorganization(&self, old_tip: H256) -> (Vec<H256>, Vec<H256>) {
+        let (mut old, mut new) = (old_tip, self.tip);
+        let (mut disconnected, mut connected) = (Vec::new(), Vec::new());
+        while old != new {
+            if self.lengths[&old] >= self.lengths[&new] {
+                disconnected.push(old);
+                old = self.blocks[&old].header.parent;
+            } else {
+                connected.push(new);
+                new = self.blocks[&new].header.parent;
+            }
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Median-time-past of the chain ending with `block`, whose ancestors are already stored
+    fn median_time_past(&self, block: &Block) -> u128 {
+        let mut timestamps = vec![block.header.timestamp];
+        let mut cur = block.header.parent;
+        while timestamps.len() < MEDIAN_TIME_SPAN {
+            match self.blocks.get(&cur) {
+                Some(parent) => {
+                    timestamps.push(parent.header.timestamp);
+                    cur = parent.header.parent;
+                }
+                None => break,
+            }
+        }
+        median_time_past(&timestamps)
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip(&self) -> H256 {
         return self.tip;
@@ -96,6 +170,59 @@ impl Blockchain {
         return self.blocks[&hash].clone();
     }
 
+    /// Every block hash must be below the target of the genesis block, there is no retargeting
+    pub fn target(&self) -> H256 {
+        self.blocks[&self.genesis].header.difficulty
+    }
+
+    /// Get the state after executing the last block of the longest chain
+    pub fn tip_state(&self) -> &State {
+        &self.states[&self.tip]
+    }
+
+    /// Check every transaction of a block against the state of its parent, in block order,
+    /// so that two transactions of the same block cannot spend the same output.
+    ///
+    /// A block may start with a coinbase, a transaction without inputs creating at most the
+    /// block reward and the fees of the block. Its lock time is the height of the parent, which
+    /// is always final and tells coinbases of different blocks apart (as in BIP 34).
+    pub fn validate_block(&self, block: &Block) -> Result<(), TransactionError> {
+        let mut state = self.states[&block.header.parent].clone();
+        let (coinbase, transactions) = match block.content.content.split_first() {
+            Some((first, rest)) if first.transcation.input.is_empty() => (Some(first), rest),
+            _ => (None, &block.content.content[..]),
+        };
+        let mut fees = 0u64;
+        for tx in transactions.iter() {
+            state.validate(tx)?;
+            fees = fees.saturating_add(state.fee(tx));
+            state.apply(tx);
+        }
+        if let Some(coinbase) = coinbase {
+            let parent_height = self.states[&block.header.parent].height;
+            if self.params.block_reward == 0 || coinbase.transcation.lock_time != parent_height {
+                return Err(TransactionError::InvalidCoinbase);
+            }
+            if coinbase.transcation.output.len() > MAX_OUTPUTS {
+                let outputs = coinbase.transcation.output.len();
+                return Err(TransactionError::TooManyOutputs(outputs));
+            }
+            let allowed = self.params.block_reward.saturating_add(fees);
+            let output = coinbase
+                .transcation
+                .output
+                .iter()
+                .fold(0u64, |acc, o| acc.saturating_add(o.value));
+            if output > allowed {
+                return Err(TransactionError::Overspend {
+                    input: allowed,
+                    output,
+                });
+            }
+        }
+        Ok(())
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut res = Vec::new();
@@ -115,6 +242,21 @@ impl Blockchain {
         res.reverse();
         res
     }
+
+    /// Every address that received an output in the longest chain, used to recover HD wallets
+    pub fn used_addresses(&self) -> HashSet<Address> {
+        if let Some(index) = &self.index {
+            return index.addresses().cloned().collect();
+        }
+        std::iter::once(self.genesis)
+            .chain(self.all_blocks_in_longest_chain())
+            .collect::<Vec<H256>>()
+            .iter()
+            .flat_map(|hash| self.blocks[hash].content.content.iter())
+            .flat_map(|tx| tx.transcation.output.iter())
+            .map(|output| output.receipient_address)
+            .collect()
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
@@ -0,0 +1,133 @@
+use super::genesis::GenesisSpec;
+use crate::types::transaction::MAX_BLOCK_TRANSACTIONS;
+
+// Everything that differs between the networks a node can join. Nodes of different networks do
+// not talk to each other: their frames start with different magic bytes, their genesis blocks
+// differ and their addresses have different prefixes, so coins cannot be sent to the wrong one.
+
+/// Parameters of a network, selected with `--network`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    pub name: &'static str,
+    /// genesis block, whose target every block must meet; `--genesis` replaces it
+    pub genesis: GenesisSpec,
+    /// most transactions a block may hold
+    pub max_block_transactions: usize,
+    /// bound of the channel from the P2P server to the workers
+    pub message_channel_size: usize,
+    pub p2p_port: u16,
+    pub api_port: u16,
+    /// first bytes of every P2P frame
+    pub magic: [u8; 4],
+    /// human-readable part of addresses
+    pub address_prefix: &'static str,
+    /// most a coinbase transaction may create besides the fees of its block, coinbases are
+    /// not allowed without a reward
+    pub block_reward: u64,
+    /// whether blocks can be generated on request through `/regtest/generate`
+    pub on_demand_mining: bool,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            name: "mainnet",
+            genesis: GenesisSpec {
+                network: "mainnet".to_string(),
+                ..Default::default()
+            },
+            max_block_transactions: MAX_BLOCK_TRANSACTIONS,
+            message_channel_size: 10000,
+            p2p_port: 6000,
+            api_port: 7000,
+            magic: [0xf9, 0xbe, 0xb4, 0xd9],
+            address_prefix: "bc",
+            block_reward: 0,
+            on_demand_mining: false,
+        }
+    }
+
+    /// A public test network, with blocks easier to mine
+    pub fn testnet() -> Self {
+        let mut target = [255u8; 32];
+        target[0] = 0x0f;
+        ChainParams {
+            name: "testnet",
+            genesis: GenesisSpec {
+                network: "testnet".to_string(),
+                target: hex::encode(target),
+                ..Default::default()
+            },
+            p2p_port: 16000,
+            api_port: 17000,
+            magic: [0x0b, 0x11, 0x09, 0x07],
+            address_prefix: "tb",
+            ..Self::mainnet()
+        }
+    }
+
+    /// A local network for tests, where about every other hash is a valid block so blocks can
+    /// be generated on demand, paying a reward to fund test wallets
+    pub fn regtest() -> Self {
+        let mut target = [255u8; 32];
+        target[0] = 0x7f;
+        ChainParams {
+            name: "regtest",
+            genesis: GenesisSpec {
+                network: "regtest".to_string(),
+                target: hex::encode(target),
+                ..Default::default()
+            },
+            p2p_port: 26000,
+            api_port: 27000,
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            address_prefix: "bcrt",
+            block_reward: 1_000_000,
+            on_demand_mining: true,
+            ..Self::mainnet()
+        }
+    }
+
+    /// The preset called `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            "testnet" => Some(Self::testnet()),
+            "regtest" => Some(Self::regtest()),
+            _ => None,
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+
+    #[test]
+    fn presets() {
+        let names = ["mainnet", "testnet", "regtest"];
+        let chains: Vec<Blockchain> = names
+            .iter()
+            .map(|name| Blockchain::with_params(ChainParams::from_name(name).unwrap()).unwrap())
+            .collect();
+        assert_eq!(ChainParams::from_name("simnet"), None);
+        // every network starts from its own genesis block
+        assert_ne!(chains[0].genesis, chains[1].genesis);
+        assert_ne!(chains[1].genesis, chains[2].genesis);
+        assert_eq!(chains[0].genesis, Blockchain::new().genesis);
+        assert!(chains[2].target() > chains[1].target());
+        assert!(chains[1].target() > chains[0].target());
+        assert_ne!(ChainParams::regtest().magic, ChainParams::mainnet().magic);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,208 @@
+use crate::types::hash::H256;
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+// What happens to a node that clients may want pushed to them instead of polling. The chain
+// actor, the network server and workers, the miner and the API publish events on a bus shared by
+// the whole node, and the API server streams them to its subscribers. Publishing never blocks: a
+// subscriber falling more than `SUBSCRIBER_BUFFER` events behind is dropped, and has to subscribe
+// again and catch up from the REST routes.
+
+pub const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// Kinds of events a subscriber can pick
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// the longest chain ends with another block
+    Tip,
+    /// blocks joining or leaving the longest chain
+    Blocks,
+    /// transactions entering or leaving the mempool, other than by being confirmed
+    Mempool,
+    Peers,
+    /// blocks found by the miner thread
+    Mining,
+}
+
+impl std::str::FromStr for Topic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tip" => Ok(Topic::Tip),
+            "blocks" => Ok(Topic::Blocks),
+            "mempool" => Ok(Topic::Mempool),
+            "peers" => Ok(Topic::Peers),
+            "mining" => Ok(Topic::Mining),
+            _ => Err(format!("unknown topic {}", s)),
+        }
+    }
+}
+
+/// Hashes and addresses are strings in the format `Display` writes, as in the REST routes
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    NewTip {
+        hash: String,
+        height: u64,
+    },
+    /// joined the longest chain, in chain order
+    BlockConnected {
+        hash: String,
+        height: u64,
+    },
+    /// left the longest chain in a reorganization, tip first
+    BlockDisconnected {
+        hash: String,
+        height: u64,
+    },
+    TransactionAccepted {
+        hash: String,
+        fee: u64,
+    },
+    /// `replaced` by a transaction paying more, or `invalid` after the longest chain changed
+    TransactionEvicted {
+        hash: String,
+        reason: String,
+    },
+    PeerConnected {
+        address: String,
+    },
+    PeerDisconnected {
+        address: String,
+    },
+    /// found by the miner thread, before the chain actor checks and connects it
+    BlockMined {
+        hash: String,
+    },
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::NewTip { .. } => Topic::Tip,
+            Event::BlockConnected { .. } | Event::BlockDisconnected { .. } => Topic::Blocks,
+            Event::TransactionAccepted { .. } | Event::TransactionEvicted { .. } => Topic::Mempool,
+            Event::PeerConnected { .. } | Event::PeerDisconnected { .. } => Topic::Peers,
+            Event::BlockMined { .. } => Topic::Mining,
+        }
+    }
+
+    /// Name of the event, as in its `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::NewTip { .. } => "new_tip",
+            Event::BlockConnected { .. } => "block_connected",
+            Event::BlockDisconnected { .. } => "block_disconnected",
+            Event::TransactionAccepted { .. } => "transaction_accepted",
+            Event::TransactionEvicted { .. } => "transaction_evicted",
+            Event::PeerConnected { .. } => "peer_connected",
+            Event::PeerDisconnected { .. } => "peer_disconnected",
+            Event::BlockMined { .. } => "block_mined",
+        }
+    }
+}
+
+struct Subscriber {
+    /// every topic if empty
+    topics: HashSet<Topic>,
+    sender: Sender<Event>,
+}
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receive the events of `topics` published from now on, or of every topic if it is empty
+    pub fn subscribe(&self, topics: HashSet<Topic>) -> Receiver<Event> {
+        let (sender, receiver) = channel::bounded(SUBSCRIBER_BUFFER);
+        let subscriber = Subscriber { topics, sender };
+        self.subscribers.lock().unwrap().push(subscriber);
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let topic = event.topic();
+        self.subscribers.lock().unwrap().retain(|s| {
+            if !s.topics.is_empty() && !s.topics.contains(&topic) {
+                return true;
+            }
+            match s.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!(
+                        "Dropping an event subscriber {} events behind",
+                        SUBSCRIBER_BUFFER
+                    );
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Report a transaction added to the mempool, with the ones it replaced
+    pub fn transaction_accepted(&self, hash: H256, fee: u64, replaced: &[H256]) {
+        for h in replaced {
+            self.publish(Event::TransactionEvicted {
+                hash: h.to_string(),
+                reason: "replaced".to_string(),
+            });
+        }
+        self.publish(Event::TransactionAccepted {
+            hash: hash.to_string(),
+            fee,
+        });
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_and_slow_subscribers() {
+        let bus = EventBus::new();
+        let peers = bus.subscribe([Topic::Peers].iter().cloned().collect());
+        let all = bus.subscribe(HashSet::new());
+        let dropped = bus.subscribe(HashSet::new());
+        drop(dropped);
+
+        bus.publish(Event::NewTip {
+            hash: H256::default().to_string(),
+            height: 1,
+        });
+        bus.publish(Event::PeerConnected {
+            address: "127.0.0.1:6000".to_string(),
+        });
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 2);
+        assert_eq!(peers.try_recv().unwrap().name(), "peer_connected");
+        assert!(peers.try_recv().is_err());
+        assert_eq!(all.try_recv().unwrap().topic(), Topic::Tip);
+        assert_eq!(all.try_recv().unwrap().topic(), Topic::Peers);
+
+        // a subscriber that stops reading is let go once its buffer is full
+        for _ in 0..SUBSCRIBER_BUFFER + 1 {
+            bus.transaction_accepted(H256::default(), 1, &[]);
+        }
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        assert!(peers.try_recv().is_err());
+        let json = serde_json::to_string(&all.try_recv().unwrap()).unwrap();
+        assert!(json.starts_with(r#"{"event":"transaction_accepted","hash":"#));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
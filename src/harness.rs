@@ -0,0 +1,293 @@
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::Blockchain;
+use crate::miner;
+use crate::network::message::Message;
+use crate::node::{self, Node};
+use crate::types::hash::H256;
+use crate::types::transaction::Mempool;
+use crate::wallet::Wallet;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Several full nodes running in one process, each with its own P2P server, workers, chain actor,
+// miner and API server on ephemeral localhost ports, to test sync, reorgs and relay end to end.
+// Links are dialed from the lower index to the higher one. An incoming peer is known by an
+// ephemeral port only, so links are always cut from the dialing side, which knows the listening
+// address of the other node.
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// How long a new link may take to show up on both sides
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Harness {
+    pub nodes: Vec<Node>,
+    /// links currently up, as (dialer, listener)
+    links: Mutex<BTreeSet<(usize, usize)>>,
+    /// links cut by `partition`, restored by `heal`
+    cut: Mutex<BTreeSet<(usize, usize)>>,
+}
+
+impl Harness {
+    /// Start `n` unconnected regtest nodes
+    pub fn new(n: usize) -> Self {
+        Self::with_params(n, ChainParams::regtest())
+    }
+
+    pub fn with_params(n: usize, params: ChainParams) -> Self {
+        let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let nodes = (0..n)
+            .map(|_| {
+                // mined blocks pay the wallet, so two nodes never mine the very same block
+                let mut wallet = Wallet::new();
+                wallet.generate_key("default").unwrap();
+                Node::start(node::Config {
+                    blockchain: Blockchain::with_params(params.clone()).unwrap(),
+                    mempool: Mempool::new(),
+                    wallet,
+                    generator_wallet: Wallet::new(),
+                    p2p_addr: localhost,
+                    api_addr: localhost,
+                    p2p_workers: 2,
+                })
+                .unwrap()
+            })
+            .collect();
+        Harness {
+            nodes,
+            links: Mutex::new(BTreeSet::new()),
+            cut: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Connect two nodes, returning once both of them see the link
+    pub fn connect(&self, a: usize, b: usize) {
+        let (a, b) = (a.min(b), a.max(b));
+        assert_ne!(a, b, "a node cannot connect to itself");
+        if self.links.lock().unwrap().contains(&(a, b)) {
+            return;
+        }
+        let (dialer, listener) = (&self.nodes[a], &self.nodes[b]);
+        let incoming = listener.server.peers().len();
+        dialer.server.connect(listener.p2p_addr).unwrap();
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        while listener.server.peers().len() <= incoming {
+            assert!(Instant::now() < deadline, "node {} never saw node {}", b, a);
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.links.lock().unwrap().insert((a, b));
+        self.announce_tips(&[a, b]);
+    }
+
+    /// Connect every node to the next one
+    pub fn line(&self) {
+        for i in 1..self.nodes.len() {
+            self.connect(i - 1, i);
+        }
+    }
+
+    /// Connect every pair of nodes
+    pub fn full_mesh(&self) {
+        for a in 0..self.nodes.len() {
+            for b in a + 1..self.nodes.len() {
+                self.connect(a, b);
+            }
+        }
+    }
+
+    /// Cut every link between `group` and the other nodes, links inside either side stay up
+    pub fn partition(&self, group: &[usize]) {
+        let mut links = self.links.lock().unwrap();
+        let crossing: Vec<(usize, usize)> = links
+            .iter()
+            .filter(|(a, b)| group.contains(a) != group.contains(b))
+            .cloned()
+            .collect();
+        for (a, b) in crossing {
+            self.nodes[a].server.disconnect(self.nodes[b].p2p_addr);
+            links.remove(&(a, b));
+            self.cut.lock().unwrap().insert((a, b));
+        }
+        // the disconnections are queued, blocks mined meanwhile could still cross
+        let cut = self.cut.lock().unwrap();
+        for &(a, b) in cut.iter() {
+            let deadline = Instant::now() + CONNECT_TIMEOUT;
+            while self.nodes[a].server.peers().contains(&self.nodes[b].p2p_addr) {
+                assert!(Instant::now() < deadline, "link {}-{} never went down", a, b);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Restore the links cut by `partition`, and have every node announce its tip so that the
+    /// sides catch up with each other
+    pub fn heal(&self) {
+        let cut: Vec<(usize, usize)> = std::mem::take(&mut *self.cut.lock().unwrap())
+            .into_iter()
+            .collect();
+        for (a, b) in cut {
+            // wait for the old connection to be dropped before dialing again
+            let deadline = Instant::now() + CONNECT_TIMEOUT;
+            while self.nodes[a].server.peers().contains(&self.nodes[b].p2p_addr) {
+                assert!(Instant::now() < deadline, "link {}-{} never went down", a, b);
+                thread::sleep(POLL_INTERVAL);
+            }
+            self.connect(a, b);
+        }
+        let all: Vec<usize> = (0..self.nodes.len()).collect();
+        self.announce_tips(&all);
+    }
+
+    fn announce_tips(&self, nodes: &[usize]) {
+        for &i in nodes {
+            let tip = self.tip(i);
+            self.nodes[i]
+                .server
+                .broadcast(Message::NewBlockHashes(vec![tip]));
+        }
+    }
+
+    /// Mine `count` blocks on a node, paying the reward to its wallet
+    pub fn generate(&self, node: usize, count: usize) -> Vec<H256> {
+        let node = &self.nodes[node];
+        let reward_to = node.wallet.lock().unwrap().addresses().first().cloned();
+        miner::generate(
+            &node.blockchain,
+            &node.mempool,
+            &node.chain,
+            count,
+            reward_to,
+        )
+    }
+
+    pub fn tip(&self, node: usize) -> H256 {
+        self.nodes[node].blockchain.read().unwrap().tip()
+    }
+
+    /// Wait until every node has the same tip, returns false if they still differ at `timeout`
+    pub fn wait_for_convergence(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let tip = self.tip(0);
+            if (1..self.nodes.len()).all(|i| self.tip(i) == tip) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hashable;
+    use ntest::timeout;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn sync_partition_and_reorg() {
+        let harness = Harness::new(3);
+        harness.line();
+
+        // blocks are relayed along the line
+        let mined = harness.generate(0, 2);
+        assert_eq!(mined.len(), 2);
+        assert!(harness.wait_for_convergence(Duration::from_secs(10)));
+        assert_eq!(harness.tip(2), mined[1]);
+
+        // both sides of a partition extend the chain on their own
+        harness.partition(&[2]);
+        let short = harness.generate(0, 1);
+        let long = harness.generate(2, 3);
+        assert!(!harness.wait_for_convergence(Duration::from_millis(500)));
+        assert_eq!(harness.tip(1), short[0]);
+
+        // once healed, every node switches to the longest chain
+        harness.heal();
+        assert!(harness.wait_for_convergence(Duration::from_secs(10)));
+        assert_eq!(harness.tip(0), long[2]);
+        let chain = harness.nodes[0]
+            .blockchain
+            .read()
+            .unwrap()
+            .all_blocks_in_longest_chain();
+        assert!(!chain.contains(&short[0]));
+        assert_eq!(chain.len(), 5);
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn reorg_restores_transactions() {
+        let harness = Harness::new(2);
+        harness.line();
+        harness.generate(0, 1);
+        assert!(harness.wait_for_convergence(Duration::from_secs(10)));
+
+        // only the side about to lose confirms a payment of the reward both sides know
+        harness.partition(&[1]);
+        let node = &harness.nodes[0];
+        let state = node.blockchain.read().unwrap().tip_state().clone();
+        let payment = {
+            let wallet = node.wallet.lock().unwrap();
+            let me = wallet.addresses()[0];
+            wallet
+                .create_transaction(&state, &Mempool::new(), me, 1, 1)
+                .unwrap()
+        };
+        node.mempool.lock().unwrap().insert(&payment, 1).unwrap();
+        let short = harness.generate(0, 1);
+        let block = node.blockchain.read().unwrap().blocks[&short[0]].clone();
+        assert!(block.content.content.iter().any(|tx| tx.hash() == payment.hash()));
+        assert!(!node.mempool.lock().unwrap().tx_map.contains_key(&payment.hash()));
+        let long = harness.generate(1, 2);
+
+        // the reorganization gives it back to the mempool, to be mined again
+        harness.heal();
+        assert!(harness.wait_for_convergence(Duration::from_secs(10)));
+        assert_eq!(harness.tip(0), long[1]);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !node.mempool.lock().unwrap().tx_map.contains_key(&payment.hash()) {
+            assert!(Instant::now() < deadline, "payment never came back");
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[test]
+    #[timeout(60000)]
+    fn regtest_addresses() {
+        let harness = Harness::new(1);
+        let node = &harness.nodes[0];
+        let me = node.wallet.lock().unwrap().addresses()[0];
+        let public_key = |prefix| {
+            get(
+                node.api_addr,
+                &format!("/wallet/public-key?address={}", me.encode(prefix)),
+            )
+        };
+        assert!(public_key("bcrt").contains(r#""success": true"#));
+        let mainnet = public_key("bc");
+        assert!(mainnet.contains(r#""success": false"#));
+        assert!(mainnet.contains("expected 'bcrt'"));
+        // and written with the prefix of the chain, whatever the process displays
+        assert!(get(node.api_addr, "/wallet/address").contains(&me.encode("bcrt")));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
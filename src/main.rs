@@ -4,25 +4,42 @@ extern crate hex_literal;
 
 pub mod api;
 pub mod blockchain;
+pub mod events;
+#[cfg(any(test, test_utilities))]
+pub mod harness;
 pub mod miner;
 pub mod network;
+pub mod simulator;
+pub mod node;
 pub mod types;
+pub mod wallet;
 
-use api::Server as ApiServer;
+use blockchain::genesis::GenesisSpec;
+use blockchain::params::ChainParams;
 use blockchain::Blockchain;
 use clap::clap_app;
-use log::{error, info};
+use log::{error, info, warn};
 use serde::__private::ser;
-use smol::channel;
-use std::collections::HashMap;
+use simulator::scenario::Scenario;
+use simulator::Simulation;
+use std::env;
+use std::fs;
 use std::net;
+use std::path::Path;
 use std::ops::RangeBounds;
 use std::process;
-use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
+use types::address::set_address_prefix;
+use types::fee_estimator::FeeEstimator;
 use types::transaction::Mempool;
-use types::transaction_generate;
+use node::Node;
+use wallet::Wallet;
+
+/// Environment variable holding the wallet passphrase, if no passphrase file is given
+const PASSPHRASE_VAR: &str = "BITCOIN_WALLET_PASSPHRASE";
+/// Environment variable holding the hex seed of a new wallet, if no seed file is given
+const SEED_VAR: &str = "BITCOIN_WALLET_SEED";
 
 fn main() {
     // parse command line arguments
@@ -30,27 +47,73 @@ fn main() {
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
-     (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
+     (@arg network: --network [NAME] default_value("mainnet") possible_values(&["mainnet", "testnet", "regtest"]) "Selects the network parameters")
+     (@arg peer_addr: --p2p [ADDR] "Sets the IP address and the port of the P2P server, port 6000 on mainnet")
+     (@arg api_addr: --api [ADDR] "Sets the IP address and the port of the API server, port 7000 on mainnet")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg wallet: --wallet [FILE] "Loads the wallet from an encrypted keystore file, creating it if missing")
+     (@arg wallet_passphrase_file: --("wallet-passphrase-file") [FILE] "Unlocks the wallet keystore at startup with the passphrase on the first line of this file, or else in the BITCOIN_WALLET_PASSPHRASE environment variable")
+     (@arg wallet_seed_file: --("wallet-seed-file") [FILE] "Derives the keys of a new wallet from the hex seed on the first line of this file, or else in the BITCOIN_WALLET_SEED environment variable, instead of a random one")
+     (@arg generator_seed: --("tx-generator-seed") [HEX] "Derives the keys the transaction generator spends from this hex seed")
+     (@arg genesis: --genesis [FILE] "Builds the genesis block from this JSON spec instead of the network's one")
+     (@arg fee_estimates: --("fee-estimates") [FILE] "Keeps the fee estimator data in this file across restarts")
+     (@arg simulate: --simulate [FILE] "Runs the network simulation described by this scenario file, prints its statistics and exits")
+     (@arg index: --index "Keeps transaction and address indexes for the explorer and wallet lookups")
+     (@arg gap_limit: --("gap-limit") [INT] default_value("20") "Sets how many unused addresses in a row end a wallet recovery scan")
     )
     .get_matches();
 
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
-    let blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(blockchain));
+    if let Some(path) = matches.value_of("simulate") {
+        let report = Scenario::load(Path::new(path))
+            .and_then(Simulation::new)
+            .unwrap_or_else(|e| {
+                error!("Error loading simulation: {}", e);
+                process::exit(1);
+            })
+            .run();
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+    let mut params = ChainParams::from_name(matches.value_of("network").unwrap()).unwrap();
+    if let Some(path) = matches.value_of("genesis") {
+        params.genesis = GenesisSpec::load(Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading genesis spec: {}", e);
+            process::exit(1);
+        });
+    }
+    // logs and the keystore display addresses with the prefix of the network the node runs on
+    set_address_prefix(params.address_prefix);
+    let mut blockchain = Blockchain::with_params(params.clone()).unwrap_or_else(|e| {
+        error!("Error building genesis block: {}", e);
+        process::exit(1);
+    });
+    if matches.is_present("index") {
+        blockchain.enable_index();
+    }
+    info!(
+        "Network {} ({}) with genesis block {}",
+        params.name, params.genesis.network, blockchain.genesis
+    );
 
-    // proj3 added
-    let buffer = Arc::new(Mutex::new(HashMap::new()));
-    let orphan_buffer = Arc::new(Mutex::new(HashMap::new()));
+    let mut mempool = Mempool::new();
+    if let Some(path) = matches.value_of("fee_estimates") {
+        mempool.fee_estimator = FeeEstimator::open(Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading fee estimates: {}", e);
+            process::exit(1);
+        });
+        let height = blockchain.tip_state().height;
+        mempool.fee_estimator.resume(blockchain.genesis, height);
+    }
 
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
-        .unwrap()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| format!("127.0.0.1:{}", params.p2p_port))
         .parse::<net::SocketAddr>()
         .unwrap_or_else(|e| {
             error!("Error parsing P2P server address: {}", e);
@@ -60,21 +123,14 @@ fn main() {
     // parse api server address
     let api_addr = matches
         .value_of("api_addr")
-        .unwrap()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|| format!("127.0.0.1:{}", params.api_port))
         .parse::<net::SocketAddr>()
         .unwrap_or_else(|e| {
             error!("Error parsing API server address: {}", e);
             process::exit(1);
         });
 
-    // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::bounded(10000);
-
-    // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
-
-    // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
         .unwrap()
@@ -83,30 +139,67 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(
-        p2p_workers,
-        msg_rx,
-        &server,
-        &blockchain,
-        &buffer,
-        &orphan_buffer,
-    );
-    worker_ctx.start();
 
-    // responsible for generate random transactions
-    let (txs_generator_ctx, txs_generator) = transaction_generate::new(&server, &blockchain);
-    txs_generator_ctx.start();
+    // keys of the transaction generator
+    let generator_wallet = match matches.value_of("generator_seed") {
+        Some(seed) => Wallet::from_seed(&hex::decode(seed).unwrap_or_else(|e| {
+            error!("Error parsing transaction generator seed: {}", e);
+            process::exit(1);
+        })),
+        None => Wallet::new(),
+    };
 
-    // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain);
-    let miner_worker_ctx = miner::worker::Worker::new(&server, finished_block_chan, &blockchain);
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    // load the wallet, without a keystore it only holds a single throwaway key
+    // like the passphrase, never on the command line
+    let wallet_seed = match matches.value_of("wallet_seed_file") {
+        Some(path) => Some(read_secret(Path::new(path))),
+        None => env::var(SEED_VAR).ok(),
+    };
+    let wallet_seed = wallet_seed.map(|seed| {
+        hex::decode(seed.trim()).unwrap_or_else(|e| {
+            error!("Error parsing wallet seed: {}", e);
+            process::exit(1);
+        })
+    });
+    let gap_limit = matches
+        .value_of("gap_limit")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing gap limit: {}", e);
+            process::exit(1);
+        });
+    // never on the command line, where every user sees it in the process list
+    let wallet_passphrase = match matches.value_of("wallet_passphrase_file") {
+        Some(path) => Some(read_secret(Path::new(path))),
+        None => env::var(PASSPHRASE_VAR).ok(),
+    };
+    let mut wallet = load_wallet(
+        matches.value_of("wallet"),
+        wallet_passphrase.as_deref(),
+        wallet_seed.as_deref(),
+    );
+    wallet.set_gap_limit(gap_limit);
+
+    // start the P2P server, workers, chain actor, miner, transaction generator and API server
+    let node = Node::start(node::Config {
+        blockchain,
+        mempool,
+        wallet,
+        generator_wallet,
+        p2p_addr,
+        api_addr,
+        p2p_workers,
+    })
+    .unwrap_or_else(|e| {
+        error!("Error starting the node: {}", e);
+        process::exit(1);
+    });
 
     // connect to known peers
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
+        let server = node.server.clone();
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
@@ -135,11 +228,62 @@ fn main() {
             }
         });
     }
-
-    // start the API server
-    ApiServer::start(api_addr, &miner, &server, &blockchain, &txs_generator);
     // debug!("test");
     loop {
         std::thread::park();
     }
 }
+
+/// First line of the file at `path`
+fn read_secret(path: &Path) -> String {
+    let content = fs::read_to_string(path).unwrap_or_else(|e| {
+        error!("Error reading {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    content.lines().next().unwrap_or("").to_string()
+}
+
+/// Open the keystore at `path`, or create it with a first key if it does not exist yet
+fn load_wallet(path: Option<&str>, passphrase: Option<&str>, seed: Option<&[u8]>) -> Wallet {
+    let path = match path {
+        Some(path) => Path::new(path),
+        None => {
+            let mut wallet = match seed {
+                Some(seed) => Wallet::from_seed(seed),
+                None => Wallet::new(),
+            };
+            wallet.generate_key("default").unwrap();
+            return wallet;
+        }
+    };
+    let exit_on_error = |e: wallet::WalletError| -> ! {
+        error!("Error loading wallet {}: {}", path.display(), e);
+        process::exit(1);
+    };
+    if path.exists() {
+        if seed.is_some() {
+            warn!("Wallet {} already exists, ignoring the seed", path.display());
+        }
+        let mut wallet = Wallet::open(path).unwrap_or_else(|e| exit_on_error(e));
+        if let Some(passphrase) = passphrase {
+            wallet
+                .unlock(passphrase, None)
+                .unwrap_or_else(|e| exit_on_error(e));
+        }
+        wallet
+    } else {
+        let passphrase = passphrase.unwrap_or_else(|| {
+            error!("A passphrase is needed to create wallet {}", path.display());
+            process::exit(1);
+        });
+        let wallet = match seed {
+            Some(seed) => Wallet::restore(path, passphrase, seed),
+            None => Wallet::create(path, passphrase),
+        };
+        let mut wallet = wallet.unwrap_or_else(|e| exit_on_error(e));
+        wallet
+            .generate_key("default")
+            .unwrap_or_else(|e| exit_on_error(e));
+        wallet
+    }
+}
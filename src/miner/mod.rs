@@ -1,12 +1,15 @@
 pub mod worker;
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::info;
+use log::{info, warn};
 use rand::Rng;
 use std::thread;
 use std::time;
 
+use crate::blockchain::actor::Handle as ChainHandle;
+use crate::blockchain::params::ChainParams;
 use crate::blockchain::Blockchain;
+use crate::types::address::Address;
 use crate::types::block::Block;
 use crate::types::block::BlockContent;
 use crate::types::block::BlockHeader;
@@ -14,9 +17,9 @@ use crate::types::block::BlockHeader;
 use crate::types::hash::Hashable;
 use crate::types::hash::H256;
 use crate::types::merkle::MerkleTree;
-use crate::types::transaction::Mempool;
-use crate::types::transaction::SignedTransaction;
-use std::sync::{Arc, Mutex};
+use crate::types::state::State;
+use crate::types::transaction::{Mempool, SignedTransaction, Transaction, UTXO_output};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 enum ControlSignal {
@@ -25,6 +28,9 @@ enum ControlSignal {
     Exit,
 }
 
+/// How long the miner keeps extending a block it found that the chain has not connected yet
+const PENDING_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
 enum OperatingState {
     Paused,
     Run(u64),
@@ -36,9 +42,26 @@ pub struct Context {
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
-    blockchain: Arc<Mutex<Blockchain>>, // midterm2, according to document, implement this type
+    blockchain: Arc<RwLock<Blockchain>>, // midterm2, according to document, implement this type
     tip: H256, // midterm2, the reason why add this part is from the discusssion on piazza
+    tip_state: State, // state after executing `tip`, used to pick valid transactions
+    target: H256,     // block hashes must be below it, set by the genesis block
+    max_block_transactions: usize, // from the network parameters
     mempool: Arc<Mutex<Mempool>>, // mempool for midproject5
+    template: Option<Template>,
+    /// blocks we found on top of the longest chain, with their parents, not connected yet
+    pending: Vec<(H256, H256)>,
+    /// when the last of them was found
+    last_found: time::Instant,
+}
+
+/// Transactions of the block being mined, only the nonce and timestamp change between attempts
+struct Template {
+    parent: H256,
+    /// of the mempool they were picked from
+    generation: u64,
+    transactions: Vec<SignedTransaction>,
+    merkle_root: H256,
 }
 
 #[derive(Clone)]
@@ -47,17 +70,25 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
-    let fake_mempool = Mempool::new();
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
         blockchain: Arc::clone(blockchain),    // midterm2 added
-        tip: blockchain.lock().unwrap().tip(), // midterm2 added
-        mempool: Arc::new(Mutex::new(fake_mempool.clone())),
+        tip: blockchain.read().unwrap().tip(), // midterm2 added
+        tip_state: blockchain.read().unwrap().tip_state().clone(),
+        target: blockchain.read().unwrap().target(),
+        max_block_transactions: blockchain.read().unwrap().params.max_block_transactions,
+        mempool: Arc::clone(mempool),
+        template: None,
+        pending: Vec::new(),
+        last_found: time::Instant::now(),
     };
 
     let handle = Handle {
@@ -71,10 +102,10 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle, Receiver<Bl
 #[cfg(any(test, test_utilities))]
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let fake_blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(fake_blockchain));
+    let blockchain = Arc::new(RwLock::new(fake_blockchain));
     let fake_mempool = Mempool::new();
     let mempool = Arc::new(Mutex::new(fake_mempool));
-    new(&blockchain)
+    new(&blockchain, &mempool)
 }
 
 impl Handle {
@@ -164,56 +195,68 @@ impl Context {
             // TODO for student: if block mining finished, you can have something like:
             // self.finished_block_chan.send(block.clone()).expect("Send finished block error");
 
-            let blockchain = self.blockchain.lock().unwrap();
-            // let mut block_parent = blockchain2.tip();    // Uncomment this, due to test case error
-            let block_parent = self.tip;
-
-            // let new_block = generate_random_block(&block_parent);
-            let mut rng = rand::thread_rng();
-            let block_nonce: u32 = rng.gen();
-            // let block_difficulty = blockchain.blocks[&block_parent].header.difficulty;
-            // adjust the difficulty to modify mining speed
-            let mut tmp_difficulty = [255u8; 32];
-            tmp_difficulty[0] = 0u8;
-            // tmp_difficulty[1] = 0u8;
-            // tmp_difficulty[2] = 63u8;
-            let block_difficulty = tmp_difficulty.into();
-
-            let block_timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-
-            //
-            let mut mempool_mutex = blockchain.mempool.lock().unwrap();
-            let mut packed_transcation: Vec<SignedTransaction> = Vec::new();
-
-            let top_txs = mempool_mutex.get_headtransactions(); // first 20 transaction to assemble merkle tree
+            // Follow the longest chain. A block we just found is still queued in the chain actor
+            // for a moment, meanwhile keep mining on top of it, but only while the chain has not
+            // moved elsewhere and for at most `PENDING_TIMEOUT`: a block the actor rejected
+            // never becomes the tip.
+            let blockchain = self.blockchain.read().unwrap();
+            let chain_tip = blockchain.tip();
+            if let Some(i) = self.pending.iter().position(|(h, _)| *h == chain_tip) {
+                self.pending.drain(..=i);
+            }
+            let waiting = self.pending.first().is_some_and(|(_, p)| *p == chain_tip)
+                && self.last_found.elapsed() < PENDING_TIMEOUT;
+            if chain_tip != self.tip && !waiting {
+                self.tip = chain_tip;
+                self.tip_state = blockchain.tip_state().clone();
+                self.pending.clear();
+            }
+            drop(blockchain);
 
-            // print!("The number is {}", mempool_mutex.deque.len());
-            let merklt_tree = MerkleTree::new(&top_txs);
+            // only the mempool is locked here, the chain itself is never touched while mining,
+            // and the transactions are only picked again when the tip or the mempool changed
+            let mempool = self.mempool.lock().unwrap();
+            let stale = match &self.template {
+                Some(t) => t.parent != self.tip || t.generation != mempool.generation,
+                None => true,
+            };
+            if stale {
+                // first valid transactions, up to the block limit, to assemble merkle tree
+                let transactions =
+                    mempool.get_headtransactions(&self.tip_state, self.max_block_transactions);
+                self.template = Some(Template {
+                    parent: self.tip,
+                    generation: mempool.generation,
+                    merkle_root: MerkleTree::new(&transactions).root(),
+                    transactions,
+                });
+            }
+            // release the mempool before hashing so workers can keep inserting transactions
+            drop(mempool);
+            let template = self.template.as_ref().unwrap();
 
             let block_header = BlockHeader {
-                parent: block_parent,
-                nonce: block_nonce,
-                difficulty: block_difficulty,
-                timestamp: block_timestamp,
-                merkle_root: merklt_tree.root(),
+                parent: template.parent,
+                nonce: rand::thread_rng().gen(),
+                // the target comes from the genesis spec, it sets the mining speed
+                difficulty: self.target,
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(),
+                merkle_root: template.merkle_root,
             };
 
-            if block_header.hash() <= block_difficulty {
-                for i in 0..20 {
-                    // if mempool not empty, pop values from mempool
-                    if !mempool_mutex.deque.is_empty() {
-                        let first_hash = mempool_mutex.deque.pop_front().unwrap();
-                        let first_tx = mempool_mutex.tx_map.remove(&first_hash).unwrap();
-                        packed_transcation.push(first_tx.clone());
-                    } else {
-                        break;
-                    }
+            if block_header.hash() < self.target {
+                // execute packed transactions on our state, they stay in the mempool until the
+                // chain actor connects the block, so the fee estimator sees them confirmed
+                for tx in template.transactions.iter() {
+                    self.tip_state.apply(tx);
                 }
 
-                let block_content = BlockContent { content: top_txs };
+                let block_content = BlockContent {
+                    content: template.transactions.clone(),
+                };
                 let new_block = Block {
                     header: block_header,
                     content: block_content,
@@ -223,9 +266,9 @@ impl Context {
                     .send(new_block.clone())
                     .expect("Send finished block error");
 
-                // block_parent = new_block.hash();     // this will not work, failed to pass miner_three_block() case
+                self.pending.push((new_block.hash(), self.tip));
+                self.last_found = time::Instant::now();
                 self.tip = new_block.hash();
-                print!("mining a new block is {}", 1);
             }
             // print!("test for mining a new block");
             if let OperatingState::Run(i) = self.operating_state {
@@ -234,12 +277,107 @@ impl Context {
                     thread::sleep(interval);
                 }
             }
-            drop(mempool_mutex);
-            drop(blockchain);
         }
     }
 }
 
+/// Block on top of `parent`, whose state after execution is `state`, holding the best
+/// transactions of the mempool. With `reward_to`, it starts with a coinbase paying it the block
+/// reward and the fees, on networks that have a reward. The nonce is left to the caller: the
+/// hash is not below `target` yet.
+pub fn template(
+    parent: H256,
+    state: &State,
+    params: &ChainParams,
+    target: H256,
+    mempool: &Mempool,
+    reward_to: Option<Address>,
+    timestamp: u128,
+) -> Block {
+    let reward_to = reward_to.filter(|_| params.block_reward > 0);
+    let max = params.max_block_transactions - reward_to.iter().count();
+    let transactions = mempool.get_headtransactions(state, max);
+    let mut content = Vec::new();
+    if let Some(address) = reward_to {
+        let mut after = state.clone();
+        let mut fees = 0u64;
+        for tx in transactions.iter() {
+            fees = fees.saturating_add(after.fee(tx));
+            after.apply(tx);
+        }
+        let value = params.block_reward.saturating_add(fees);
+        content.push(SignedTransaction {
+            transcation: Transaction {
+                receiver: address,
+                value: value.min(u32::MAX as u64) as u32,
+                output: vec![UTXO_output {
+                    receipient_address: address,
+                    value,
+                    script: None,
+                }],
+                lock_time: state.height,
+                ..Default::default()
+            },
+            witnesses: Vec::new(),
+        });
+    }
+    content.extend(transactions);
+
+    let header = BlockHeader {
+        parent,
+        nonce: 0,
+        difficulty: target,
+        timestamp,
+        merkle_root: MerkleTree::new(&content).root(),
+    };
+    Block {
+        header,
+        content: BlockContent { content },
+    }
+}
+
+/// Mine `count` blocks on top of the longest chain right away, each with transactions from the
+/// mempool, and connect each one before mining the next. With `reward_to`, blocks start with a
+/// coinbase paying it the block reward and the fees, on networks that have a reward. Returns
+/// the hashes of the blocks connected.
+pub fn generate(
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    chain: &ChainHandle,
+    count: usize,
+    reward_to: Option<Address>,
+) -> Vec<H256> {
+    let mut res = Vec::new();
+    for _ in 0..count {
+        // the blockchain and mempool locks are never nested
+        let chain_view = blockchain.read().unwrap();
+        let parent = chain_view.tip();
+        let state = chain_view.tip_state().clone();
+        let target = chain_view.target();
+        let params = chain_view.params.clone();
+        drop(chain_view);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        let mempool = mempool.lock().unwrap();
+        let mut block = template(parent, &state, &params, target, &mempool, reward_to, timestamp);
+        drop(mempool);
+        while block.header.hash() >= target {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        let hash = block.hash();
+        chain.connect_blocks_and_wait(vec![block]);
+        if !blockchain.read().unwrap().blocks.contains_key(&hash) {
+            warn!("Generated block {} was rejected", hash);
+            break;
+        }
+        res.push(hash);
+    }
+    res
+}
+
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
@@ -260,6 +398,57 @@ mod test {
             block_prev = block_next;
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn generate_on_demand() {
+        use crate::blockchain::{actor, params::ChainParams, Blockchain};
+        use crate::events::{Event, EventBus, Topic};
+        use crate::network::server;
+        use crate::types::state::TransactionError;
+        use crate::types::transaction::Mempool;
+        use std::sync::{Arc, Mutex, RwLock};
+
+        let params = ChainParams::regtest();
+        let blockchain = Blockchain::with_params(params.clone()).unwrap();
+        let blockchain = Arc::new(RwLock::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, _server_receiver) = server::Handle::new_for_test();
+        let events = EventBus::new();
+        let (chain_ctx, chain) = actor::new(&server, &blockchain, &mempool, &events);
+        chain_ctx.start();
+        let blocks = events.subscribe([Topic::Blocks].iter().cloned().collect());
+
+        let address = [3u8; 20].into();
+        let hashes = super::generate(&blockchain, &mempool, &chain, 3, Some(address));
+        assert_eq!(hashes.len(), 3);
+        for (height, hash) in hashes.iter().enumerate() {
+            let connected = Event::BlockConnected {
+                hash: hash.to_string(),
+                height: height as u64 + 1,
+            };
+            assert_eq!(blocks.try_recv().unwrap(), connected);
+        }
+        let blockchain = blockchain.read().unwrap();
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), hashes);
+        let coins = blockchain.tip_state().utxos_of(&address);
+        assert_eq!(coins.len(), 3);
+        assert!(coins.iter().all(|(_, o)| o.value == params.block_reward));
+
+        // a coinbase is only valid where there is a reward, and pays no more than it
+        let mut block = blockchain.get(hashes[0]);
+        block.content.content[0].transcation.output[0].value += 1;
+        assert!(matches!(
+            blockchain.validate_block(&block),
+            Err(TransactionError::Overspend { .. })
+        ));
+        let mainnet = Blockchain::new();
+        block.header.parent = mainnet.genesis;
+        assert_eq!(
+            mainnet.validate_block(&block),
+            Err(TransactionError::InvalidCoinbase)
+        );
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
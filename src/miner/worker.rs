@@ -1,30 +1,28 @@
-use crate::blockchain::Blockchain;
-use crate::network::message::Message;
-use crate::network::server::Handle as ServerHandle;
+use crate::blockchain::actor::Handle as ChainHandle;
+use crate::events::{Event, EventBus};
 use crate::types::block::Block;
 use crate::types::hash::Hashable;
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use log::{debug, info};
-use std::sync::{Arc, Mutex};
+use crossbeam::channel::Receiver;
+use log::info;
 use std::thread;
 
 #[derive(Clone)]
 pub struct Worker {
-    server: ServerHandle,
     finished_block_chan: Receiver<Block>,
-    blockchain: Arc<Mutex<Blockchain>>,
+    chain: ChainHandle,
+    events: EventBus,
 }
 
 impl Worker {
     pub fn new(
-        server: &ServerHandle,
         finished_block_chan: Receiver<Block>,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        chain: &ChainHandle,
+        events: &EventBus,
     ) -> Self {
         Self {
-            server: server.clone(),
             finished_block_chan,
-            blockchain: Arc::clone(blockchain),
+            chain: chain.clone(),
+            events: events.clone(),
         }
     }
 
@@ -45,16 +43,12 @@ impl Worker {
                 .recv()
                 .expect("Receive finished block error");
 
-            // TODO for student: insert this finished block to blockchain, and broadcast this block hash
-            let mut new_blockchain = self.blockchain.lock().unwrap();
-            new_blockchain.insert(&_block);
-
-            // Midterm2, according to the GitLab, we need to broadcast a hash of block
-            // Although, worker will not run in "miner_three_block()" case
-            let mut blk_hashes = Vec::new();
-            blk_hashes.push(_block.hash());
-            self.server.broadcast(Message::NewBlockHashes(blk_hashes));
-            drop(new_blockchain);
+            // Mined blocks are connected by the chain actor like any block from a peer,
+            // and the actor broadcasts the hash once the block is in the chain
+            self.events.publish(Event::BlockMined {
+                hash: _block.hash().to_string(),
+            });
+            self.chain.connect_blocks(vec![_block]);
         }
     }
 }
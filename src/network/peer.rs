@@ -46,6 +46,15 @@ impl Handle {
         &self.addr
     }
 
+    /// Stop writing to the peer, which closes the connection
+    pub fn disconnect(&self) {
+        self.write_queue.close_channel();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.write_queue.is_closed()
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
         let (s,r) = mpsc::unbounded();
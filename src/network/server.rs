@@ -1,6 +1,7 @@
 use crate::types::address::Address;
 use super::peer;
 use super::message;
+use crate::events::{Event, EventBus};
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
@@ -13,9 +14,12 @@ use std::sync::Arc;
 use std::thread;
 
 
+/// Every frame starts with `magic`, so peers of another network are dropped
 pub fn new(
     addr: std::net::SocketAddr,
+    magic: [u8; 4],
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    events: &EventBus,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
@@ -24,9 +28,11 @@ pub fn new(
     let ctx = Context {
         peers: std::collections::HashMap::new(),
         addr,
+        magic,
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        events: events.clone(),
     };
     Ok((ctx, handle))
 }
@@ -34,17 +40,21 @@ pub fn new(
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
+    magic: [u8; 4],
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    events: EventBus,
 }
 
 impl Context {
-    /// Start a new server context.
-    pub fn start(self) -> std::io::Result<()> {
+    /// Start a new server context, returns the address it listens at, whose port is picked by
+    /// the system if the given one is 0.
+    pub fn start(self) -> std::io::Result<net::SocketAddr> {
         // initialize the server socket
         let listener = Async::<net::TcpListener>::bind(self.addr)?;
-        info!("P2P server listening at {}", self.addr);
+        let local_addr = listener.get_ref().local_addr()?;
+        info!("P2P server listening at {}", local_addr);
         let control_chan = self.control_sender.clone();
         let ex = Executor::new();
         let ex = Arc::new(ex);
@@ -58,7 +68,7 @@ impl Context {
         })
             .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
-        return Ok(());
+        Ok(local_addr)
     }
 
     /// the loop that endlessly accept incoming peers
@@ -97,8 +107,24 @@ impl Context {
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
+                    // the address may already belong to a new connection to the same peer
+                    if self.peers.get(&addr).is_some_and(|hd| hd.is_disconnected()) {
+                        self.peers.remove(&addr);
+                    }
                     info!("Peer {} disconnected", addr);
+                    self.events.publish(Event::PeerDisconnected {
+                        address: addr.to_string(),
+                    });
+                }
+                ControlSignal::DisconnectPeer(addr) => {
+                    trace!("Processing DisconnectPeer({})", addr);
+                    if let Some(hd) = self.peers.remove(&addr) {
+                        hd.disconnect();
+                    }
+                }
+                ControlSignal::GetPeers(result_chan) => {
+                    trace!("Processing GetPeers command");
+                    let _ = result_chan.send(self.peers.keys().cloned().collect());
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
@@ -143,6 +169,9 @@ impl Context {
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
         let addr = stream.get_ref().peer_addr()?;
+        let reader_handle = handle.clone();
+        let shutdown_stream = stream.clone();
+        let magic = self.magic;
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
@@ -150,9 +179,21 @@ impl Context {
         ex.spawn(async move {
             // the buffer to store the frame header, which contains the length of the frame
             let mut size_buffer: [u8; 4] = [0; 4];
+            let mut magic_buffer: [u8; 4] = [0; 4];
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
             loop {
+                // the frame starts with the magic bytes of the network
+                match reader.read_exact(&mut magic_buffer).await {
+                    Ok(_) if magic_buffer == magic => {}
+                    Ok(_) => {
+                        debug!("Peer {} is on another network", addr);
+                        break;
+                    }
+                    Err(_) => {
+                        break;
+                    }
+                }
                 // first, read exactly 4 bytes to get the frame header
                 let msg_size = match reader.read_exact(&mut size_buffer).await {
                     Ok(_) => u32::from_be_bytes(size_buffer),
@@ -180,21 +221,27 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, stop writing to it too
+            reader_handle.disconnect();
         })
             .detach();
 
         // second, start a task that keeps writing to this guy
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
-            loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+            // first, get a message to write from the queue, until the peer is disconnected
+            while let Some(new_msg) = write_queue.next().await {
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
 
-                // third, write the frame header and the payload
+                // third, write the magic bytes, the frame header and the payload
+                match writer.write_all(&magic).await {
+                    Ok(_) => {}
+                    Err(_) => {
+                        break;
+                    }
+                }
                 match writer.write_all(&size_buffer).await {
                     Ok(_) => {}
                     Err(_) => {
@@ -214,7 +261,9 @@ impl Context {
                     }
                 }
             }
-            // the peer is disconnected
+            // the peer is disconnected, make sure the reader stops too
+            write_queue.close();
+            let _ = shutdown_stream.get_ref().shutdown(net::Shutdown::Both);
             control_chan
                 .send(ControlSignal::DroppedPeer(addr))
                 .await
@@ -224,6 +273,9 @@ impl Context {
 
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
+        self.events.publish(Event::PeerConnected {
+            address: addr.to_string(),
+        });
         Ok(handle)
     }
 }
@@ -259,6 +311,18 @@ impl Handle {
         smol::block_on(receiver).unwrap()
     }
 
+    /// Close the connection to the peer at `addr`, if any
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectPeer(addr))).unwrap();
+    }
+
+    /// Addresses of the peers connected, for incoming ones the port they connected from
+    pub fn peers(&self) -> Vec<std::net::SocketAddr> {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeers(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
     pub fn broadcast(&self, msg: message::Message) {
         smol::block_on(self.control_chan.send(ControlSignal::BroadcastMessage(msg))).unwrap();
     }
@@ -284,5 +348,7 @@ enum ControlSignal {
     BroadcastMessage(message::Message),
     GetNewPeer(Async<net::TcpStream>),
     DroppedPeer(std::net::SocketAddr),
+    DisconnectPeer(std::net::SocketAddr),
+    GetPeers(oneshot::Sender<Vec<std::net::SocketAddr>>),
     SendToPeer((Address,message::Message)),
 }
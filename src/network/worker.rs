@@ -1,32 +1,37 @@
 use super::message::Message;
 use super::peer;
 use super::server::Handle as ServerHandle;
+use crate::blockchain::actor::Handle as ChainHandle;
 use crate::blockchain::Blockchain;
-use crate::types::block::Block;
+use crate::events::EventBus;
 use crate::types::hash::{Hashable, H256};
-use crate::types::transaction::{verify, Mempool, SignedTransaction, Transaction};
-use std::collections::HashMap;
-use std::convert::TryInto;
+use crate::types::state::TransactionError;
+use crate::types::transaction::{Mempool, MempoolError, SignedTransaction, UTXO_input};
 
-// use futures::executor::block_on;
-// use futures::lock;
 use log::{debug, error, warn};
-use ring::signature;
-use std::sync::{Arc, Mutex};
-use std::{clone, mem, thread};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
 
 #[cfg(any(test, test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
 #[cfg(any(test, test_utilities))]
 use super::server::TestReceiver as ServerTestReceiver;
+
+// Workers never take the blockchain write lock and never hold the blockchain lock and the
+// mempool lock at the same time. Blocks are handed to the chain actor, which connects them one
+// at a time, while transactions are validated against a read snapshot and only touch the mempool
+// to look up the unconfirmed outputs they spend and to insert. This is what lets `p2p_workers`
+// actually process messages in parallel.
 #[derive(Clone)]
 pub struct Worker {
     msg_chan: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,       // proj3 added
-    buffer: Arc<Mutex<HashMap<H256, Block>>>, // proj3 added
-    orphan_buffer: Arc<Mutex<HashMap<H256, Block>>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    chain: ChainHandle,
+    events: EventBus,
 }
 
 impl Worker {
@@ -34,17 +39,19 @@ impl Worker {
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
-        buffer: &Arc<Mutex<HashMap<H256, Block>>>,
-        orphan_buffer: &Arc<Mutex<HashMap<H256, Block>>>,
+        blockchain: &Arc<RwLock<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        chain: &ChainHandle,
+        events: &EventBus,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
-            buffer: Arc::clone(buffer),
-            orphan_buffer: Arc::clone(orphan_buffer),
+            mempool: Arc::clone(mempool),
+            chain: chain.clone(),
+            events: events.clone(),
         }
     }
 
@@ -59,6 +66,101 @@ impl Worker {
         }
     }
 
+    /// Validate transactions received from `peer`, add them to the mempool and announce the new
+    /// ones. Transactions spending outputs we know nothing about go to the orphan pool and their
+    /// parents are requested from the peer; orphans are validated again once a parent is added.
+    ///
+    /// Transactions are validated against the tip state under a read lock, so several workers
+    /// can check transactions at the same time, with the outputs of the mempool transactions they
+    /// spend copied beforehand. The mempool is only locked to copy those and to insert.
+    fn add_transactions(&self, mut pending: Vec<SignedTransaction>, peer: &mut peer::Handle) {
+        let mut transactions_new = Vec::new();
+        let mut missing_parents = HashSet::new();
+        while !pending.is_empty() {
+            let inputs = pending.iter().flat_map(|tx| tx.transcation.input.iter());
+            let unconfirmed = self.mempool.lock().unwrap().unconfirmed_outputs(inputs);
+
+            let blockchain = self.blockchain.read().unwrap();
+            let state = blockchain.tip_state();
+            let mut verified = Vec::new();
+            let mut orphans = Vec::new();
+            let mut held = Vec::new();
+            for tx in pending {
+                match state.validate_with(&tx, &unconfirmed) {
+                    Ok(()) => {}
+                    // time locked transactions are kept, not relayed, until their lock passes
+                    Err(e) if e.is_non_final() => {
+                        debug!("Hold transaction {}: {}", tx.hash(), e);
+                        held.push(tx);
+                        continue;
+                    }
+                    Err(TransactionError::MissingInput(_)) => {
+                        let missing: Vec<UTXO_input> = tx
+                            .transcation
+                            .input
+                            .iter()
+                            .filter(|i| {
+                                !state.utxos.contains_key(i) && !unconfirmed.contains_key(i)
+                            })
+                            .cloned()
+                            .collect();
+                        orphans.push((tx, missing));
+                        continue;
+                    }
+                    Err(e) => {
+                        debug!("Reject transaction {}: {}", tx.hash(), e);
+                        continue;
+                    }
+                }
+                let fee = state.fee_with(&tx, &unconfirmed);
+                verified.push((tx, fee));
+            }
+            drop(blockchain);
+
+            let mut mempool = self.mempool.lock().unwrap();
+            let mut added = Vec::new();
+            for (tx, fee) in verified {
+                let t_hash = tx.hash();
+                match mempool.insert(&tx, fee) {
+                    Ok(evicted) => {
+                        for h in evicted.iter() {
+                            debug!("Transaction {} replaced by {}", h, t_hash);
+                        }
+                        self.events.transaction_accepted(t_hash, fee, &evicted);
+                        added.push(t_hash);
+                    }
+                    Err(MempoolError::AlreadyKnown) => {}
+                    Err(e) => debug!("Reject transaction {}: {}", t_hash, e),
+                }
+            }
+            for tx in held {
+                mempool.hold(&tx);
+            }
+            for (tx, missing) in orphans {
+                missing_parents.extend(missing.iter().map(|i| i.prev_tx_hash));
+                let t_hash = tx.hash();
+                if mempool.orphans.insert(tx, &missing) {
+                    debug!("Orphan transaction {}", t_hash);
+                }
+            }
+            pending = mempool.orphans.take_children(&added);
+            drop(mempool);
+            transactions_new.extend(added);
+        }
+
+        let missing_parents: Vec<H256> = missing_parents
+            .into_iter()
+            .filter(|h| !transactions_new.contains(h))
+            .collect();
+        if !missing_parents.is_empty() {
+            peer.write(Message::GetTransactions(missing_parents));
+        }
+        if !transactions_new.is_empty() {
+            self.server
+                .broadcast(Message::NewTransactionHashes(transactions_new));
+        }
+    }
+
     fn worker_loop(&self) {
         loop {
             let result = smol::block_on(self.msg_chan.recv());
@@ -70,11 +172,6 @@ impl Worker {
             let (msg, mut peer) = msg;
             let msg: Message = bincode::deserialize(&msg).unwrap();
 
-            // I think it world be better to initialize lock type variables in advanced
-            let mut locked_blockchian = self.blockchain.lock().unwrap();
-            let mut locked_bffer = self.buffer.lock().unwrap();
-            let mut locked_orphan_buffer = self.orphan_buffer.lock().unwrap();
-
             match msg {
                 Message::Ping(nonce) => {
                     debug!("Ping: {}", nonce);
@@ -84,187 +181,61 @@ impl Worker {
                     debug!("Pong: {}", nonce);
                 }
                 Message::NewBlockHashes(hashes) => {
-                    let mut new_blocks: Vec<H256> = Vec::new();
-
-                    for hash in hashes {
-                        if !locked_blockchian.blocks.contains_key(&hash) {
-                            new_blocks.push(hash);
-                        }
-                    }
-                    if new_blocks.len() > 0 {
-                        peer.write(Message::GetBlocks(new_blocks.clone()));
+                    let blockchain = self.blockchain.read().unwrap();
+                    let new_blocks: Vec<H256> = hashes
+                        .into_iter()
+                        .filter(|hash| !blockchain.blocks.contains_key(hash))
+                        .collect();
+                    drop(blockchain);
+                    if !new_blocks.is_empty() {
+                        peer.write(Message::GetBlocks(new_blocks));
                     }
                 }
                 Message::GetBlocks(hashes) => {
-                    let mut new_blocks = Vec::new();
-
-                    for hash in hashes {
-                        if locked_blockchian.blocks.contains_key(&hash) {
-                            new_blocks.push(locked_blockchian.blocks[&hash].clone());
-                        }
-                    }
-                    if new_blocks.len() > 0 {
+                    let blockchain = self.blockchain.read().unwrap();
+                    let new_blocks: Vec<_> = hashes
+                        .iter()
+                        .filter_map(|hash| blockchain.blocks.get(hash).cloned())
+                        .collect();
+                    drop(blockchain);
+                    if !new_blocks.is_empty() {
                         peer.write(Message::Blocks(new_blocks));
                     }
                 }
                 Message::Blocks(blocks) => {
-                    let mut new_block_hashes: Vec<H256> = Vec::new();
-                    let mut unseen: Vec<H256> = Vec::new();
-
-                    for block in blocks.iter() {
-                        // judge if the block already exists in the block chain
-                        if locked_blockchian.blocks.contains_key(&block.hash()) {
-                            continue;
-                        }
-                        // judge if not parent already exists, then add current block into buffer
-                        if !locked_blockchian.blocks.contains_key(&block.header.parent) {
-                            unseen.push(block.header.parent);
-                            // structure in orphan_buffer:
-                            // key : H256 of parent, value : block of child
-                            locked_orphan_buffer.insert(block.header.parent, block.clone());
-                        }
-                        // parent exists, check from the buffer
-                        else {
-                            let mut tmp_difficulty = [255u8; 32];
-                            tmp_difficulty[0] = 0u8;
-                            // tmp_difficulty[1] = 0u8;
-                            // tmp_difficulty[2] = 63u8;
-                            let root_diff: H256 = tmp_difficulty.into();
-                            // let root_diff  = locked_blockchian.blocks[&block.header.parent].header.difficulty;
-                            if block.hash() < block.header.difficulty
-                                && block.header.difficulty == root_diff
-                            {
-                                // add current block into chain
-                                locked_blockchian.insert(block);
-                                new_block_hashes.push(block.hash());
-                                // search childs in the buffer and add them into chain iterately.
-                                let mut parent_hash = block.hash();
-
-                                while locked_orphan_buffer.contains_key(&parent_hash) {
-                                    // child block of parent_hash
-                                    let child_block =
-                                        locked_orphan_buffer.remove(&parent_hash).unwrap();
-                                    // POV validation
-                                    if child_block.header.difficulty == root_diff
-                                        && child_block.header.difficulty > child_block.hash()
-                                    {
-                                        // add child block
-                                        locked_blockchian.insert(&child_block);
-                                        new_block_hashes.push(child_block.hash());
-                                    }
-                                    // update parent hash for next iteration
-                                    parent_hash = child_block.hash();
-                                }
-                            }
-                        }
-                    }
-                    if new_block_hashes.len() > 0 {
-                        self.server
-                            .broadcast(Message::NewBlockHashes(new_block_hashes.clone()));
-                    }
-                    if unseen.len() > 0 {
-                        self.server.broadcast(Message::GetBlocks(unseen.clone()));
-                    }
-
-                    // let mut new_blocks: Vec<H256> = Vec::new();
-                    // let mut buffer_parents: Vec<H256> = Vec::new();
-
-                    // for block in blocks.iter() {
-                    //     // If the block isn't inside blockchain, then we can try to insert it
-                    //     // Or, we just pass it
-                    //     if !(locked_blockchian.blocks.contains_key(&block.hash())) {
-                    //         // If block's parent isn't inside the blockchain,
-                    //         // then we need to wait until block's parent be added in.
-                    //         if locked_blockchian.blocks.contains_key(&block.header.parent) {
-                    //             locked_blockchian.insert(block);
-                    //             new_blocks.push(block.hash());
-                    //         } else {
-                    //             locked_bffer.insert(block.header.parent, block.clone());
-                    //             buffer_parents.push(block.header.parent.clone());
-                    //             peer.write(Message::GetBlocks(buffer_parents.clone()));
-                    //         }
-                    //     }
-                    // }
-                    // if new_blocks.len() > 0 {
-                    //     self.server
-                    //         .broadcast(Message::NewBlockHashes(new_blocks.clone()));
-                    // }
+                    // validation, orphan handling and relaying happen in the chain actor
+                    self.chain.connect_blocks(blocks);
                 }
                 Message::NewTransactionHashes(hashes) => {
-                    println!("receive req new txs");
-                    let mempool_mutex = locked_blockchian.mempool.lock().unwrap();
+                    let mempool = self.mempool.lock().unwrap();
                     // vector to store transaction not included in mempool
-                    let mut transactions_new = Vec::new();
-                    for hash in hashes.iter() {
-                        if !mempool_mutex.tx_map.contains_key(hash) {
-                            transactions_new.push(hash.clone());
-                        }
-                    }
-                    if transactions_new.len() > 0 {
+                    let transactions_new: Vec<H256> = hashes
+                        .into_iter()
+                        .filter(|hash| {
+                            !mempool.tx_map.contains_key(hash) && !mempool.orphans.contains(hash)
+                        })
+                        .collect();
+                    drop(mempool);
+                    if !transactions_new.is_empty() {
                         peer.write(Message::GetTransactions(transactions_new));
-                        // println!("request new txs");
                     }
-                    drop(mempool_mutex);
                 }
                 Message::GetTransactions(hashes) => {
-                    // println!("receive req get txs");
-                    let mempool_mutex = locked_blockchian.mempool.lock().unwrap();
-                    // vector to store requested blocks
-                    let mut transactions = Vec::new();
-
-                    // let mut map = mempool_mutex.tx_map;
-                    for hash in hashes.iter() {
-                        if mempool_mutex.tx_map.contains_key(hash) {
-                            transactions.push(mempool_mutex.tx_map[hash].clone());
-                        }
-                    }
-                    if transactions.len() > 0 {
+                    let mempool = self.mempool.lock().unwrap();
+                    // vector to store requested transactions
+                    let transactions: Vec<SignedTransaction> = hashes
+                        .iter()
+                        .filter_map(|hash| mempool.tx_map.get(hash).cloned())
+                        .collect();
+                    drop(mempool);
+                    if !transactions.is_empty() {
                         peer.write(Message::Transactions(transactions));
-                        // println!("return all txs that are requested");
                     }
-                    drop(mempool_mutex);
                 }
                 Message::Transactions(signedtransactions) => {
-                    // println!("receive req txs");
-                    let mut mempool_mutex = locked_blockchian.mempool.lock().unwrap();
-
-                    let mut transactions_new = Vec::new();
-
-                    for tx in signedtransactions {
-                        let t_hash = tx.hash();
-                        let public_key_tx = tx.public_key;
-                        let signature_tx = tx.signature;
-                        let transaction = tx.transcation;
-
-                        // verify with the publickey
-                        if !verify(&transaction, &public_key_tx, &signature_tx){
-                            continue;
-                        }
-
-                        // add check here
-                        if !mempool_mutex.tx_map.contains_key(&t_hash) {
-                            let clone_tx = transaction.clone();
-                            let clone_signed_tx = SignedTransaction {
-                                public_key: public_key_tx.clone(),
-                                signature: signature_tx.clone(),
-                                transcation: clone_tx,
-                            };
-                            mempool_mutex.insert(&clone_signed_tx);
-                            // println!("adding new txs in mempool");
-                            transactions_new.push(t_hash);
-                        }
-                    }
-                    if transactions_new.len() > 0 {
-                        self.server
-                            .broadcast(Message::NewTransactionHashes(transactions_new));
-                        // println!("inserting some in mempool, tell others adding some new txs");
-                    }
-                    drop(mempool_mutex);
+                    self.add_transactions(signedtransactions, &mut peer);
                 }
             }
-            drop(locked_blockchian);
-            drop(locked_bffer);
-            drop(locked_orphan_buffer);
         }
     }
 }
@@ -293,25 +264,44 @@ impl TestMsgSender {
 #[cfg(any(test, test_utilities))]
 /// returns two structs used by tests, and an ordered vector of hashes of all blocks in the blockchain
 fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    generate_test_workers_and_start(1, Blockchain::new())
+}
+
+#[cfg(any(test, test_utilities))]
+/// same as `generate_test_worker_and_start`, but with `num_worker` worker threads on top of a
+/// prepared blockchain
+fn generate_test_workers_and_start(
+    num_worker: usize,
+    fake_blockchain: Blockchain,
+) -> (TestMsgSender, ServerTestReceiver, Vec<H256>) {
+    use crate::blockchain::actor;
+
     let (server, server_receiver) = ServerHandle::new_for_test();
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
 
-    let fake_blockchain = Blockchain::new();
-    let blockchain = Arc::new(Mutex::new(fake_blockchain));
-    let fake_buffer = HashMap::new();
-    let buffer: Arc<Mutex<HashMap<H256, Block>>> = Arc::new(Mutex::new(fake_buffer));
-    let fake_orphan_buffer = HashMap::new();
-    let orphan_buffer: Arc<Mutex<HashMap<H256, Block>>> = Arc::new(Mutex::new(fake_orphan_buffer));
+    let blockchain = Arc::new(RwLock::new(fake_blockchain));
     let fake_mempool = Mempool::new();
     let mempool: Arc<Mutex<Mempool>> = Arc::new(Mutex::new(fake_mempool));
-    let worker = Worker::new(1, msg_chan, &server, &blockchain, &buffer, &orphan_buffer);
+    let events = EventBus::new();
+    let (chain_ctx, chain) = actor::new(&server, &blockchain, &mempool, &events);
+    chain_ctx.start();
+    let worker = Worker::new(
+        num_worker,
+        msg_chan,
+        &server,
+        &blockchain,
+        &mempool,
+        &chain,
+        &events,
+    );
     worker.start();
 
     let mut res: Vec<H256> = Vec::new();
-    let locked_blockchain = blockchain.lock().unwrap();
+    let locked_blockchain = blockchain.read().unwrap();
     for (_hash, _block) in locked_blockchain.blocks.iter() {
         res.push(*_hash);
     }
+    drop(locked_blockchain);
 
     (test_msg_sender, server_receiver, res)
 }
@@ -325,7 +315,7 @@ mod test {
     use ntest::timeout;
 
     use super::super::message::Message;
-    use super::generate_test_worker_and_start;
+    use super::{generate_test_worker_and_start, generate_test_workers_and_start};
 
     #[test]
     #[timeout(60000)]
@@ -368,6 +358,154 @@ mod test {
             panic!();
         }
     }
+
+    #[test]
+    #[timeout(60000)]
+    fn orphan_waits_for_parent() {
+        use crate::blockchain::Blockchain;
+        use crate::types::address::Address;
+        use crate::types::key_pair;
+        use crate::types::transaction::{
+            signature_hash, SignedTransaction, Transaction, UTXO_input, UTXO_output, Witness,
+        };
+        use ring::signature::KeyPair;
+
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let coin = UTXO_input {
+            prev_tx_hash: [1u8; 32].into(),
+            index: 0,
+        };
+        let spend = |input: UTXO_input, value: u64| {
+            let spent = UTXO_output {
+                receipient_address: owner,
+                value,
+                script: None,
+            };
+            let t = Transaction {
+                input: vec![input],
+                output: vec![spent.clone()],
+                ..Default::default()
+            };
+            let mut witness = Witness::default();
+            witness.sign(&signature_hash(&t, 0, &spent), &key);
+            SignedTransaction {
+                transcation: t,
+                witnesses: vec![witness],
+            }
+        };
+        let parent = spend(coin.clone(), 10);
+        let child = spend(
+            UTXO_input {
+                prev_tx_hash: parent.hash(),
+                index: 0,
+            },
+            10,
+        );
+        let mut blockchain = Blockchain::new();
+        let tip = blockchain.tip();
+        blockchain.states.get_mut(&tip).unwrap().utxos.insert(
+            coin,
+            UTXO_output {
+                receipient_address: owner,
+                value: 10,
+                script: None,
+            },
+        );
+        let (test_msg_sender, server_receiver, _v) =
+            generate_test_workers_and_start(1, blockchain);
+
+        // the child arrives first, its parent is requested from the same peer
+        let mut peer_receiver = test_msg_sender.send(Message::Transactions(vec![child.clone()]));
+        match peer_receiver.recv() {
+            Message::GetTransactions(v) => assert_eq!(v, vec![parent.hash()]),
+            _ => panic!(),
+        }
+        // both are relayed once the parent arrives
+        test_msg_sender.send(Message::Transactions(vec![parent.clone()]));
+        match server_receiver.recv() {
+            Some(Message::NewTransactionHashes(v)) => {
+                assert_eq!(v, vec![parent.hash(), child.hash()])
+            }
+            _ => panic!(),
+        }
+    }
+
+    /// Benchmark for transaction relay, run with `cargo test -- --ignored --nocapture`.
+    /// Throughput should grow with the number of workers since signatures are checked in parallel.
+    #[test]
+    #[ignore]
+    fn bench_transactions_scale_with_workers() {
+        use crate::blockchain::Blockchain;
+        use crate::types::address::Address;
+        use crate::types::key_pair;
+        use crate::types::transaction::{
+            generate_random_transaction, signature_hash, SignedTransaction, UTXO_input,
+            UTXO_output, Witness,
+        };
+        use ring::signature::KeyPair;
+        use std::time::Instant;
+
+        let num_txs = 2000;
+        let mut genesis = Blockchain::new();
+        let genesis_hash = genesis.tip();
+        let txs: Vec<SignedTransaction> = (0..num_txs)
+            .map(|i| {
+                let mut t = generate_random_transaction();
+                let mut prev_tx_hash = [0u8; 32];
+                prev_tx_hash[..8].copy_from_slice(&(i as u64).to_be_bytes());
+                t.input = vec![UTXO_input {
+                    prev_tx_hash: prev_tx_hash.into(),
+                    index: 0,
+                }];
+                let key = key_pair::random();
+                // fund the input so the transaction passes the state checks
+                let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+                let spent = UTXO_output {
+                    receipient_address: owner,
+                    value: t.output[0].value,
+                    script: None,
+                };
+                let mut witness = Witness::default();
+                witness.sign(&signature_hash(&t, 0, &spent), &key);
+                genesis
+                    .states
+                    .get_mut(&genesis_hash)
+                    .unwrap()
+                    .utxos
+                    .insert(t.input[0].clone(), spent);
+                SignedTransaction {
+                    transcation: t,
+                    witnesses: vec![witness],
+                }
+            })
+            .collect();
+
+        for &num_worker in [1, 2, 4, 8].iter() {
+            let mut blockchain = Blockchain::new();
+            blockchain.states = genesis.states.clone();
+            let (test_msg_sender, server_receiver, _v) =
+                generate_test_workers_and_start(num_worker, blockchain);
+            let start = Instant::now();
+            for tx in txs.iter() {
+                test_msg_sender.send(Message::Transactions(vec![tx.clone()]));
+            }
+            let mut relayed = 0;
+            while relayed < num_txs {
+                if let Some(Message::NewTransactionHashes(v)) = server_receiver.recv() {
+                    relayed += v.len();
+                }
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{} workers: {} txs in {:?} ({:.0} tx/s)",
+                num_worker,
+                num_txs,
+                elapsed,
+                num_txs as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
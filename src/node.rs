@@ -0,0 +1,137 @@
+use crate::api::{Context as ApiContext, Server as ApiServer};
+use crate::blockchain::actor::{self, Handle as ChainHandle};
+use crate::blockchain::Blockchain;
+use crate::events::EventBus;
+use crate::miner::{self, Handle as MinerHandle};
+use crate::network::server::{self, Handle as ServerHandle};
+use crate::network::worker::Worker;
+use crate::types::transaction::Mempool;
+use crate::types::transaction_generate::{self, Handle as TXGenerateHandle};
+use crate::wallet::Wallet;
+use log::info;
+use smol::channel;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+
+// A full node is a P2P server with its workers, the chain actor, a miner, a transaction generator
+// and the API server, all sharing the blockchain, the mempool and the wallet, and publishing what
+// happens on one event bus. `main` starts one from the command line, the test harness starts
+// several in one process.
+
+/// What a node is started with
+pub struct Config {
+    /// the chain to extend, with the parameters of its network
+    pub blockchain: Blockchain,
+    pub mempool: Mempool,
+    pub wallet: Wallet,
+    /// keys the transaction generator spends from
+    pub generator_wallet: Wallet,
+    /// a port of 0 lets the system pick one
+    pub p2p_addr: SocketAddr,
+    pub api_addr: SocketAddr,
+    pub p2p_workers: usize,
+}
+
+/// Handles of a running node
+#[derive(Clone)]
+pub struct Node {
+    /// address the P2P server listens at
+    pub p2p_addr: SocketAddr,
+    /// address the API server listens at
+    pub api_addr: SocketAddr,
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub wallet: Arc<Mutex<Wallet>>,
+    pub server: ServerHandle,
+    pub chain: ChainHandle,
+    pub miner: MinerHandle,
+    pub tx_generator: TXGenerateHandle,
+    pub events: EventBus,
+}
+
+impl Node {
+    /// Start every part of a node, it runs until the process exits
+    pub fn start(config: Config) -> std::io::Result<Node> {
+        let params = config.blockchain.params.clone();
+        let blockchain = Arc::new(RwLock::new(config.blockchain));
+        // the mempool is shared on its own, never locked while holding the blockchain lock
+        let mempool = Arc::new(Mutex::new(config.mempool));
+        let events = EventBus::new();
+
+        // create channels between server and worker
+        let (msg_tx, msg_rx) = channel::bounded(params.message_channel_size);
+
+        // start the p2p server
+        let (server_ctx, server) = server::new(config.p2p_addr, params.magic, msg_tx, &events)?;
+        let p2p_addr = server_ctx.start()?;
+
+        // start the chain actor, the only writer of the blockchain
+        let (chain_ctx, chain) = actor::new(&server, &blockchain, &mempool, &events);
+        chain_ctx.start();
+
+        // start the worker
+        let worker_ctx = Worker::new(
+            config.p2p_workers,
+            msg_rx,
+            &server,
+            &blockchain,
+            &mempool,
+            &chain,
+            &events,
+        );
+        worker_ctx.start();
+
+        // responsible for generate random transactions
+        let (txs_generator_ctx, tx_generator) = transaction_generate::new(
+            &server,
+            &blockchain,
+            &mempool,
+            config.generator_wallet,
+            &events,
+        );
+        for address in tx_generator.addresses() {
+            info!(
+                "Transaction generator address {}",
+                address.encode(params.address_prefix)
+            );
+        }
+        txs_generator_ctx.start();
+
+        // start the miner
+        let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
+        let miner_worker_ctx = miner::worker::Worker::new(finished_block_chan, &chain, &events);
+        miner_ctx.start();
+        miner_worker_ctx.start();
+
+        for address in config.wallet.addresses() {
+            info!("Wallet address {}", address.encode(params.address_prefix));
+        }
+        let wallet = Arc::new(Mutex::new(config.wallet));
+
+        // start the API server
+        let api_context = ApiContext {
+            miner: miner.clone(),
+            network: server.clone(),
+            blockchain: Arc::clone(&blockchain),
+            chain: chain.clone(),
+            tx_generator: tx_generator.clone(),
+            mempool: Arc::clone(&mempool),
+            wallet: Arc::clone(&wallet),
+            events: events.clone(),
+        };
+        let api_addr = ApiServer::start(config.api_addr, &api_context);
+
+        Ok(Node {
+            p2p_addr,
+            api_addr,
+            blockchain,
+            mempool,
+            wallet,
+            server,
+            chain,
+            miner,
+            tx_generator,
+            events,
+        })
+    }
+}
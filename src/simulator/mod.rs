@@ -0,0 +1,696 @@
+pub mod scenario;
+pub mod strategy;
+
+use crate::blockchain::genesis::Allocation;
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::Blockchain;
+use crate::miner;
+use crate::types::address::Address;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::transaction::{Mempool, SignedTransaction};
+use crate::types::transaction_generate::{self, SpendConfig, GENERATOR_KEYS};
+use crate::wallet::Wallet;
+use rand::distributions::Open01;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use scenario::{Scenario, Topology};
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use strategy::{Attack, Attempt, Strategy};
+
+// A discrete-event simulation of a network of miners, to measure how often the chain forks for a
+// given propagation delay and mining rate. There are no threads and no sockets: events are taken
+// from a queue in order of a virtual clock, and every random choice comes from one generator
+// seeded by the scenario, so a scenario always gives the same report.
+//
+// Each node keeps a real `Blockchain` and `Mempool`. Blocks are built with the miner's template,
+// checked with `validate_block` and connected with `insert` like on a real node; proof of work is
+// replaced by the time between the blocks of a node, which is exponential with a mean of the block
+// interval divided by the node's share of the hashpower. Blocks and transactions are relayed to
+// every peer but the sender, each link adding a random latency, and each node sending one message
+// at a time at the speed of its bandwidth.
+
+/// Coins of each key of the transaction spender in the genesis block
+const SPENDER_FUNDS: u64 = 1_000_000_000;
+
+/// Virtual time in microseconds
+type Time = u64;
+
+fn millis(ms: u64) -> Time {
+    ms.saturating_mul(1000)
+}
+
+enum Event {
+    /// the node finds a block
+    Mine(usize),
+    /// a message from a peer arrives
+    Deliver {
+        to: usize,
+        from: usize,
+        payload: Payload,
+    },
+    /// a new transaction enters the network
+    Transaction,
+}
+
+#[derive(Clone)]
+enum Payload {
+    Block(Block),
+    Transaction(SignedTransaction),
+}
+
+impl Payload {
+    fn size(&self) -> u64 {
+        match self {
+            Payload::Block(block) => bincode::serialize(block).unwrap().len() as u64,
+            Payload::Transaction(tx) => tx.size(),
+        }
+    }
+}
+
+struct Scheduled {
+    time: Time,
+    /// order of scheduling, breaks ties between events at the same time
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.time, self.seq) == (other.time, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    /// reversed, so that the max-heap pops the earliest event
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+struct Node {
+    blockchain: Blockchain,
+    mempool: Mempool,
+    peers: Vec<usize>,
+    /// share of the blocks of the network it mines
+    hashpower: f64,
+    /// bytes per second, unlimited if `None`
+    bandwidth: Option<u64>,
+    /// when it is done sending the messages already queued
+    busy_until: Time,
+    /// blocks received before their parent, by the hash of the parent
+    orphans: HashMap<H256, Vec<Block>>,
+    reward_to: Address,
+    /// state of its strategy, `None` for honest nodes
+    attack: Option<Attack>,
+}
+
+/// Mined block, for the statistics
+struct MinedBlock {
+    miner: usize,
+    parent: H256,
+    time: Time,
+    /// number of nodes that connected it, and when the last one did
+    reached: usize,
+    last_arrival: Time,
+}
+
+/// Results of a simulation
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    pub seed: u64,
+    pub duration: u64,
+    pub nodes: usize,
+    pub blocks_mined: u64,
+    /// blocks in the longest chain at the end, without the genesis block
+    pub main_chain_length: u64,
+    /// mined blocks left out of the longest chain
+    pub stale_blocks: u64,
+    pub stale_rate: f64,
+    /// extra children of blocks extended more than once
+    pub forks: u64,
+    /// forks per block of the longest chain
+    pub fork_rate: f64,
+    /// blocks that arrived at a node before their parent
+    pub orphans: u64,
+    /// orphans per block delivered
+    pub orphan_rate: f64,
+    /// times a node switched to a chain not extending its tip
+    pub reorgs: u64,
+    /// most blocks a node disconnected in one reorg
+    pub max_reorg_depth: u64,
+    /// mean time for a block to reach every node, in milliseconds
+    pub mean_propagation: f64,
+    pub invalid_blocks: u64,
+    pub transactions: u64,
+    /// transactions in the longest chain
+    pub confirmed_transactions: u64,
+    /// whether every honest node ended with the same tip
+    pub converged: bool,
+    /// share of the blocks of the longest chain each node mined
+    pub revenue_shares: Vec<f64>,
+    /// share of the hashpower of the nodes that are not honest
+    pub attacker_hashpower: f64,
+    /// share of the blocks of the longest chain they mined
+    pub attacker_revenue_share: f64,
+    /// double spends that were either published or given up
+    pub double_spend_attempts: u64,
+    /// published double spends whose payment is left out of the longest chain and whose
+    /// conflicting transaction is in it
+    pub double_spend_successes: u64,
+    pub double_spend_success_rate: f64,
+}
+
+pub struct Simulation {
+    scenario: Scenario,
+    rng: StdRng,
+    now: Time,
+    seq: u64,
+    queue: BinaryHeap<Scheduled>,
+    nodes: Vec<Node>,
+    /// genesis timestamp, block timestamps are virtual time after it
+    epoch: u128,
+    spender: Wallet,
+    mined: HashMap<H256, MinedBlock>,
+    /// blocks received from peers
+    deliveries: u64,
+    attempts: Vec<Attempt>,
+    report: Report,
+}
+
+impl Simulation {
+    pub fn new(scenario: Scenario) -> Result<Self, String> {
+        scenario.check()?;
+        let mut rng = StdRng::seed_from_u64(scenario.seed);
+        let mut params = ChainParams::from_name(&scenario.network)
+            .ok_or_else(|| format!("unknown network {}", scenario.network))?;
+
+        // keys come from the seed too, signatures are deterministic
+        let mut spender = Wallet::from_seed(&rng.gen::<[u8; 32]>());
+        for i in 0..GENERATOR_KEYS {
+            spender.generate_key(&format!("spender-{}", i)).unwrap();
+        }
+        params.genesis.allocations = spender
+            .addresses()
+            .iter()
+            .map(|address| Allocation {
+                address: address.encode(params.address_prefix),
+                value: SPENDER_FUNDS,
+            })
+            .collect();
+        // attackers get coins of their own to double spend
+        let mut attacks = Vec::new();
+        for spec in scenario.nodes.iter() {
+            let attack = match spec.strategy {
+                Strategy::Honest => None,
+                _ => {
+                    let attack = Attack::new(spec.strategy.clone(), &rng.gen::<[u8; 32]>());
+                    params.genesis.allocations.extend(attack.allocations(params.address_prefix));
+                    Some(attack)
+                }
+            };
+            attacks.push(attack);
+        }
+        let epoch = params.genesis.timestamp;
+
+        let mut miners = Wallet::from_seed(&rng.gen::<[u8; 32]>());
+        let total: f64 = scenario.nodes.iter().map(|n| n.hashpower).sum();
+        let mut nodes = Vec::new();
+        for ((i, spec), attack) in scenario.nodes.iter().enumerate().zip(attacks) {
+            let blockchain = Blockchain::with_params(params.clone()).map_err(|e| e.to_string())?;
+            let attack = attack.map(|attack| attack.starting_at(blockchain.genesis));
+            nodes.push(Node {
+                blockchain,
+                mempool: Mempool::new(),
+                peers: Vec::new(),
+                hashpower: spec.hashpower / total,
+                bandwidth: spec.bandwidth.or(scenario.bandwidth),
+                busy_until: 0,
+                orphans: HashMap::new(),
+                reward_to: miners.generate_key(&format!("miner-{}", i)).unwrap(),
+                attack,
+            });
+        }
+        for (a, b) in links(&scenario.topology, nodes.len(), &mut rng) {
+            nodes[a].peers.push(b);
+            nodes[b].peers.push(a);
+        }
+        strategy::eclipse_victims(&mut nodes);
+
+        let report = Report {
+            seed: scenario.seed,
+            duration: scenario.duration,
+            nodes: nodes.len(),
+            ..Default::default()
+        };
+        let mut sim = Simulation {
+            scenario,
+            rng,
+            now: 0,
+            seq: 0,
+            queue: BinaryHeap::new(),
+            nodes,
+            epoch,
+            spender,
+            mined: HashMap::new(),
+            deliveries: 0,
+            attempts: Vec::new(),
+            report,
+        };
+        sim.start_attacks();
+        for i in 0..sim.nodes.len() {
+            sim.schedule_mining(i);
+        }
+        sim.schedule_transaction();
+        Ok(sim)
+    }
+
+    /// Run the scenario to its end
+    pub fn run(mut self) -> Report {
+        let end = millis(self.scenario.duration);
+        while let Some(next) = self.queue.pop() {
+            if next.time > end {
+                break;
+            }
+            self.now = next.time;
+            match next.event {
+                Event::Mine(node) => self.mine(node),
+                Event::Deliver { to, from, payload } => match payload {
+                    Payload::Block(block) => self.receive_block(to, Some(from), block),
+                    Payload::Transaction(tx) => self.receive_transaction(to, Some(from), tx),
+                },
+                Event::Transaction => self.new_transaction(),
+            }
+        }
+        self.finish()
+    }
+
+    fn schedule(&mut self, time: Time, event: Event) {
+        self.seq += 1;
+        self.queue.push(Scheduled {
+            time,
+            seq: self.seq,
+            event,
+        });
+    }
+
+    /// Time until the next event of a Poisson process of mean interval `mean`
+    fn exponential(&mut self, mean: f64) -> Time {
+        let u: f64 = self.rng.sample(Open01);
+        (-mean * u.ln()) as Time
+    }
+
+    fn schedule_mining(&mut self, node: usize) {
+        let share = self.nodes[node].hashpower;
+        if share > 0.0 {
+            let delay = self.exponential(millis(self.scenario.block_interval) as f64 / share);
+            self.schedule(self.now + delay, Event::Mine(node));
+        }
+    }
+
+    fn schedule_transaction(&mut self) {
+        if self.scenario.transaction_rate > 0.0 {
+            let delay = self.exponential(1e6 / self.scenario.transaction_rate);
+            self.schedule(self.now + delay, Event::Transaction);
+        }
+    }
+
+    /// Queue `payload` on the uplink of `from`, it arrives at `to` once sent and after the
+    /// latency of the link
+    fn send(&mut self, from: usize, to: usize, payload: Payload) {
+        let start = self.now.max(self.nodes[from].busy_until);
+        let transfer = match self.nodes[from].bandwidth {
+            Some(bandwidth) => payload.size().saturating_mul(1_000_000) / bandwidth.max(1),
+            None => 0,
+        };
+        self.nodes[from].busy_until = start + transfer;
+        let latency = self
+            .rng
+            .gen_range(self.scenario.latency.min..=self.scenario.latency.max);
+        let arrival = start + transfer + millis(latency);
+        self.schedule(arrival, Event::Deliver { to, from, payload });
+    }
+
+    fn relay(&mut self, node: usize, except: Option<usize>, payload: Payload) {
+        let peers: Vec<usize> = self.nodes[node]
+            .peers
+            .iter()
+            .cloned()
+            .filter(|p| Some(*p) != except)
+            .collect();
+        for peer in peers {
+            self.send(node, peer, payload.clone());
+        }
+    }
+
+    fn mine(&mut self, node: usize) {
+        if self.nodes[node].attack.is_some() {
+            self.attacker_mine(node);
+        } else {
+            let parent = self.nodes[node].blockchain.tip();
+            let block = self.build_block(node, parent);
+            self.receive_block(node, None, block);
+        }
+        self.schedule_mining(node);
+    }
+
+    /// Block of `node` on top of `parent`, with the transactions of its mempool
+    fn build_block(&mut self, node: usize, parent: H256) -> Block {
+        let n = &self.nodes[node];
+        let timestamp = self.epoch + (self.now / 1000) as u128;
+        let mut block = miner::template(
+            parent,
+            &n.blockchain.states[&parent],
+            &n.blockchain.params,
+            n.blockchain.target(),
+            &n.mempool,
+            Some(n.reward_to),
+            timestamp,
+        );
+        // the proof of work is the time it took, the nonce only tells blocks apart
+        block.header.nonce = self.rng.gen();
+        self.mined.insert(
+            block.hash(),
+            MinedBlock {
+                miner: node,
+                parent,
+                time: self.now,
+                reached: 0,
+                last_arrival: self.now,
+            },
+        );
+        self.report.blocks_mined += 1;
+        block
+    }
+
+    fn receive_block(&mut self, node: usize, from: Option<usize>, block: Block) {
+        let hash = block.hash();
+        let n = &mut self.nodes[node];
+        if n.blockchain.blocks.contains_key(&hash) {
+            return;
+        }
+        if from.is_some() {
+            self.deliveries += 1;
+        }
+        if !n.blockchain.blocks.contains_key(&block.header.parent) {
+            self.report.orphans += 1;
+            n.orphans
+                .entry(block.header.parent)
+                .or_default()
+                .push(block);
+            return;
+        }
+        self.connect(node, from, block);
+    }
+
+    /// Validate and connect a block whose parent is known, then the orphans waiting for it.
+    /// Honest nodes relay every block they connect, attackers decide with their strategy.
+    fn connect(&mut self, node: usize, from: Option<usize>, block: Block) {
+        let mut pending = vec![(from, block)];
+        while let Some((from, block)) = pending.pop() {
+            let hash = block.hash();
+            if !self.store_block(node, &block) {
+                continue;
+            }
+            if let Some(children) = self.nodes[node].orphans.remove(&hash) {
+                pending.extend(children.into_iter().map(|child| (None, child)));
+            }
+            if self.nodes[node].attack.is_some() {
+                self.attacker_received(node, from, block);
+            } else {
+                self.relay(node, from, Payload::Block(block));
+            }
+        }
+    }
+
+    /// Validate and insert a block whose parent is known, returns whether it is new and valid
+    fn store_block(&mut self, node: usize, block: &Block) -> bool {
+        let hash = block.hash();
+        let n = &mut self.nodes[node];
+        if n.blockchain.blocks.contains_key(&hash) {
+            return false;
+        }
+        if n.blockchain.validate_block(block).is_err() {
+            self.report.invalid_blocks += 1;
+            return false;
+        }
+        let old_tip = n.blockchain.tip();
+        n.blockchain.insert(block);
+        let new_tip = n.blockchain.tip();
+        // attackers follow their own chain and prune their mempool when they mine
+        if new_tip != old_tip && n.attack.is_none() {
+            if block.header.parent != old_tip {
+                let depth = reorg_depth(&n.blockchain, old_tip, new_tip);
+                self.report.reorgs += 1;
+                self.report.max_reorg_depth = self.report.max_reorg_depth.max(depth);
+            }
+            let state = n.blockchain.tip_state().clone();
+            n.mempool.prune(&state);
+        }
+        if let Some(mined) = self.mined.get_mut(&hash) {
+            mined.reached += 1;
+            mined.last_arrival = self.now;
+        }
+        true
+    }
+
+    fn new_transaction(&mut self) {
+        let origin = self.rng.gen_range(0..self.nodes.len());
+        let n = &self.nodes[origin];
+        let tx = transaction_generate::spend(
+            &self.spender,
+            n.blockchain.tip_state(),
+            &n.mempool,
+            &SpendConfig::default(),
+            &mut self.rng,
+        );
+        if let Some(tx) = tx {
+            self.report.transactions += 1;
+            self.receive_transaction(origin, None, tx);
+        }
+        self.schedule_transaction();
+    }
+
+    fn receive_transaction(&mut self, node: usize, from: Option<usize>, tx: SignedTransaction) {
+        let n = &mut self.nodes[node];
+        if n.mempool.tx_map.contains_key(&tx.hash()) {
+            return;
+        }
+        let unconfirmed = n.mempool.unconfirmed_outputs(tx.transcation.input.iter());
+        let state = n.blockchain.tip_state();
+        if state.validate_with(&tx, &unconfirmed).is_err() {
+            return;
+        }
+        let fee = state.fee_with(&tx, &unconfirmed);
+        if n.mempool.insert(&tx, fee).is_ok() {
+            self.relay(node, from, Payload::Transaction(tx));
+        }
+    }
+
+    fn finish(mut self) -> Report {
+        let mut report = std::mem::take(&mut self.report);
+        // the tip most honest nodes agree on, then the longest, then the first one: attackers
+        // may be withholding a longer chain, and eclipsed nodes may hold the attacker's one
+        let honest: Vec<usize> = (0..self.nodes.len())
+            .filter(|i| self.nodes[*i].attack.is_none())
+            .collect();
+        let best = *honest
+            .iter()
+            .rev()
+            .max_by_key(|i| {
+                let chain = &self.nodes[**i].blockchain;
+                let agreeing = honest
+                    .iter()
+                    .filter(|j| self.nodes[**j].blockchain.tip() == chain.tip())
+                    .count();
+                (agreeing, chain.lengths[&chain.tip()])
+            })
+            .unwrap();
+        let chain = &self.nodes[best].blockchain;
+        let main: HashSet<H256> = chain.all_blocks_in_longest_chain().into_iter().collect();
+        report.main_chain_length = main.len() as u64;
+        report.confirmed_transactions = main
+            .iter()
+            .flat_map(|h| chain.blocks[h].content.content.iter())
+            .filter(|tx| !tx.transcation.input.is_empty())
+            .count() as u64;
+        report.stale_blocks = self.mined.keys().filter(|h| !main.contains(h)).count() as u64;
+
+        let mut mined_by = vec![0u64; self.nodes.len()];
+        for mined in main.iter().filter_map(|h| self.mined.get(h)) {
+            mined_by[mined.miner] += 1;
+        }
+        let main_length = report.main_chain_length as f64;
+        report.revenue_shares = mined_by.iter().map(|c| ratio(*c, main_length)).collect();
+        let attackers: Vec<usize> = (0..self.nodes.len())
+            .filter(|i| self.nodes[*i].attack.is_some())
+            .collect();
+        report.attacker_hashpower = attackers.iter().map(|i| self.nodes[*i].hashpower).sum();
+        let attacker_blocks = attackers.iter().map(|i| mined_by[*i]).sum();
+        report.attacker_revenue_share = ratio(attacker_blocks, main_length);
+
+        let confirmed: HashSet<H256> = main
+            .iter()
+            .flat_map(|h| chain.blocks[h].content.content.iter())
+            .map(|tx| tx.hash())
+            .collect();
+        for attempt in self.attempts.iter().filter(|a| a.published || a.abandoned) {
+            report.double_spend_attempts += 1;
+            if attempt.published
+                && confirmed.contains(&attempt.refund)
+                && !confirmed.contains(&attempt.payment)
+            {
+                report.double_spend_successes += 1;
+            }
+        }
+        report.double_spend_success_rate = ratio(
+            report.double_spend_successes,
+            report.double_spend_attempts as f64,
+        );
+
+        let mut children: HashMap<H256, u64> = HashMap::new();
+        for mined in self.mined.values() {
+            *children.entry(mined.parent).or_default() += 1;
+        }
+        report.forks = children.values().map(|c| c - 1).sum();
+
+        // summed as integers, floats would depend on the order of the hash map
+        let everyone: Vec<Time> = self
+            .mined
+            .values()
+            .filter(|m| m.reached == self.nodes.len())
+            .map(|m| m.last_arrival - m.time)
+            .collect();
+        let total: Time = everyone.iter().sum();
+        report.mean_propagation = ratio(total, everyone.len() as f64) / 1000.0;
+
+        report.orphan_rate = ratio(report.orphans, self.deliveries as f64);
+        report.stale_rate = ratio(report.stale_blocks, report.blocks_mined as f64);
+        report.fork_rate = ratio(report.forks, report.main_chain_length as f64);
+        let tip = chain.tip();
+        report.converged = honest
+            .iter()
+            .all(|i| self.nodes[*i].blockchain.tip() == tip);
+        report
+    }
+}
+
+fn ratio(count: u64, total: f64) -> f64 {
+    if total > 0.0 {
+        count as f64 / total
+    } else {
+        0.0
+    }
+}
+
+/// Blocks disconnected when the tip moves from `old` to `new`
+fn reorg_depth(blockchain: &Blockchain, old: H256, new: H256) -> u64 {
+    let (mut a, mut b) = (old, new);
+    let mut depth = 0;
+    while a != b {
+        if blockchain.lengths[&a] >= blockchain.lengths[&b] {
+            a = blockchain.blocks[&a].header.parent;
+            depth += 1;
+        } else {
+            b = blockchain.blocks[&b].header.parent;
+        }
+    }
+    depth
+}
+
+/// Pairs of linked nodes, each once
+fn links(topology: &Topology, n: usize, rng: &mut StdRng) -> BTreeSet<(usize, usize)> {
+    let mut res = BTreeSet::new();
+    match topology {
+        Topology::FullMesh => {
+            for a in 0..n {
+                for b in a + 1..n {
+                    res.insert((a, b));
+                }
+            }
+        }
+        Topology::Line => res.extend((1..n).map(|i| (i - 1, i))),
+        Topology::Ring => {
+            res.extend((1..n).map(|i| (i - 1, i)));
+            if n > 2 {
+                res.insert((0, n - 1));
+            }
+        }
+        Topology::Random { degree } => {
+            let all: Vec<usize> = (0..n).collect();
+            for a in 0..n {
+                let others: Vec<usize> = all.iter().cloned().filter(|b| *b != a).collect();
+                for b in others.choose_multiple(rng, *degree) {
+                    res.insert((a.min(*b), a.max(*b)));
+                }
+            }
+        }
+    }
+    res
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::scenario::*;
+    use super::*;
+
+    fn scenario(seed: u64, latency: u64) -> Scenario {
+        Scenario {
+            seed,
+            network: "regtest".to_string(),
+            duration: 120_000,
+            block_interval: 1000,
+            nodes: vec![
+                NodeSpec {
+                    hashpower: 1.0,
+                    bandwidth: None,
+                    strategy: Strategy::Honest,
+                };
+                5
+            ],
+            topology: Topology::Line,
+            latency: Latency {
+                min: latency / 2,
+                max: latency,
+            },
+            bandwidth: Some(1_000_000),
+            transaction_rate: 1.0,
+        }
+    }
+
+    #[test]
+    fn deterministic_fork_statistics() {
+        let fast = Simulation::new(scenario(1, 2)).unwrap().run();
+        assert_eq!(fast, Simulation::new(scenario(1, 2)).unwrap().run());
+        assert_ne!(fast, Simulation::new(scenario(2, 2)).unwrap().run());
+        assert!(fast.blocks_mined > 80);
+        assert_eq!(fast.invalid_blocks, 0);
+        assert!(fast.confirmed_transactions > 0);
+        assert_eq!(
+            fast.main_chain_length + fast.stale_blocks,
+            fast.blocks_mined
+        );
+
+        // slower propagation, compared to the block interval, wastes more blocks
+        let slow = Simulation::new(scenario(1, 800)).unwrap().run();
+        assert!(slow.stale_rate > fast.stale_rate);
+        assert!(slow.fork_rate > fast.fork_rate);
+        assert!(slow.mean_propagation > fast.mean_propagation);
+        assert!(slow.reorgs > 0);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
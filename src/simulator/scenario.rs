@@ -0,0 +1,131 @@
+use super::strategy::Strategy;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+// A scenario file describes one simulated network. Times are virtual milliseconds.
+//
+// {
+//   "seed": 7,
+//   "network": "regtest",
+//   "duration": 3600000,
+//   "block_interval": 10000,
+//   "nodes": [
+//     { "hashpower": 3 },
+//     { "hashpower": 1, "bandwidth": 125000 },
+//     { "hashpower": 2, "strategy": { "double_spend": { "confirmations": 2, "give_up": 4 } } }
+//   ],
+//   "topology": { "random": { "degree": 4 } },
+//   "latency": { "min": 50, "max": 200 },
+//   "bandwidth": 1250000,
+//   "transaction_rate": 2.0
+// }
+
+/// A simulated node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeSpec {
+    /// share of the blocks it mines, relative to the other nodes
+    pub hashpower: f64,
+    /// bytes per second it can upload, instead of the scenario's
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// honest unless given
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+/// Which nodes are linked
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Topology {
+    #[default]
+    FullMesh,
+    Line,
+    Ring,
+    /// every node dials `degree` others picked at random, links go both ways
+    Random {
+        degree: usize,
+    },
+}
+
+/// Delay of every message, picked uniformly for each one, so messages may overtake each other
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Latency {
+    pub min: u64,
+    pub max: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Scenario {
+    /// runs with the same seed give the same report
+    #[serde(default)]
+    pub seed: u64,
+    /// chain parameters the nodes use
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// virtual time simulated
+    pub duration: u64,
+    /// mean time between two blocks of the whole network, the inverse of the mining rate
+    pub block_interval: u64,
+    pub nodes: Vec<NodeSpec>,
+    #[serde(default)]
+    pub topology: Topology,
+    #[serde(default)]
+    pub latency: Latency,
+    /// bytes per second every node can upload, unlimited if not given
+    #[serde(default)]
+    pub bandwidth: Option<u64>,
+    /// transactions per second entering the network, spending the coins of the genesis block
+    #[serde(default)]
+    pub transaction_rate: f64,
+}
+
+fn default_network() -> String {
+    "regtest".to_string()
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("cannot read scenario: {}", e))?;
+        let scenario: Scenario =
+            serde_json::from_slice(&bytes).map_err(|e| format!("invalid scenario: {}", e))?;
+        scenario.check()?;
+        Ok(scenario)
+    }
+
+    /// Reject scenarios that cannot be run
+    pub fn check(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("a scenario needs at least one node".to_string());
+        }
+        if self.block_interval == 0 {
+            return Err("the block interval must be positive".to_string());
+        }
+        if self
+            .nodes
+            .iter()
+            .any(|n| n.hashpower.is_nan() || n.hashpower < 0.0)
+        {
+            return Err("hashpower cannot be negative".to_string());
+        }
+        if self.nodes.iter().all(|n| n.hashpower == 0.0) {
+            return Err("some node must have hashpower".to_string());
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            if let Strategy::Eclipse { victim, .. } = node.strategy {
+                if victim == i
+                    || self.nodes.get(victim).map(|v| &v.strategy) != Some(&Strategy::Honest)
+                {
+                    return Err(format!("node {} cannot eclipse node {}", i, victim));
+                }
+            }
+        }
+        if self.latency.min > self.latency.max {
+            return Err("the minimum latency is above the maximum".to_string());
+        }
+        if self.transaction_rate.is_nan() || self.transaction_rate < 0.0 {
+            return Err("the transaction rate cannot be negative".to_string());
+        }
+        Ok(())
+    }
+}
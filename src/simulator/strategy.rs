@@ -0,0 +1,501 @@
+use super::{Node, Payload, Simulation};
+use crate::blockchain::genesis::Allocation;
+use crate::types::block::Block;
+use crate::types::hash::{Hashable, H256};
+use crate::types::state::State;
+use crate::types::transaction::{Mempool, UTXO_input};
+use crate::wallet::Wallet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Strategies of attacking miners. They mine with the same template and keep the same
+// `Blockchain` as honest nodes, but choose which block to mine on and when to let the network
+// see their blocks. They never relay the blocks of others, unless the strategy says so.
+//
+// A selfish miner keeps the blocks it finds to itself and publishes them only to orphan honest
+// blocks, following Eyal and Sirer's algorithm; the honest nodes that mine on its block during
+// a race come from the propagation of the simulated network. A double spender pays a merchant
+// and mines in secret a chain where the same coin goes back to itself; once the payment is deep
+// enough in the public chain and the secret one is longer, it publishes it. An eclipse attacker
+// holds every connection of its victim and feeds it a chain where the victim is paid, while
+// the network confirms a transaction sending the coin back to the attacker.
+
+/// Coins of every attacker in the genesis block, one is used by each double spend
+const ATTACK_COINS: usize = 64;
+const ATTACK_COIN_VALUE: u64 = 100_000;
+/// Fee of the payments and of the transactions conflicting with them
+const ATTACK_FEE: u64 = 1000;
+
+/// How a simulated node mines and relays blocks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    Honest,
+    /// withholds its blocks and publishes them to orphan honest ones
+    Selfish,
+    /// publishes a secret chain dropping its payment once the payment has `confirmations`
+    /// blocks, or gives up when the public chain is `give_up` blocks ahead
+    DoubleSpend { confirmations: u64, give_up: u64 },
+    /// isolates `victim` and tries to double spend a payment once the victim saw it
+    /// `confirmations` blocks deep
+    Eclipse { victim: usize, confirmations: u64 },
+}
+
+/// A double spend: a payment to a merchant and a transaction spending the same coin
+pub(super) struct Attempt {
+    pub payment: H256,
+    pub refund: H256,
+    /// public tip when it started
+    pub start: H256,
+    /// the secret chain was published, or the victim saw the payment deep enough
+    pub published: bool,
+    pub abandoned: bool,
+}
+
+/// State of an attacking node
+pub(super) struct Attack {
+    strategy: Strategy,
+    wallet: Wallet,
+    /// block it mines on
+    private_tip: H256,
+    /// highest block the network knows of
+    public_tip: H256,
+    /// blocks not published yet, oldest first; for an eclipse, public blocks kept from the victim
+    withheld: Vec<Block>,
+    /// one of its published blocks competes with an honest one at the same height
+    race: bool,
+    /// index of the current double spend in `Simulation::attempts`
+    attempt: Option<usize>,
+    used_coins: HashSet<UTXO_input>,
+}
+
+impl Attack {
+    pub fn new(strategy: Strategy, seed: &[u8]) -> Self {
+        let mut wallet = Wallet::from_seed(seed);
+        wallet.generate_key("attacker").unwrap();
+        Attack {
+            strategy,
+            wallet,
+            private_tip: H256::default(),
+            public_tip: H256::default(),
+            withheld: Vec::new(),
+            race: false,
+            attempt: None,
+            used_coins: HashSet::new(),
+        }
+    }
+
+    pub fn starting_at(mut self, genesis: H256) -> Self {
+        self.private_tip = genesis;
+        self.public_tip = genesis;
+        self
+    }
+
+    /// Coins to put in the genesis block of a network whose addresses start with `prefix`
+    pub fn allocations(&self, prefix: &str) -> Vec<Allocation> {
+        let address = self.wallet.addresses()[0].encode(prefix);
+        vec![
+            Allocation {
+                address,
+                value: ATTACK_COIN_VALUE,
+            };
+            ATTACK_COINS
+        ]
+    }
+}
+
+/// Give every eclipse attacker all the connections of its victim
+pub(super) fn eclipse_victims(nodes: &mut [Node]) {
+    for attacker in 0..nodes.len() {
+        let victim = match nodes[attacker].attack.as_ref().map(|a| &a.strategy) {
+            Some(Strategy::Eclipse { victim, .. }) => *victim,
+            _ => continue,
+        };
+        for peer in std::mem::take(&mut nodes[victim].peers) {
+            nodes[peer].peers.retain(|p| *p != victim);
+        }
+        nodes[victim].peers.push(attacker);
+        nodes[attacker].peers.push(victim);
+    }
+}
+
+impl Simulation {
+    /// Double spenders make their first payment when the simulation starts
+    pub(super) fn start_attacks(&mut self) {
+        for node in 0..self.nodes.len() {
+            if let Some(mut attack) = self.nodes[node].attack.take() {
+                self.start_attempt(node, &mut attack);
+                self.nodes[node].attack = Some(attack);
+            }
+        }
+    }
+
+    fn height(&self, node: usize, hash: H256) -> u32 {
+        self.nodes[node].blockchain.lengths[&hash]
+    }
+
+    /// Blocks from the one holding `tx` to `tip`, both included, looking back to `start`
+    fn confirmations(&self, node: usize, tip: H256, start: H256, tx: H256) -> u64 {
+        let blockchain = &self.nodes[node].blockchain;
+        let mut cur = tip;
+        let mut depth = 0;
+        while blockchain.lengths[&cur] > blockchain.lengths[&start] {
+            let block = &blockchain.blocks[&cur];
+            depth += 1;
+            if block.content.content.iter().any(|t| t.hash() == tx) {
+                return depth;
+            }
+            cur = block.header.parent;
+        }
+        0
+    }
+
+    /// Attacker `node` found a block
+    pub(super) fn attacker_mine(&mut self, node: usize) {
+        let mut attack = self.nodes[node].attack.take().unwrap();
+        if attack.attempt.is_none() {
+            self.start_attempt(node, &mut attack);
+        }
+        // without a double spend going on, attackers other than selfish ones mine honestly
+        let honest = attack.attempt.is_none() && attack.strategy != Strategy::Selfish;
+        let parent = if honest {
+            attack.public_tip
+        } else {
+            attack.private_tip
+        };
+        let state = self.nodes[node].blockchain.states[&parent].clone();
+        self.nodes[node].mempool.prune(&state);
+        let block = self.build_block(node, parent);
+        let hash = block.hash();
+        self.store_block(node, &block);
+        attack.private_tip = hash;
+
+        if honest {
+            attack.public_tip = hash;
+            self.relay(node, None, Payload::Block(block));
+        } else {
+            match attack.strategy {
+                Strategy::Selfish => {
+                    let lead = self.height(node, parent) as i64
+                        - self.height(node, attack.public_tip) as i64;
+                    attack.withheld.push(block);
+                    // win the race with the next block
+                    if attack.race && lead == 0 {
+                        self.publish(node, &mut attack, None);
+                        attack.race = false;
+                    }
+                }
+                Strategy::DoubleSpend { .. } => {
+                    attack.withheld.push(block);
+                    self.check_double_spend(node, &mut attack);
+                }
+                Strategy::Eclipse { victim, .. } => {
+                    self.send(node, victim, Payload::Block(block));
+                    self.check_eclipse(node, &mut attack);
+                }
+                Strategy::Honest => unreachable!(),
+            }
+        }
+        self.nodes[node].attack = Some(attack);
+    }
+
+    /// Attacker `node` connected a block it did not mine, received from `from`
+    pub(super) fn attacker_received(&mut self, node: usize, from: Option<usize>, block: Block) {
+        let mut attack = self.nodes[node].attack.take().unwrap();
+        let hash = block.hash();
+        let height = self.height(node, hash);
+        let public_height = self.height(node, attack.public_tip);
+
+        match attack.strategy.clone() {
+            Strategy::Selfish => {
+                if height > public_height {
+                    let lead = self.height(node, attack.private_tip) as i64 - public_height as i64;
+                    attack.public_tip = hash;
+                    match lead {
+                        // the honest chain is ahead, mine on it
+                        i64::MIN..=0 => {
+                            attack.private_tip = hash;
+                            attack.withheld.clear();
+                            attack.race = false;
+                        }
+                        // match it and race
+                        1 => {
+                            self.publish(node, &mut attack, None);
+                            attack.race = true;
+                        }
+                        // orphan it
+                        2 => {
+                            self.publish(node, &mut attack, None);
+                            attack.race = false;
+                        }
+                        // stay ahead, revealing as much as the honest chain has
+                        _ => self.publish(node, &mut attack, Some(height)),
+                    }
+                }
+            }
+            Strategy::DoubleSpend { .. } => {
+                if height > public_height {
+                    attack.public_tip = hash;
+                }
+                if attack.attempt.is_none() {
+                    attack.private_tip = attack.public_tip;
+                }
+                self.check_double_spend(node, &mut attack);
+            }
+            Strategy::Eclipse { victim, .. } => {
+                if from == Some(victim) {
+                    // the victim extends the chain it was fed
+                    if height > self.height(node, attack.private_tip) {
+                        attack.private_tip = hash;
+                    }
+                    self.check_eclipse(node, &mut attack);
+                } else {
+                    if height > public_height {
+                        attack.public_tip = hash;
+                    }
+                    if attack.attempt.is_some() {
+                        attack.withheld.push(block);
+                    } else {
+                        self.send(node, victim, Payload::Block(block));
+                    }
+                }
+            }
+            Strategy::Honest => unreachable!(),
+        }
+        self.nodes[node].attack = Some(attack);
+    }
+
+    /// Send withheld blocks up to `height`, or all of them, to every peer
+    fn publish(&mut self, node: usize, attack: &mut Attack, height: Option<u32>) {
+        let withheld = std::mem::take(&mut attack.withheld);
+        for block in withheld {
+            let hash = block.hash();
+            let block_height = self.height(node, hash);
+            if height.is_some_and(|h| block_height > h) {
+                attack.withheld.push(block);
+                continue;
+            }
+            if block_height > self.height(node, attack.public_tip) {
+                attack.public_tip = hash;
+            }
+            self.relay(node, None, Payload::Block(block));
+        }
+    }
+
+    /// Pay the merchant with a coin and spend the same coin again, from the public tip
+    fn start_attempt(&mut self, node: usize, attack: &mut Attack) {
+        let (merchant, to_victim) = match attack.strategy {
+            Strategy::DoubleSpend { .. } => {
+                match self.nodes.iter().position(|n| n.attack.is_none()) {
+                    Some(merchant) => (merchant, false),
+                    None => return,
+                }
+            }
+            Strategy::Eclipse { victim, .. } => (victim, true),
+            _ => return,
+        };
+        let blockchain = &self.nodes[node].blockchain;
+        let mut coins: Vec<_> = attack
+            .wallet
+            .utxos(&blockchain.states[&attack.public_tip])
+            .into_iter()
+            .filter(|(input, coin)| {
+                coin.value >= ATTACK_COIN_VALUE && !attack.used_coins.contains(input)
+            })
+            .collect();
+        // sorted, hash maps have no fixed order
+        coins.sort_by_key(|(input, _)| (input.prev_tx_hash, input.index));
+        let (input, coin) = match coins.into_iter().next() {
+            Some(coin) => coin,
+            None => return,
+        };
+        attack.used_coins.insert(input.clone());
+
+        // sign against the coin alone, so that both transactions spend it
+        let mut spent = State::default();
+        spent.utxos.insert(input, coin.clone());
+        let amount = (coin.value - ATTACK_FEE) / 2;
+        let merchant_address = self.nodes[merchant].reward_to;
+        let own_address = attack.wallet.addresses()[0];
+        let payment = attack
+            .wallet
+            .create_transaction(
+                &spent,
+                &Mempool::new(),
+                merchant_address,
+                amount,
+                ATTACK_FEE,
+            )
+            .unwrap();
+        let refund = attack
+            .wallet
+            .create_transaction(&spent, &Mempool::new(), own_address, amount, ATTACK_FEE)
+            .unwrap();
+
+        self.attempts.push(Attempt {
+            payment: payment.hash(),
+            refund: refund.hash(),
+            start: attack.public_tip,
+            published: false,
+            abandoned: false,
+        });
+        attack.attempt = Some(self.attempts.len() - 1);
+        attack.private_tip = attack.public_tip;
+        attack.withheld.clear();
+
+        // the secret chain holds one of them, the public network gets the other
+        let (secret, public) = if to_victim {
+            (payment, refund)
+        } else {
+            (refund, payment)
+        };
+        let mempool = &mut self.nodes[node].mempool;
+        let _ = mempool.insert(&secret, ATTACK_FEE);
+        let peers: Vec<usize> = self.nodes[node]
+            .peers
+            .iter()
+            .cloned()
+            .filter(|p| !to_victim || *p != merchant)
+            .collect();
+        for peer in peers {
+            self.send(node, peer, Payload::Transaction(public.clone()));
+        }
+    }
+
+    fn check_double_spend(&mut self, node: usize, attack: &mut Attack) {
+        let (confirmations, give_up) = match attack.strategy {
+            Strategy::DoubleSpend {
+                confirmations,
+                give_up,
+            } => (confirmations, give_up),
+            _ => return,
+        };
+        let i = match attack.attempt {
+            Some(i) => i,
+            None => return,
+        };
+        let private_height = self.height(node, attack.private_tip) as u64;
+        let public_height = self.height(node, attack.public_tip) as u64;
+        let (payment, start) = (self.attempts[i].payment, self.attempts[i].start);
+        let confirmed = self.confirmations(node, attack.public_tip, start, payment);
+        if confirmed >= confirmations.max(1) && private_height > public_height {
+            self.publish(node, attack, None);
+            self.attempts[i].published = true;
+            attack.attempt = None;
+            self.start_attempt(node, attack);
+        } else if public_height >= private_height + give_up.max(1) {
+            self.attempts[i].abandoned = true;
+            attack.attempt = None;
+            self.start_attempt(node, attack);
+        }
+    }
+
+    fn check_eclipse(&mut self, node: usize, attack: &mut Attack) {
+        let (victim, confirmations) = match attack.strategy {
+            Strategy::Eclipse {
+                victim,
+                confirmations,
+            } => (victim, confirmations),
+            _ => return,
+        };
+        let i = match attack.attempt {
+            Some(i) => i,
+            None => return,
+        };
+        let (payment, start) = (self.attempts[i].payment, self.attempts[i].start);
+        let confirmed = self.confirmations(node, attack.private_tip, start, payment);
+        if confirmed >= confirmations.max(1) {
+            // the victim took the payment, let it see the public chain again
+            self.attempts[i].published = true;
+            attack.attempt = None;
+            for block in std::mem::take(&mut attack.withheld) {
+                self.send(node, victim, Payload::Block(block));
+            }
+            attack.private_tip = attack.public_tip;
+            self.start_attempt(node, attack);
+        }
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::super::scenario::*;
+    use super::super::Simulation;
+    use super::Strategy;
+
+    fn scenario(attacker: f64, strategy: Strategy) -> Scenario {
+        let honest = NodeSpec {
+            hashpower: (1.0 - attacker) / 4.0,
+            bandwidth: None,
+            strategy: Strategy::Honest,
+        };
+        let mut nodes = vec![honest; 4];
+        nodes.push(NodeSpec {
+            hashpower: attacker,
+            bandwidth: None,
+            strategy,
+        });
+        Scenario {
+            seed: 11,
+            network: "regtest".to_string(),
+            duration: 300_000,
+            block_interval: 1000,
+            nodes,
+            topology: Topology::FullMesh,
+            latency: Latency { min: 5, max: 20 },
+            bandwidth: None,
+            transaction_rate: 0.0,
+        }
+    }
+
+    #[test]
+    fn selfish_mining_pays_above_hashpower() {
+        let honest = Simulation::new(scenario(0.4, Strategy::Honest))
+            .unwrap()
+            .run();
+        assert_eq!(honest.attacker_hashpower, 0.0);
+        let selfish = Simulation::new(scenario(0.4, Strategy::Selfish))
+            .unwrap()
+            .run();
+        assert!((selfish.attacker_hashpower - 0.4).abs() < 1e-9);
+        // honest miners lose their blocks to the withheld ones
+        assert!(selfish.attacker_revenue_share > 0.45);
+        assert!(selfish.stale_rate > honest.stale_rate * 5.0);
+        assert_eq!(selfish.invalid_blocks, 0);
+    }
+
+    #[test]
+    fn double_spends_depend_on_hashpower() {
+        let strategy = Strategy::DoubleSpend {
+            confirmations: 2,
+            give_up: 3,
+        };
+        let strong = Simulation::new(scenario(0.45, strategy.clone()))
+            .unwrap()
+            .run();
+        let weak = Simulation::new(scenario(0.1, strategy)).unwrap().run();
+        assert!(strong.double_spend_attempts > 10);
+        assert!(strong.double_spend_successes > 0);
+        assert!(weak.double_spend_attempts > 10);
+        assert!(strong.double_spend_success_rate > weak.double_spend_success_rate);
+        assert!(strong.converged);
+    }
+
+    #[test]
+    fn eclipsed_node_is_double_spent() {
+        let eclipse = Strategy::Eclipse {
+            victim: 0,
+            confirmations: 1,
+        };
+        let report = Simulation::new(scenario(0.2, eclipse)).unwrap().run();
+        assert!(report.double_spend_successes > 0);
+        // the victim mined on the chain it was fed, not on the public one
+        let fair = 0.2;
+        assert!(report.revenue_shares[0] < fair);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
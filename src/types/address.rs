@@ -2,6 +2,7 @@ extern crate ring;
 
 use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
+use std::sync::RwLock;
 // Add ring crate
 use ring::digest::{self, Context, Digest, SHA256};
 
@@ -23,21 +24,184 @@ impl std::convert::From<[u8; 20]> for Address {
     }
 }
 
-impl std::fmt::Display for Address {
+impl std::convert::AsRef<[u8]> for Address {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Human-readable part of every address, in front of the `1` separator, unless the network
+/// parameters set another one
+pub const ADDRESS_PREFIX: &str = "bc";
+
+// Addresses are displayed and parsed all over the node, the prefix of the network it runs on is
+// set once at startup rather than passed around. Code that may serve chains of several networks
+// in one process, like the genesis block and the API, calls `encode` and `decode` with the prefix
+// of its `ChainParams` instead.
+static NETWORK_PREFIX: RwLock<&str> = RwLock::new(ADDRESS_PREFIX);
+
+/// Prefix `Display` and `FromStr` use for addresses
+pub fn address_prefix() -> &'static str {
+    *NETWORK_PREFIX.read().unwrap()
+}
+
+pub fn set_address_prefix(prefix: &'static str) {
+    *NETWORK_PREFIX.write().unwrap() = prefix;
+}
+
+// Addresses are written in bech32 (BIP-173): the prefix, a `1`, the 20 bytes in groups of 5 bits
+// using the charset below, and a 6 characters checksum. The checksum commits to the prefix too,
+// so an address of another network is rejected, and any typo of up to 4 characters is detected.
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const SEPARATOR: char = '1';
+const CHECKSUM_LEN: usize = 6;
+
+/// Reasons a string is not a valid address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressError {
+    MixedCase,
+    MissingSeparator,
+    WrongPrefix { expected: String, found: String },
+    InvalidCharacter(char),
+    InvalidChecksum,
+    InvalidLength(usize),
+    InvalidPadding,
+}
+
+impl std::fmt::Display for AddressError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let start = if let Some(precision) = f.precision() {
-            if precision >= 40 {
-                0
-            } else {
-                20 - precision / 2
+        match self {
+            AddressError::MixedCase => write!(f, "address mixes upper and lower case"),
+            AddressError::MissingSeparator => {
+                write!(f, "address has no '{}' separator after its prefix", SEPARATOR)
+            }
+            AddressError::WrongPrefix { expected, found } => write!(
+                f,
+                "address prefix is '{}', expected '{}' (wrong network?)",
+                found, expected
+            ),
+            AddressError::InvalidCharacter(c) => {
+                write!(f, "invalid character '{}' in address", c)
+            }
+            AddressError::InvalidChecksum => {
+                write!(f, "address checksum does not match (typo in the address?)")
+            }
+            AddressError::InvalidLength(len) => {
+                write!(f, "address holds {} bytes instead of 20", len)
             }
-        } else {
-            0
-        };
-        for byte_idx in start..20 {
-            write!(f, "{:>02x}", &self.0[byte_idx])?;
+            AddressError::InvalidPadding => write!(f, "address has non-zero padding bits"),
+        }
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a_57b2, 0x2650_8e6d, 0x1ea1_19fa, 0x3d42_33dd, 0x2a14_62b3];
+    let mut chk: u32 = 1;
+    for value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ff_ffff) << 5) ^ u32::from(*value);
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+fn expand_prefix(prefix: &str) -> Vec<u8> {
+    let mut res: Vec<u8> = prefix.bytes().map(|b| b >> 5).collect();
+    res.push(0);
+    res.extend(prefix.bytes().map(|b| b & 31));
+    res
+}
+
+fn checksum(prefix: &str, data: &[u8]) -> Vec<u8> {
+    let mut values = expand_prefix(prefix);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0u8; CHECKSUM_LEN]);
+    let chk = polymod(&values) ^ 1;
+    (0..CHECKSUM_LEN)
+        .map(|i| ((chk >> (5 * (5 - i))) & 31) as u8)
+        .collect()
+}
+
+/// Regroup bits, e.g. bytes into 5-bit groups; the last group is padded with zeros if `pad`
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Result<Vec<u8>, AddressError> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let mut res = Vec::new();
+    let max = (1u32 << to) - 1;
+    for value in data {
+        acc = (acc << from) | u32::from(*value);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            res.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            res.push(((acc << (to - bits)) & max) as u8);
         }
-        Ok(())
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return Err(AddressError::InvalidPadding);
+    }
+    Ok(res)
+}
+
+fn encode_bech32(prefix: &str, data: &[u8]) -> String {
+    let mut res = prefix.to_string();
+    res.push(SEPARATOR);
+    for value in data.iter().chain(checksum(prefix, data).iter()) {
+        res.push(CHARSET[*value as usize] as char);
+    }
+    res
+}
+
+/// Split a bech32 string into its lower case prefix and its 5-bit data, checksum removed
+fn decode_bech32(s: &str) -> Result<(String, Vec<u8>), AddressError> {
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(AddressError::MixedCase);
+    }
+    let s = s.to_ascii_lowercase();
+    let pos = s.rfind(SEPARATOR).ok_or(AddressError::MissingSeparator)?;
+    if pos == 0 || pos + 1 + CHECKSUM_LEN > s.len() {
+        return Err(AddressError::MissingSeparator);
+    }
+    let (prefix, rest) = (&s[..pos], &s[pos + 1..]);
+    if let Some(c) = prefix.chars().find(|c| !('!'..='~').contains(c)) {
+        return Err(AddressError::InvalidCharacter(c));
+    }
+    let data = rest
+        .chars()
+        .map(|c| {
+            CHARSET
+                .iter()
+                .position(|x| *x as char == c)
+                .map(|i| i as u8)
+                .ok_or(AddressError::InvalidCharacter(c))
+        })
+        .collect::<Result<Vec<u8>, AddressError>>()?;
+    let mut values = expand_prefix(prefix);
+    values.extend_from_slice(&data);
+    if polymod(&values) != 1 {
+        return Err(AddressError::InvalidChecksum);
+    }
+    Ok((prefix.to_string(), data[..data.len() - CHECKSUM_LEN].to_vec()))
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.encode(address_prefix()))
+    }
+}
+
+impl std::str::FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Address::decode(s, address_prefix())
     }
 }
 
@@ -66,12 +230,34 @@ impl Address {
     pub fn new(param: [u8; 20]) -> Self {
         Address(param)
     }
+
+    /// Checksummed text encoding of the address with the given network prefix
+    pub fn encode(&self, prefix: &str) -> String {
+        encode_bech32(prefix, &convert_bits(&self.0, 8, 5, true).unwrap())
+    }
+
+    /// Parse an address written by `encode`, it must carry the given network prefix
+    pub fn decode(s: &str, prefix: &str) -> Result<Address, AddressError> {
+        let (found, data) = decode_bech32(s)?;
+        if found != prefix {
+            return Err(AddressError::WrongPrefix {
+                expected: prefix.to_string(),
+                found,
+            });
+        }
+        let bytes = convert_bits(&data, 5, 8, false)?;
+        let address: [u8; 20] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| AddressError::InvalidLength(bytes.len()))?;
+        Ok(Address(address))
+    }
 }
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
 
 #[cfg(test)]
 mod test {
-    use super::Address;
+    use super::*;
 
     #[test]
     fn from_a_test_key() {
@@ -83,6 +269,52 @@ mod test {
         // "0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d"
         // take the last 20 bytes, we get "1851a0eae0060a132cf0f64a0ffaea248de6cba0"
     }
+
+    #[test]
+    fn bech32_test_vectors() {
+        // valid strings from BIP-173
+        assert_eq!(decode_bech32("A12UEL5L"), Ok(("a".to_string(), vec![])));
+        let (prefix, data) =
+            decode_bech32("abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw").unwrap();
+        assert_eq!(prefix, "abcdef");
+        assert_eq!(data, (0..32).collect::<Vec<u8>>());
+        assert_eq!(
+            decode_bech32("a12UEL5L"),
+            Err(AddressError::MixedCase)
+        );
+    }
+
+    #[test]
+    fn encode_and_detect_errors() {
+        let addr: Address = hex!("1851a0eae0060a132cf0f64a0ffaea248de6cba0").into();
+        let s = addr.to_string();
+        assert!(s.starts_with("bc1"));
+        assert_eq!(s.len(), 41);
+        assert_eq!(s.parse::<Address>(), Ok(addr));
+        assert_eq!(s.to_uppercase().parse::<Address>(), Ok(addr));
+
+        // flip one character
+        let mut typo: Vec<char> = s.chars().collect();
+        typo[10] = if typo[10] == 'q' { 'p' } else { 'q' };
+        let typo: String = typo.into_iter().collect();
+        assert_eq!(typo.parse::<Address>(), Err(AddressError::InvalidChecksum));
+
+        assert_eq!(
+            Address::decode(&addr.encode("tb"), "bc"),
+            Err(AddressError::WrongPrefix {
+                expected: "bc".to_string(),
+                found: "tb".to_string()
+            })
+        );
+        assert_eq!(
+            "deadbeef".parse::<Address>(),
+            Err(AddressError::MissingSeparator)
+        );
+        assert_eq!(
+            "bc1qbqqqqqqq".parse::<Address>(),
+            Err(AddressError::InvalidCharacter('b'))
+        );
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
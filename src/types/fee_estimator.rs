@@ -0,0 +1,267 @@
+use super::hash::H256;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Fee estimation in the spirit of Bitcoin Core's: every transaction is put in a bucket by its fee
+// rate when it is confirmed, together with the number of blocks it waited since it entered the
+// mempool. The estimate for a target of N blocks is the lowest range of buckets in which enough
+// transactions were confirmed within N blocks. Transactions still waiting for longer than the
+// target count as failures of their bucket. Older data is decayed at every block, so the
+// estimate follows the recent state of the network.
+
+/// Highest target an estimate can be asked for, in blocks
+pub const MAX_TARGET: usize = 25;
+/// Share of the transactions of a range of buckets that must be confirmed within the target
+pub const SUCCESS_THRESHOLD: f64 = 0.85;
+/// Decayed number of transactions a range of buckets needs before its success rate is trusted
+pub const SUFFICIENT_DATA: f64 = 4.0;
+/// Weight every data point keeps at each new block
+pub const DECAY: f64 = 0.998;
+const MIN_BUCKET_RATE: f64 = 1.0;
+const MAX_BUCKET_RATE: f64 = 1e9;
+const BUCKET_SPACING: f64 = 1.2;
+
+/// Fee rate in fee per 1000 bytes of serialized transaction
+pub fn fee_rate(fee: u64, size: u64) -> f64 {
+    fee as f64 * 1000.0 / size.max(1) as f64
+}
+
+fn bucket_bounds() -> Vec<f64> {
+    let mut res = vec![0.0];
+    let mut rate = MIN_BUCKET_RATE;
+    while rate <= MAX_BUCKET_RATE {
+        res.push(rate);
+        rate *= BUCKET_SPACING;
+    }
+    res
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeeEstimator {
+    /// lowest fee rate of each bucket, not saved as floats may not read back exactly
+    #[serde(skip, default = "bucket_bounds")]
+    buckets: Vec<f64>,
+    /// for each bucket, transactions confirmed within `i + 1` blocks
+    confirmed: Vec<Vec<f64>>,
+    /// for each bucket, transactions confirmed within any number of blocks
+    total: Vec<f64>,
+    /// for each bucket, sum of the fee rates of the confirmed transactions
+    fee_rates: Vec<f64>,
+    /// height of the last block processed
+    pub height: u64,
+    /// genesis block of the chain the data was collected on
+    #[serde(default)]
+    pub genesis: H256,
+    /// where the data is saved, if anywhere
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FeeEstimator {
+    /// An estimator without any data, kept in memory only
+    pub fn new() -> Self {
+        let buckets = bucket_bounds();
+        let n = buckets.len();
+        FeeEstimator {
+            buckets,
+            confirmed: vec![vec![0.0; MAX_TARGET]; n],
+            total: vec![0.0; n],
+            fee_rates: vec![0.0; n],
+            height: 0,
+            genesis: H256::default(),
+            path: None,
+        }
+    }
+
+    /// Load the data saved at `path`, or start without data if there is no file yet. `save`
+    /// writes it back there.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut res = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice::<FeeEstimator>(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Self::new(),
+            Err(e) => return Err(e),
+        };
+        // data collected with other buckets cannot be used
+        let n = res.buckets.len();
+        if res.total.len() != n
+            || res.fee_rates.len() != n
+            || res.confirmed.len() != n
+            || res.confirmed.iter().any(|c| c.len() != MAX_TARGET)
+        {
+            res = Self::new();
+        }
+        res.path = Some(path.to_path_buf());
+        Ok(res)
+    }
+
+    /// Go on with the chain starting with `genesis`, whose tip is at `height`. Blocks are not kept
+    /// across restarts, so the chain usually starts over below the last block processed, which
+    /// is then taken back to the tip for the next blocks not to be ignored. Data collected on
+    /// another chain is dropped.
+    pub fn resume(&mut self, genesis: H256, height: u64) {
+        if self.genesis != genesis {
+            let path = self.path.take();
+            *self = Self::new();
+            self.genesis = genesis;
+            self.path = path;
+        }
+        self.height = self.height.min(height);
+    }
+
+    /// Write the data to the file it was opened from, if any
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => fs::write(path, serde_json::to_vec(self)?),
+            None => Ok(()),
+        }
+    }
+
+    fn bucket(&self, fee_rate: f64) -> usize {
+        self.buckets
+            .iter()
+            .rposition(|lowest| *lowest <= fee_rate)
+            .unwrap_or_default()
+    }
+
+    /// Record the block at `height`, with the fee rate of each of its transactions that we saw
+    /// in the mempool and how many blocks it waited there, at least one
+    pub fn process_block(&mut self, height: u64, confirmed: &[(f64, u64)]) {
+        if height <= self.height {
+            return;
+        }
+        self.height = height;
+        for b in 0..self.buckets.len() {
+            self.total[b] *= DECAY;
+            self.fee_rates[b] *= DECAY;
+            for count in self.confirmed[b].iter_mut() {
+                *count *= DECAY;
+            }
+        }
+        for (fee_rate, blocks) in confirmed {
+            let b = self.bucket(*fee_rate);
+            self.total[b] += 1.0;
+            self.fee_rates[b] += fee_rate;
+            let first = (*blocks).max(1) as usize - 1;
+            for count in self.confirmed[b].iter_mut().skip(first) {
+                *count += 1.0;
+            }
+        }
+    }
+
+    /// Fee rate needed to be confirmed within `target` blocks, given the fee rate of each
+    /// transaction in the mempool and how many blocks it has been waiting. `None` without
+    /// enough data.
+    pub fn estimate(&self, target: usize, pending: &[(f64, u64)]) -> Option<f64> {
+        if target == 0 {
+            return None;
+        }
+        let target = target.min(MAX_TARGET);
+        let mut failed = vec![0.0; self.buckets.len()];
+        for (fee_rate, waited) in pending {
+            if *waited >= target as u64 {
+                failed[self.bucket(*fee_rate)] += 1.0;
+            }
+        }
+
+        // walk down from the highest fee rates, grouping buckets until there is enough data, and
+        // stop at the first group that does not confirm fast enough
+        let mut res = None;
+        let (mut within, mut total, mut failures, mut fee_rates) = (0.0, 0.0, 0.0, 0.0);
+        for b in (0..self.buckets.len()).rev() {
+            within += self.confirmed[b][target - 1];
+            total += self.total[b];
+            failures += failed[b];
+            fee_rates += self.fee_rates[b];
+            if total + failures < SUFFICIENT_DATA {
+                continue;
+            }
+            if within / (total + failures) < SUCCESS_THRESHOLD {
+                break;
+            }
+            res = Some(fee_rates / total);
+            within = 0.0;
+            total = 0.0;
+            failures = 0.0;
+            fee_rates = 0.0;
+        }
+        res
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_and_persist() {
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(estimator.estimate(1, &[]), None);
+        // high fee rates confirm in the next block, low ones after five
+        for height in 1..=10 {
+            estimator.process_block(height, &[(100.0, 1), (100.0, 1), (10.0, 5), (10.0, 5)]);
+        }
+        // a block seen twice is only counted once
+        estimator.process_block(10, &[(1.0, 30); 100]);
+
+        let one = estimator.estimate(1, &[]).unwrap();
+        assert!((one - 100.0).abs() < 1e-6);
+        let five = estimator.estimate(5, &[]).unwrap();
+        assert!((five - 10.0).abs() < 1e-6);
+        // larger targets than the data covers get the same answer as the largest one
+        assert_eq!(estimator.estimate(100, &[]), estimator.estimate(MAX_TARGET, &[]));
+        // low fee transactions stuck in the mempool make their bucket fail
+        let stuck = vec![(10.0, 6); 10];
+        assert!((estimator.estimate(5, &stuck).unwrap() - 100.0).abs() < 1e-6);
+
+        let path = std::env::temp_dir().join(format!("fee-estimates-{}", rand::random::<u64>()));
+        let mut saved = FeeEstimator::open(&path).unwrap();
+        assert_eq!(saved.estimate(1, &[]), None);
+        saved.confirmed = estimator.confirmed.clone();
+        saved.total = estimator.total.clone();
+        saved.fee_rates = estimator.fee_rates.clone();
+        saved.height = estimator.height;
+        saved.save().unwrap();
+        let loaded = FeeEstimator::open(&path).unwrap();
+        assert_eq!(loaded.height, 10);
+        assert_eq!(loaded.estimate(5, &[]), estimator.estimate(5, &[]));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restart_from_genesis() {
+        let genesis: H256 = [7u8; 32].into();
+        let path = std::env::temp_dir().join(format!("fee-estimates-{}", rand::random::<u64>()));
+        let mut estimator = FeeEstimator::open(&path).unwrap();
+        estimator.resume(genesis, 0);
+        for height in 1..=10 {
+            estimator.process_block(height, &[(100.0, 1); 4]);
+        }
+        estimator.save().unwrap();
+
+        // the node starts again from genesis, its new blocks still count
+        let mut reopened = FeeEstimator::open(&path).unwrap();
+        reopened.resume(genesis, 0);
+        assert_eq!(reopened.height, 0);
+        reopened.process_block(1, &[(10.0, 1); 100]);
+        assert_eq!(reopened.height, 1);
+        assert!(reopened.estimate(1, &[]).unwrap() < 100.0);
+
+        // data of another network is of no use
+        let mut other = FeeEstimator::open(&path).unwrap();
+        other.resume([8u8; 32].into(), 0);
+        assert_eq!(other.estimate(1, &[]), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -47,6 +47,17 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    /// Parse the 64 hex digits `Display` writes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0u8; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(H256(bytes))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -1,7 +1,13 @@
 pub mod address;
 pub mod block;
+pub mod fee_estimator;
 pub mod hash;
 pub mod key_pair;
 pub mod merkle;
+pub mod multisig;
+pub mod orphan;
+pub mod script;
+pub mod state;
+pub mod timelock;
 pub mod transaction;
 pub mod transaction_generate;
@@ -0,0 +1,96 @@
+use super::address::Address;
+use super::hash::H256;
+use super::script::Script;
+use super::transaction::Witness;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Largest number of keys in a multisig policy
+pub const MAX_MULTISIG_KEYS: usize = 16;
+const PUBLIC_KEY_LEN: usize = 32;
+
+/// An m-of-n lock: an output locked by the `Script::multisig` script of this policy can only be
+/// spent with valid signatures from at least `threshold` of `public_keys`. The output is still
+/// paid to an address, the hash of the script, so it shows up like any other in
+/// `State::utxos_of`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    pub public_keys: Vec<Vec<u8>>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, public_keys: Vec<Vec<u8>>) -> Self {
+        MultisigPolicy {
+            threshold,
+            public_keys,
+        }
+    }
+
+    /// At least one signature is required, at most every key, and keys are distinct Ed25519 keys
+    pub fn is_valid(&self) -> bool {
+        let distinct: HashSet<&Vec<u8>> = self.public_keys.iter().collect();
+        self.threshold >= 1
+            && self.public_keys.len() <= MAX_MULTISIG_KEYS
+            && usize::from(self.threshold) <= self.public_keys.len()
+            && distinct.len() == self.public_keys.len()
+            && self.public_keys.iter().all(|k| k.len() == PUBLIC_KEY_LEN)
+    }
+
+    /// The address outputs locked by this policy are paid to
+    pub fn address(&self) -> Address {
+        Script::multisig(self).address()
+    }
+
+    /// Number of distinct policy keys with a valid signature of `digest` in the witness
+    pub fn count_valid(&self, digest: &H256, witness: &Witness) -> usize {
+        let mut signers = HashSet::new();
+        for s in witness.signatures.iter() {
+            if self.public_keys.contains(&s.public_key)
+                && !signers.contains(&s.public_key)
+                && s.verify(digest)
+            {
+                signers.insert(&s.public_key);
+            }
+        }
+        signers.len()
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn two_of_three() {
+        let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+        let policy = MultisigPolicy::new(
+            2,
+            keys.iter()
+                .map(|k| k.public_key().as_ref().to_vec())
+                .collect(),
+        );
+        assert!(policy.is_valid());
+        assert!(!MultisigPolicy::new(4, policy.public_keys.clone()).is_valid());
+        assert!(!MultisigPolicy::new(0, policy.public_keys.clone()).is_valid());
+
+        let digest: H256 = [1u8; 32].into();
+        let mut witness = Witness::default();
+        assert!(witness.sign(&digest, &keys[0]));
+        // signing twice with the same key does not count twice
+        assert!(!witness.sign(&digest, &keys[0]));
+        assert_eq!(policy.count_valid(&digest, &witness), 1);
+        // a signature from a key outside the policy is ignored
+        witness.sign(&digest, &key_pair::random());
+        assert_eq!(policy.count_valid(&digest, &witness), 1);
+        witness.sign(&digest, &keys[2]);
+        assert_eq!(policy.count_valid(&digest, &witness), 2);
+        assert_eq!(policy.count_valid(&[2u8; 32].into(), &witness), 0);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,165 @@
+use super::hash::{Hashable, H256};
+use super::transaction::{SignedTransaction, UTXO_input};
+use rand::seq::IteratorRandom;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Largest number of orphans kept
+pub const MAX_ORPHANS: usize = 100;
+/// Larger transactions are not kept as orphans, so the pool stays small
+pub const MAX_ORPHAN_SIZE: u64 = 100_000;
+/// How long an orphan waits for its parents before it is dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// Transactions spending outputs of transactions that have not arrived yet. They wait here,
+/// indexed by the outpoints they are missing, until a parent is added to the mempool or confirmed
+/// and they can be validated again, for at most `ORPHAN_EXPIRY`. When the pool is full a random
+/// orphan makes room for the new one, so a peer cannot keep a given transaction out by flooding
+/// it.
+#[derive(Debug, Default, Clone)]
+pub struct OrphanPool {
+    pub orphans: HashMap<H256, SignedTransaction>,
+    /// orphans waiting for each missing outpoint
+    pub waiting: HashMap<UTXO_input, HashSet<H256>>,
+    /// when each orphan arrived
+    pub arrivals: HashMap<H256, Instant>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            waiting: HashMap::new(),
+            arrivals: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    /// Keep `tx` until one of its `missing` inputs shows up. Returns false if it was already
+    /// there or is too large.
+    pub fn insert(&mut self, tx: SignedTransaction, missing: &[UTXO_input]) -> bool {
+        let hash = tx.hash();
+        if self.orphans.contains_key(&hash) || tx.size() > MAX_ORPHAN_SIZE {
+            return false;
+        }
+        let now = Instant::now();
+        self.expire(now);
+        if self.orphans.len() >= MAX_ORPHANS {
+            let evicted = *self.orphans.keys().choose(&mut rand::thread_rng()).unwrap();
+            self.remove(&evicted);
+        }
+        for input in missing {
+            self.waiting
+                .entry(input.clone())
+                .or_default()
+                .insert(hash);
+        }
+        self.orphans.insert(hash, tx);
+        self.arrivals.insert(hash, now);
+        true
+    }
+
+    /// Drop the orphans that arrived `ORPHAN_EXPIRY` or longer before `now`
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<H256> = self
+            .arrivals
+            .iter()
+            .filter(|(_, arrival)| now.saturating_duration_since(**arrival) >= ORPHAN_EXPIRY)
+            .map(|(h, _)| *h)
+            .collect();
+        for h in expired.iter() {
+            self.remove(h);
+        }
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        let tx = self.orphans.remove(hash)?;
+        self.arrivals.remove(hash);
+        for input in tx.transcation.input.iter() {
+            if let Some(waiting) = self.waiting.get_mut(input) {
+                waiting.remove(hash);
+                if waiting.is_empty() {
+                    self.waiting.remove(input);
+                }
+            }
+        }
+        Some(tx)
+    }
+
+    /// Take out the orphans spending an output of one of `parents`, to be validated again
+    pub fn take_children(&mut self, parents: &[H256]) -> Vec<SignedTransaction> {
+        let children: HashSet<H256> = self
+            .waiting
+            .iter()
+            .filter(|(input, _)| parents.contains(&input.prev_tx_hash))
+            .flat_map(|(_, hashes)| hashes.iter().cloned())
+            .collect();
+        children.iter().filter_map(|h| self.remove(h)).collect()
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::Transaction;
+
+    fn spending(inputs: Vec<UTXO_input>) -> SignedTransaction {
+        SignedTransaction {
+            witnesses: vec![Default::default(); inputs.len()],
+            transcation: Transaction {
+                input: inputs,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn wait_for_parents() {
+        let parent: H256 = [1u8; 32].into();
+        let outpoint = |prev_tx_hash, index| UTXO_input {
+            prev_tx_hash,
+            index,
+        };
+        let mut pool = OrphanPool::new();
+        let orphan = spending(vec![outpoint(parent, 0), outpoint(parent, 1)]);
+        assert!(pool.insert(orphan.clone(), &orphan.transcation.input));
+        assert!(!pool.insert(orphan.clone(), &orphan.transcation.input));
+        assert!(pool.take_children(&[[2u8; 32].into()]).is_empty());
+        assert_eq!(pool.take_children(&[parent]).len(), 1);
+        assert!(pool.is_empty());
+        assert!(pool.waiting.is_empty());
+
+        // full pools make room
+        for i in 0..MAX_ORPHANS + 10 {
+            let tx = spending(vec![outpoint([i as u8; 32].into(), 0)]);
+            let missing = tx.transcation.input.clone();
+            assert!(pool.insert(tx, &missing));
+        }
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert_eq!(
+            pool.waiting.values().map(|w| w.len()).sum::<usize>(),
+            MAX_ORPHANS
+        );
+
+        // orphans whose parents never come are dropped in the end
+        pool.expire(Instant::now() + ORPHAN_EXPIRY);
+        assert!(pool.is_empty());
+        assert!(pool.waiting.is_empty());
+        assert!(pool.arrivals.is_empty());
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
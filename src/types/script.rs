@@ -0,0 +1,757 @@
+use super::address::Address;
+use super::hash::H256;
+use super::multisig::{MultisigPolicy, MAX_MULTISIG_KEYS};
+use super::timelock::{LockTime, RelativeLock};
+use super::transaction::Witness;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+// A small stack language for locking outputs, modelled on Bitcoin script. It has no loops or
+// jumps, so a script runs at most once through its opcodes, and execution is bounded by the
+// limits below. It only depends on the script, the witness of the spending input and the lock
+// time and sequence of the spending transaction, so every node gets the same result. Whether those
+// locks have passed is checked by `State::validate`, not by the script.
+//
+// Signatures are not pushed on the stack: the witness of an input carries a set of signatures of
+// the input's `signature_hash`, and the signature opcodes look up the one made by a given public
+// key or address. This keeps partial signing independent of the order of the signers.
+//
+// Outputs paid to an address, and multisig outputs, use the standard templates below; `classify`
+// recognizes them so validation can skip the interpreter.
+
+/// Largest script, in bytes
+pub const MAX_SCRIPT_SIZE: usize = 1000;
+/// Largest element pushed on the stack, in bytes
+pub const MAX_ELEMENT_SIZE: usize = 520;
+/// Largest number of elements on the stack, including the initial ones from the witness
+pub const MAX_STACK_SIZE: usize = 100;
+/// Largest number of non-push opcodes executed, each key of a multisig check counts as one
+pub const MAX_OPS: usize = 200;
+/// Numbers are at most 8 bytes long, enough for block heights and millisecond timestamps
+const MAX_NUM_SIZE: usize = 8;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_NOP: u8 = 0x61;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_SHA256: u8 = 0xa8;
+/// Pops a public key, pushes the address it controls
+pub const OP_ADDRESS: u8 = 0xa9;
+/// Pops a public key, pushes whether the witness has a valid signature by it
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+/// Pops n, n public keys and m, pushes whether at least m of them have a valid signature
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+/// Pops an address, pushes whether the witness has a valid signature by a key of that address
+pub const OP_CHECKADDRESSSIG: u8 = 0xb0;
+/// Fails unless the lock time of the spending transaction is at least the one on top of the
+/// stack, of the same kind (height or time). The number is left in place.
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+/// Fails unless the sequence of the spending input is a relative lock at least as long as the one
+/// on top of the stack, of the same kind. The number is left in place, a number with
+/// `SEQUENCE_DISABLE_FLAG` set makes it a no-op.
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+/// A locking script, as raw bytecode
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct Script(pub Vec<u8>);
+
+/// The standard scripts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptType {
+    /// `<address> OP_CHECKADDRESSSIG`
+    PayToAddress(Address),
+    /// `<m> <key 1> .. <key n> <n> OP_CHECKMULTISIG`
+    Multisig(MultisigPolicy),
+    NonStandard,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptError {
+    ScriptSize(usize),
+    PushSize(usize),
+    StackSize,
+    OpCount,
+    TruncatedPush,
+    BadOpcode(u8),
+    UnbalancedConditional,
+    StackUnderflow,
+    InvalidNumber,
+    InvalidMultisig,
+    VerifyFailed,
+    LockTime { required: u64, lock_time: u64 },
+    Sequence { required: u32, sequence: u32 },
+    OpReturn,
+    EvalFalse,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ScriptError::ScriptSize(n) => {
+                write!(f, "script is {} bytes, limit is {}", n, MAX_SCRIPT_SIZE)
+            }
+            ScriptError::PushSize(n) => {
+                write!(f, "element is {} bytes, limit is {}", n, MAX_ELEMENT_SIZE)
+            }
+            ScriptError::StackSize => write!(f, "more than {} stack elements", MAX_STACK_SIZE),
+            ScriptError::OpCount => write!(f, "more than {} opcodes", MAX_OPS),
+            ScriptError::TruncatedPush => write!(f, "push past the end of the script"),
+            ScriptError::BadOpcode(op) => write!(f, "unknown opcode 0x{:02x}", op),
+            ScriptError::UnbalancedConditional => write!(f, "unbalanced conditional"),
+            ScriptError::StackUnderflow => write!(f, "not enough stack elements"),
+            ScriptError::InvalidNumber => write!(f, "invalid number"),
+            ScriptError::InvalidMultisig => write!(f, "invalid multisig key or signature count"),
+            ScriptError::VerifyFailed => write!(f, "verify failed"),
+            ScriptError::LockTime {
+                required,
+                lock_time,
+            } => write!(
+                f,
+                "lock time {} required, transaction has {}",
+                LockTime::new(*required),
+                LockTime::new(*lock_time)
+            ),
+            ScriptError::Sequence { required, sequence } => write!(
+                f,
+                "relative lock {:#x} required, input has {:#x}",
+                required, sequence
+            ),
+            ScriptError::OpReturn => write!(f, "OP_RETURN executed"),
+            ScriptError::EvalFalse => write!(f, "script finished with false on the stack"),
+        }
+    }
+}
+
+/// Everything a script can look at besides its own stack
+pub struct ExecutionContext<'a> {
+    /// `signature_hash` of the input being unlocked
+    pub digest: H256,
+    pub witness: &'a Witness,
+    /// `Transaction::lock_time` of the spending transaction
+    pub lock_time: u64,
+    /// sequence of the input being unlocked
+    pub sequence: u32,
+}
+
+impl<'a> ExecutionContext<'a> {
+    fn signed_by(&self, public_key: &[u8]) -> bool {
+        self.witness
+            .signatures
+            .iter()
+            .any(|s| s.public_key == public_key && s.verify(&self.digest))
+    }
+
+    fn signed_by_address(&self, address: &Address) -> bool {
+        self.witness.signatures.iter().any(|s| {
+            Address::from_public_key_bytes(&s.public_key) == *address && s.verify(&self.digest)
+        })
+    }
+}
+
+/// Minimal little-endian encoding with the sign in the top bit of the last byte, as in Bitcoin
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut res = Vec::new();
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        res.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = res.last().copied() {
+        if last & 0x80 != 0 {
+            res.push(if negative { 0x80 } else { 0 });
+        } else if negative {
+            *res.last_mut().unwrap() |= 0x80;
+        }
+    }
+    res
+}
+
+fn decode_num(bytes: &[u8]) -> Result<i64, ScriptError> {
+    if bytes.len() > MAX_NUM_SIZE {
+        return Err(ScriptError::InvalidNumber);
+    }
+    // reject non-minimal encodings, so every number has a single representation
+    if let Some(last) = bytes.last() {
+        if last & 0x7f == 0 && (bytes.len() == 1 || bytes[bytes.len() - 2] & 0x80 == 0) {
+            return Err(ScriptError::InvalidNumber);
+        }
+    }
+    let mut res: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        let byte = if i == bytes.len() - 1 { byte & 0x7f } else { *byte };
+        res |= u64::from(byte) << (8 * i);
+    }
+    let res = i64::try_from(res).map_err(|_| ScriptError::InvalidNumber)?;
+    match bytes.last() {
+        Some(last) if last & 0x80 != 0 => Ok(-res),
+        _ => Ok(res),
+    }
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte != 0 {
+            // negative zero is false
+            return !(i == bytes.len() - 1 && *byte == 0x80);
+        }
+    }
+    false
+}
+
+fn from_bool(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+/// One decoded instruction: an opcode, and the data it pushes if it is a push
+enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+/// Iterate over the instructions of a script, failing on a push running past the end
+struct Instructions<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Instructions<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ScriptError> {
+        if self.pos + n > self.bytes.len() {
+            self.pos = self.bytes.len();
+            return Err(ScriptError::TruncatedPush);
+        }
+        let res = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(res)
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        let len = match op {
+            0x01..=0x4b => op as usize,
+            OP_PUSHDATA1 => match self.take(1) {
+                Ok(b) => b[0] as usize,
+                Err(e) => return Some(Err(e)),
+            },
+            OP_PUSHDATA2 => match self.take(2) {
+                Ok(b) => u16::from_le_bytes([b[0], b[1]]) as usize,
+                Err(e) => return Some(Err(e)),
+            },
+            _ => return Some(Ok(Instruction::Op(op))),
+        };
+        Some(self.take(len).map(Instruction::Push))
+    }
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script(Vec::new())
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Self {
+        self.0.push(op);
+        self
+    }
+
+    /// Push data with the shortest push opcode
+    pub fn push_data(mut self, data: &[u8]) -> Self {
+        match data.len() {
+            0 => self.0.push(OP_0),
+            n @ 1..=0x4b => self.0.push(n as u8),
+            n @ 0x4c..=0xff => self.0.extend_from_slice(&[OP_PUSHDATA1, n as u8]),
+            n => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend_from_slice(&(n as u16).to_le_bytes());
+            }
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    /// Push a number, with the small number opcodes when possible
+    pub fn push_int(self, n: i64) -> Self {
+        match n {
+            0 => self.push_opcode(OP_0),
+            -1 => self.push_opcode(OP_1NEGATE),
+            1..=16 => self.push_opcode(OP_1 + (n as u8) - 1),
+            _ => self.push_data(&encode_num(n)),
+        }
+    }
+
+    /// Standard script of an output paid to an address
+    pub fn pay_to_address(address: &Address) -> Self {
+        Script::new()
+            .push_data(address.as_ref())
+            .push_opcode(OP_CHECKADDRESSSIG)
+    }
+
+    /// Standard script of a multisig output
+    pub fn multisig(policy: &MultisigPolicy) -> Self {
+        let mut script = Script::new().push_int(i64::from(policy.threshold));
+        for key in policy.public_keys.iter() {
+            script = script.push_data(key);
+        }
+        script
+            .push_int(policy.public_keys.len() as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+    }
+
+    /// The address an output locked by this script is paid to, the hash of the script
+    pub fn address(&self) -> Address {
+        Address::from_public_key_bytes(&self.0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn instructions(&self) -> Instructions<'_> {
+        Instructions {
+            bytes: &self.0,
+            pos: 0,
+        }
+    }
+
+    /// Whether the script pushes `data` somewhere, e.g. a public key or an address
+    pub fn pushes(&self, data: &[u8]) -> bool {
+        self.instructions()
+            .any(|i| matches!(i, Ok(Instruction::Push(d)) if d == data))
+    }
+
+    /// Recognize the standard templates
+    pub fn classify(&self) -> ScriptType {
+        let instructions: Result<Vec<Instruction>, ScriptError> = self.instructions().collect();
+        let instructions = match instructions {
+            Ok(v) => v,
+            Err(_) => return ScriptType::NonStandard,
+        };
+        match instructions.as_slice() {
+            [Instruction::Push(address), Instruction::Op(OP_CHECKADDRESSSIG)]
+                if address.len() == 20 =>
+            {
+                let mut buffer = [0u8; 20];
+                buffer.copy_from_slice(address);
+                return ScriptType::PayToAddress(buffer.into());
+            }
+            [Instruction::Op(m), keys @ .., Instruction::Op(n), Instruction::Op(OP_CHECKMULTISIG)]
+                if (OP_1..=OP_16).contains(m) && (OP_1..=OP_16).contains(n) =>
+            {
+                let keys: Option<Vec<Vec<u8>>> = keys
+                    .iter()
+                    .map(|k| match k {
+                        Instruction::Push(k) => Some(k.to_vec()),
+                        Instruction::Op(_) => None,
+                    })
+                    .collect();
+                if let Some(keys) = keys {
+                    let policy = MultisigPolicy::new(m - OP_1 + 1, keys);
+                    if usize::from(n - OP_1 + 1) == policy.public_keys.len() && policy.is_valid() {
+                        return ScriptType::Multisig(policy);
+                    }
+                }
+            }
+            _ => {}
+        }
+        ScriptType::NonStandard
+    }
+
+    /// Run the script on top of the witness stack. It succeeds if it runs to the end without
+    /// error and leaves a true value on top of the stack.
+    pub fn execute(&self, ctx: &ExecutionContext) -> Result<(), ScriptError> {
+        if self.len() > MAX_SCRIPT_SIZE {
+            return Err(ScriptError::ScriptSize(self.len()));
+        }
+        if ctx.witness.stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+        if let Some(e) = ctx.witness.stack.iter().find(|e| e.len() > MAX_ELEMENT_SIZE) {
+            return Err(ScriptError::PushSize(e.len()));
+        }
+        let mut stack: Vec<Vec<u8>> = ctx.witness.stack.clone();
+        // one entry per open IF, whether its branch is being executed
+        let mut conditions: Vec<bool> = Vec::new();
+        let mut op_count = 0;
+
+        for instruction in self.instructions() {
+            let executing = conditions.iter().all(|c| *c);
+            let op = match instruction? {
+                Instruction::Push(data) => {
+                    if data.len() > MAX_ELEMENT_SIZE {
+                        return Err(ScriptError::PushSize(data.len()));
+                    }
+                    if executing {
+                        stack.push(data.to_vec());
+                    }
+                    if stack.len() > MAX_STACK_SIZE {
+                        return Err(ScriptError::StackSize);
+                    }
+                    continue;
+                }
+                Instruction::Op(op) => op,
+            };
+            if op > OP_16 {
+                op_count += 1;
+                if op_count > MAX_OPS {
+                    return Err(ScriptError::OpCount);
+                }
+            }
+            match op {
+                OP_IF | OP_NOTIF => {
+                    let mut value = false;
+                    if executing {
+                        value = cast_to_bool(&pop(&mut stack)?) == (op == OP_IF);
+                    }
+                    conditions.push(value);
+                    continue;
+                }
+                OP_ELSE => {
+                    let last = conditions
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *last = !*last;
+                    continue;
+                }
+                OP_ENDIF => {
+                    conditions
+                        .pop()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    continue;
+                }
+                _ if !executing => continue,
+                _ => {}
+            }
+            match op {
+                OP_0 => stack.push(Vec::new()),
+                OP_1NEGATE => stack.push(encode_num(-1)),
+                OP_1..=OP_16 => stack.push(encode_num(i64::from(op - OP_1 + 1))),
+                OP_NOP => {}
+                OP_VERIFY => {
+                    if !cast_to_bool(&pop(&mut stack)?) {
+                        return Err(ScriptError::VerifyFailed);
+                    }
+                }
+                OP_RETURN => return Err(ScriptError::OpReturn),
+                OP_DROP => {
+                    pop(&mut stack)?;
+                }
+                OP_DUP => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+                    stack.push(top);
+                }
+                OP_SWAP => {
+                    let len = stack.len();
+                    if len < 2 {
+                        return Err(ScriptError::StackUnderflow);
+                    }
+                    stack.swap(len - 1, len - 2);
+                }
+                OP_SIZE => {
+                    let size = stack.last().ok_or(ScriptError::StackUnderflow)?.len();
+                    stack.push(encode_num(size as i64));
+                }
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    let a = pop(&mut stack)?;
+                    let b = pop(&mut stack)?;
+                    verify_or_push(&mut stack, a == b, op == OP_EQUALVERIFY)?;
+                }
+                OP_SHA256 => {
+                    let data = pop(&mut stack)?;
+                    let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+                    stack.push(digest.as_ref().to_vec());
+                }
+                OP_ADDRESS => {
+                    let key = pop(&mut stack)?;
+                    stack.push(Address::from_public_key_bytes(&key).as_ref().to_vec());
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    let key = pop(&mut stack)?;
+                    verify_or_push(&mut stack, ctx.signed_by(&key), op == OP_CHECKSIGVERIFY)?;
+                }
+                OP_CHECKADDRESSSIG => {
+                    let address = pop(&mut stack)?;
+                    let signed = address.len() == 20 && {
+                        let mut buffer = [0u8; 20];
+                        buffer.copy_from_slice(&address);
+                        ctx.signed_by_address(&buffer.into())
+                    };
+                    stack.push(from_bool(signed));
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    let n = pop_count(&mut stack, MAX_MULTISIG_KEYS)?;
+                    op_count += n;
+                    if op_count > MAX_OPS {
+                        return Err(ScriptError::OpCount);
+                    }
+                    let mut keys = Vec::with_capacity(n);
+                    for _ in 0..n {
+                        keys.push(pop(&mut stack)?);
+                    }
+                    let m = pop_count(&mut stack, n)?;
+                    let policy = MultisigPolicy::new(m as u8, keys);
+                    let signed = policy.count_valid(&ctx.digest, ctx.witness) >= m;
+                    verify_or_push(&mut stack, signed, op == OP_CHECKMULTISIGVERIFY)?;
+                }
+                OP_CHECKLOCKTIMEVERIFY => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let required = decode_num(top)?;
+                    let required = u64::try_from(required).map_err(|_| ScriptError::InvalidNumber)?;
+                    let satisfied = match (LockTime::new(required), LockTime::new(ctx.lock_time)) {
+                        (LockTime::None, _) => true,
+                        (LockTime::Height(r), LockTime::Height(l)) => r <= l,
+                        (LockTime::Time(r), LockTime::Time(l)) => r <= l,
+                        _ => false,
+                    };
+                    if !satisfied {
+                        return Err(ScriptError::LockTime {
+                            required,
+                            lock_time: ctx.lock_time,
+                        });
+                    }
+                }
+                OP_CHECKSEQUENCEVERIFY => {
+                    let top = stack.last().ok_or(ScriptError::StackUnderflow)?;
+                    let required = decode_num(top)?;
+                    let required = u32::try_from(required).map_err(|_| ScriptError::InvalidNumber)?;
+                    if let Some(required_lock) = RelativeLock::from_sequence(required) {
+                        let lock = RelativeLock::from_sequence(ctx.sequence);
+                        let satisfied = match (required_lock, lock) {
+                            (RelativeLock::Blocks(r), Some(RelativeLock::Blocks(s))) => r <= s,
+                            (RelativeLock::Time(r), Some(RelativeLock::Time(s))) => r <= s,
+                            _ => false,
+                        };
+                        if !satisfied {
+                            return Err(ScriptError::Sequence {
+                                required,
+                                sequence: ctx.sequence,
+                            });
+                        }
+                    }
+                }
+                _ => return Err(ScriptError::BadOpcode(op)),
+            }
+            if stack.len() > MAX_STACK_SIZE {
+                return Err(ScriptError::StackSize);
+            }
+        }
+        if !conditions.is_empty() {
+            return Err(ScriptError::UnbalancedConditional);
+        }
+        match stack.last() {
+            Some(top) if cast_to_bool(top) => Ok(()),
+            _ => Err(ScriptError::EvalFalse),
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+/// Pop a number between 0 and `max`
+fn pop_count(stack: &mut Vec<Vec<u8>>, max: usize) -> Result<usize, ScriptError> {
+    let n = decode_num(&pop(stack)?)?;
+    if n < 0 || n as usize > max {
+        return Err(ScriptError::InvalidMultisig);
+    }
+    Ok(n as usize)
+}
+
+fn verify_or_push(stack: &mut Vec<Vec<u8>>, value: bool, verify: bool) -> Result<(), ScriptError> {
+    if verify {
+        if !value {
+            return Err(ScriptError::VerifyFailed);
+        }
+    } else {
+        stack.push(from_bool(value));
+    }
+    Ok(())
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::timelock::{LOCKTIME_THRESHOLD, SEQUENCE_FINAL};
+    use ring::signature::KeyPair;
+
+    fn run(script: &Script, witness: &Witness, lock_time: u64) -> Result<(), ScriptError> {
+        script.execute(&ExecutionContext {
+            digest: [3u8; 32].into(),
+            witness,
+            lock_time,
+            sequence: SEQUENCE_FINAL,
+        })
+    }
+
+    #[test]
+    fn numbers() {
+        for n in [0, 1, -1, 127, 128, -128, 255, 256, 1 << 40, -(1 << 40), i64::MAX].iter() {
+            assert_eq!(decode_num(&encode_num(*n)), Ok(*n));
+        }
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert_eq!(decode_num(&[0x01, 0x00]), Err(ScriptError::InvalidNumber));
+    }
+
+    #[test]
+    fn standard_templates() {
+        let keys: Vec<_> = (0..3).map(|_| key_pair::random()).collect();
+        let address = Address::from_public_key_bytes(keys[0].public_key().as_ref());
+        let p2a = Script::pay_to_address(&address);
+        assert_eq!(p2a.classify(), ScriptType::PayToAddress(address));
+        let policy = MultisigPolicy::new(
+            2,
+            keys.iter()
+                .map(|k| k.public_key().as_ref().to_vec())
+                .collect(),
+        );
+        let multisig = Script::multisig(&policy);
+        assert_eq!(multisig.classify(), ScriptType::Multisig(policy));
+
+        // the interpreter agrees with the checks validation does for the standard templates
+        let mut witness = Witness::default();
+        assert_eq!(run(&p2a, &witness, 0), Err(ScriptError::EvalFalse));
+        witness.sign(&[3u8; 32].into(), &keys[0]);
+        assert_eq!(run(&p2a, &witness, 0), Ok(()));
+        assert_eq!(run(&multisig, &witness, 0), Err(ScriptError::EvalFalse));
+        witness.sign(&[3u8; 32].into(), &keys[2]);
+        assert_eq!(run(&multisig, &witness, 0), Ok(()));
+        witness.signatures[1].signature[0] ^= 1;
+        assert_eq!(run(&multisig, &witness, 0), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn hash_and_time_locks() {
+        let key = key_pair::random();
+        let secret = b"open sesame";
+        let hash = ring::digest::digest(&ring::digest::SHA256, secret);
+        // pay to the key once the spending transaction is locked past height 100, or to whoever
+        // knows the secret
+        let script = Script::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SHA256)
+            .push_data(hash.as_ref())
+            .push_opcode(OP_EQUAL)
+            .push_opcode(OP_ELSE)
+            .push_int(100)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_data(key.public_key().as_ref())
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_ENDIF);
+        assert_eq!(script.classify(), ScriptType::NonStandard);
+
+        let mut witness = Witness {
+            stack: vec![secret.to_vec(), vec![1]],
+            ..Default::default()
+        };
+        assert_eq!(run(&script, &witness, 0), Ok(()));
+        witness.stack = vec![b"guess".to_vec(), vec![1]];
+        assert_eq!(run(&script, &witness, 0), Err(ScriptError::EvalFalse));
+
+        witness.stack = vec![vec![]];
+        witness.sign(&[3u8; 32].into(), &key);
+        assert_eq!(
+            run(&script, &witness, 99),
+            Err(ScriptError::LockTime {
+                required: 100,
+                lock_time: 99
+            })
+        );
+        assert_eq!(run(&script, &witness, 100), Ok(()));
+        // a time lock does not satisfy a height lock
+        assert!(run(&script, &witness, LOCKTIME_THRESHOLD).is_err());
+
+        // pay to the key 10 blocks after the output is created
+        let sequence = RelativeLock::Blocks(10).to_sequence();
+        let script = Script::new()
+            .push_int(i64::from(sequence))
+            .push_opcode(OP_CHECKSEQUENCEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_data(key.public_key().as_ref())
+            .push_opcode(OP_CHECKSIG);
+        witness.stack.clear();
+        let run_with_sequence = |sequence| {
+            script.execute(&ExecutionContext {
+                digest: [3u8; 32].into(),
+                witness: &witness,
+                lock_time: 0,
+                sequence,
+            })
+        };
+        assert_eq!(
+            run_with_sequence(SEQUENCE_FINAL),
+            Err(ScriptError::Sequence {
+                required: sequence,
+                sequence: SEQUENCE_FINAL
+            })
+        );
+        assert!(run_with_sequence(RelativeLock::Blocks(9).to_sequence()).is_err());
+        assert!(run_with_sequence(RelativeLock::Time(10).to_sequence()).is_err());
+        assert_eq!(run_with_sequence(sequence), Ok(()));
+    }
+
+    #[test]
+    fn resource_limits() {
+        let witness = Witness::default();
+        let too_long = Script(vec![OP_NOP; MAX_SCRIPT_SIZE + 1]);
+        assert_eq!(
+            run(&too_long, &witness, 0),
+            Err(ScriptError::ScriptSize(MAX_SCRIPT_SIZE + 1))
+        );
+        let many_ops = Script::new().push_int(1);
+        let many_ops = Script([many_ops.0, vec![OP_NOP; MAX_OPS + 1]].concat());
+        assert_eq!(run(&many_ops, &witness, 0), Err(ScriptError::OpCount));
+        let deep = Script(vec![OP_1; MAX_STACK_SIZE + 1]);
+        assert_eq!(run(&deep, &witness, 0), Err(ScriptError::StackSize));
+        let big_push = Script::new().push_data(&[1u8; MAX_ELEMENT_SIZE + 1]);
+        assert_eq!(
+            run(&big_push, &witness, 0),
+            Err(ScriptError::PushSize(MAX_ELEMENT_SIZE + 1))
+        );
+        assert_eq!(
+            run(&Script(vec![0x05, 0x01]), &witness, 0),
+            Err(ScriptError::TruncatedPush)
+        );
+        assert_eq!(
+            run(&Script(vec![OP_1, OP_IF]), &witness, 0),
+            Err(ScriptError::UnbalancedConditional)
+        );
+        assert_eq!(run(&Script(vec![0xff]), &witness, 0), Err(ScriptError::BadOpcode(0xff)));
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -0,0 +1,173 @@
+use serde::{Deserialize, Serialize};
+
+// Transactions can be locked in time in two ways, following BIP 65, 68, 112 and 113:
+//
+// - `Transaction::lock_time` is absolute: the transaction cannot be included before a block
+//   height or, from `LOCKTIME_THRESHOLD` on, a time in milliseconds since the epoch.
+// - each entry of `Transaction::sequences` is relative to the block that created the output the
+//   input spends: a number of blocks, or a time in units of `SEQUENCE_GRANULARITY`.
+//
+// Times are compared with the median-time-past of the chain, the median timestamp of its last
+// `MEDIAN_TIME_SPAN` blocks, rather than with the timestamp of a single block, which its miner
+// picks freely.
+
+/// Lock times below this are block heights, others are timestamps in milliseconds
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+/// Number of blocks the median-time-past is taken over
+pub const MEDIAN_TIME_SPAN: usize = 11;
+/// A sequence with this bit set has no relative lock
+pub const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// A sequence with this bit set is a time, otherwise a number of blocks
+pub const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+/// Bits of a sequence holding the value of the lock
+pub const SEQUENCE_MASK: u32 = 0xffff;
+/// Milliseconds per unit of a relative time lock
+pub const SEQUENCE_GRANULARITY: u128 = 512_000;
+/// Sequence of an input without a relative lock
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+/// Inputs with a sequence up to this opt in to replace-by-fee, it has `SEQUENCE_DISABLE_FLAG` set
+pub const MAX_REPLACEABLE_SEQUENCE: u32 = 0xffff_fffd;
+
+/// Meaning of `Transaction::lock_time`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockTime {
+    None,
+    /// the transaction can go in blocks above this height
+    Height(u64),
+    /// the transaction can go in blocks on top of a chain whose median-time-past is above this
+    Time(u128),
+}
+
+impl LockTime {
+    pub fn new(lock_time: u64) -> Self {
+        if lock_time == 0 {
+            LockTime::None
+        } else if lock_time < LOCKTIME_THRESHOLD {
+            LockTime::Height(lock_time)
+        } else {
+            LockTime::Time(u128::from(lock_time))
+        }
+    }
+
+    /// Whether a transaction with this lock can go in the block at `height`, on top of a chain
+    /// with the given median-time-past
+    pub fn is_satisfied(&self, height: u64, median_time_past: u128) -> bool {
+        match *self {
+            LockTime::None => true,
+            LockTime::Height(h) => h < height,
+            LockTime::Time(t) => t < median_time_past,
+        }
+    }
+}
+
+impl std::fmt::Display for LockTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LockTime::None => write!(f, "none"),
+            LockTime::Height(h) => write!(f, "height {}", h),
+            LockTime::Time(t) => write!(f, "time {}", t),
+        }
+    }
+}
+
+/// Meaning of an entry of `Transaction::sequences`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    /// blocks on top of the one that created the spent output
+    Blocks(u16),
+    /// units of `SEQUENCE_GRANULARITY` after the median-time-past the spent output was created at
+    Time(u16),
+}
+
+impl RelativeLock {
+    /// The lock encoded by a sequence, if any
+    pub fn from_sequence(sequence: u32) -> Option<Self> {
+        if sequence & SEQUENCE_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = (sequence & SEQUENCE_MASK) as u16;
+        if sequence & SEQUENCE_TYPE_FLAG != 0 {
+            Some(RelativeLock::Time(value))
+        } else {
+            Some(RelativeLock::Blocks(value))
+        }
+    }
+
+    pub fn to_sequence(&self) -> u32 {
+        match *self {
+            RelativeLock::Blocks(n) => u32::from(n),
+            RelativeLock::Time(n) => SEQUENCE_TYPE_FLAG | u32::from(n),
+        }
+    }
+
+    /// Whether an output created at `created` can be spent in the block at `height`, on top of a
+    /// chain with the given median-time-past
+    pub fn is_satisfied(
+        &self,
+        created: &Confirmation,
+        height: u64,
+        median_time_past: u128,
+    ) -> bool {
+        match *self {
+            RelativeLock::Blocks(n) => height >= created.height + u64::from(n),
+            RelativeLock::Time(n) => {
+                median_time_past >= created.median_time_past + u128::from(n) * SEQUENCE_GRANULARITY
+            }
+        }
+    }
+}
+
+/// Where an output was created: the height of its block and the median-time-past of the chain
+/// that block was mined on
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Confirmation {
+    pub height: u64,
+    pub median_time_past: u128,
+}
+
+/// Median of the timestamps of the last `MEDIAN_TIME_SPAN` blocks, `timestamps` holds at most
+/// that many, in any order
+pub fn median_time_past(timestamps: &[u128]) -> u128 {
+    let mut sorted = timestamps.to_vec();
+    sorted.sort_unstable();
+    sorted.get(sorted.len() / 2).copied().unwrap_or(0)
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks() {
+        assert_eq!(LockTime::new(0), LockTime::None);
+        assert_eq!(LockTime::new(100), LockTime::Height(100));
+        assert_eq!(
+            LockTime::new(LOCKTIME_THRESHOLD),
+            LockTime::Time(u128::from(LOCKTIME_THRESHOLD))
+        );
+        assert!(!LockTime::Height(100).is_satisfied(100, u128::MAX));
+        assert!(LockTime::Height(100).is_satisfied(101, 0));
+        assert!(!LockTime::Time(1000).is_satisfied(u64::MAX, 1000));
+        assert!(LockTime::Time(1000).is_satisfied(0, 1001));
+
+        assert_eq!(RelativeLock::from_sequence(SEQUENCE_FINAL), None);
+        for lock in [RelativeLock::Blocks(10), RelativeLock::Time(2)].iter() {
+            assert_eq!(RelativeLock::from_sequence(lock.to_sequence()), Some(*lock));
+        }
+        let created = Confirmation {
+            height: 5,
+            median_time_past: 1000,
+        };
+        assert!(!RelativeLock::Blocks(10).is_satisfied(&created, 14, 0));
+        assert!(RelativeLock::Blocks(10).is_satisfied(&created, 15, 0));
+        assert!(!RelativeLock::Time(2).is_satisfied(&created, 100, 1000 + 1_023_999));
+        assert!(RelativeLock::Time(2).is_satisfied(&created, 100, 1000 + 1_024_000));
+
+        assert_eq!(median_time_past(&[]), 0);
+        assert_eq!(median_time_past(&[5, 1, 3]), 3);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
@@ -1,16 +1,20 @@
 extern crate ring;
 
 use super::address::Address;
+use super::fee_estimator::{fee_rate, FeeEstimator};
+use super::script::{Script, ScriptType};
+use super::orphan::OrphanPool;
+use super::state::{State, TransactionError};
+use super::timelock::{MAX_REPLACEABLE_SEQUENCE, SEQUENCE_FINAL};
 use crate::types::hash::{Hashable, H256};
-use rand::Rng;
+use rand::seq::IteratorRandom;
 use ring::digest::{self, Context, Digest, SHA256};
 use ring::signature::{
     self, Ed25519KeyPair, EdDSAParameters, KeyPair, Signature, VerificationAlgorithm,
 };
 use serde::{Deserialize, Serialize};
-use std::cmp;
-use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
@@ -19,14 +23,60 @@ pub struct Transaction {
     pub value: u32,
     pub input: Vec<UTXO_input>,
     pub output: Vec<UTXO_output>,
+    /// height or time the transaction is locked until, 0 for none, see `timelock::LockTime`
+    #[serde(default)]
+    pub lock_time: u64,
+    /// relative lock of each input, in the same order, see `timelock::RelativeLock`. Empty when
+    /// no input has one.
+    #[serde(default)]
+    pub sequences: Vec<u32>,
 }
 
+impl Transaction {
+    /// Sequence of input `index`
+    pub fn sequence(&self, index: usize) -> u32 {
+        self.sequences.get(index).copied().unwrap_or(SEQUENCE_FINAL)
+    }
+
+    /// Whether the transaction opts in to being replaced in the mempool by one paying more fees
+    pub fn signals_replacement(&self) -> bool {
+        self.sequences.iter().any(|s| *s <= MAX_REPLACEABLE_SEQUENCE)
+    }
+}
+
+impl SignedTransaction {
+    /// Serialized size in bytes, fee rates are per byte of it
+    pub fn size(&self) -> u64 {
+        bincode::serialized_size(self).unwrap()
+    }
+}
+
+// The Midterm1 handout had a single public key and signature over the whole transaction, which
+// meant every input had to belong to the same address. Each input is now unlocked by its own
+// witness, signing the `signature_hash` of that input.
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
-    // follow the definition in Midterm1 handout
+    pub transcation: Transaction,
+    /// one witness per input, in the same order
+    pub witnesses: Vec<Witness>,
+}
+
+/// One signature of an input's `signature_hash`, with the key that made it
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct InputSignature {
     pub public_key: Vec<u8>,
     pub signature: Vec<u8>,
-    pub transcation: Transaction,
+}
+
+/// Data unlocking one input: a single signature for an output paid to the address of a key, up
+/// to one per key of the policy for a multisig output, and for other scripts the elements the
+/// script starts with on its stack. It is kept out of the transaction body so that signing does
+/// not change what is signed and signers can add theirs in any order.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct Witness {
+    pub signatures: Vec<InputSignature>,
+    #[serde(default)]
+    pub stack: Vec<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Eq, PartialEq, Hash)]
@@ -40,12 +90,110 @@ pub struct UTXO_input {
 pub struct UTXO_output {
     pub receipient_address: Address,
     pub value: u64,
+    // locking script of the output, `receipient_address` is then the address of the script.
+    // Without one the output is locked to the key of `receipient_address`.
+    #[serde(default)]
+    pub script: Option<Script>,
 }
 
+impl UTXO_output {
+    /// The script that has to be satisfied to spend the output
+    pub fn locking_script(&self) -> Script {
+        match &self.script {
+            Some(script) => script.clone(),
+            None => Script::pay_to_address(&self.receipient_address),
+        }
+    }
+
+    pub fn script_type(&self) -> ScriptType {
+        match &self.script {
+            Some(script) => script.classify(),
+            None => ScriptType::PayToAddress(self.receipient_address),
+        }
+    }
+}
+
+/// Largest number of transactions in a mainnet block, see `ChainParams`
+pub const MAX_BLOCK_TRANSACTIONS: usize = 20;
+/// Largest number of unconfirmed transactions in a package, a transaction with its ancestors
+pub const MAX_ANCESTORS: usize = 25;
+/// Largest number of unconfirmed transactions in a transaction with its descendants
+pub const MAX_DESCENDANTS: usize = 25;
+/// Largest size of a transaction with its ancestors, or with its descendants
+pub const MAX_PACKAGE_SIZE: u64 = 101_000;
+/// Largest number of transactions held until their time lock passes
+pub const MAX_HELD: usize = 100;
+
 #[derive(Debug, Default, Clone)]
 pub struct Mempool {
     pub deque: VecDeque<H256>,
     pub tx_map: HashMap<H256, SignedTransaction>,
+    /// the transaction spending each outpoint, at most one per outpoint
+    pub spent: HashMap<UTXO_input, H256>,
+    /// fee of each transaction, given on insertion
+    pub fees: HashMap<H256, u64>,
+    /// serialized size of each transaction
+    pub sizes: HashMap<H256, u64>,
+    /// transactions waiting for parents that are neither in the mempool nor in the chain
+    pub orphans: OrphanPool,
+    /// transactions valid but for their time lock, neither relayed nor mined until it passes
+    pub held: HashMap<H256, SignedTransaction>,
+    /// height of the chain the mempool was last pruned against
+    pub height: u64,
+    /// height of the chain when each transaction entered the mempool
+    pub entry_heights: HashMap<H256, u64>,
+    /// how long transactions took to be confirmed, by fee rate
+    pub fee_estimator: FeeEstimator,
+    /// bumped whenever the transactions a block could hold may have changed, so that a miner
+    /// knows when to build its block template again
+    pub generation: u64,
+}
+
+/// Reasons `Mempool::insert` turns a transaction down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MempoolError {
+    AlreadyKnown,
+    /// an input is already spent by a transaction in the mempool that cannot be replaced
+    Conflict { input: UTXO_input, spent_by: H256 },
+    /// a replacement has to pay more than everything it evicts
+    InsufficientFee { fee: u64, required: u64 },
+    /// a replacement has to pay a higher fee rate than every transaction it conflicts with
+    InsufficientFeeRate { replaced: H256 },
+    /// the transaction would have too many unconfirmed ancestors, or they would be too large
+    AncestorLimit { count: usize, size: u64 },
+    /// an unconfirmed ancestor would have too many descendants, or they would be too large
+    DescendantLimit { ancestor: H256 },
+}
+
+impl std::fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "transaction is already in the mempool"),
+            MempoolError::Conflict { input, spent_by } => write!(
+                f,
+                "input {}:{} is already spent by {}, which does not signal replacement",
+                input.prev_tx_hash, input.index, spent_by
+            ),
+            MempoolError::InsufficientFee { fee, required } => write!(
+                f,
+                "replacement pays a fee of {}, at least {} required",
+                fee, required
+            ),
+            MempoolError::InsufficientFeeRate { replaced } => {
+                write!(f, "replacement has a lower fee rate than {}", replaced)
+            }
+            MempoolError::AncestorLimit { count, size } => write!(
+                f,
+                "{} unconfirmed transactions of {} bytes with its ancestors, limits are {} and {}",
+                count, size, MAX_ANCESTORS, MAX_PACKAGE_SIZE
+            ),
+            MempoolError::DescendantLimit { ancestor } => write!(
+                f,
+                "unconfirmed ancestor {} has too many descendants, limits are {} and {} bytes",
+                ancestor, MAX_DESCENDANTS, MAX_PACKAGE_SIZE
+            ),
+        }
+    }
 }
 
 impl Mempool {
@@ -53,48 +201,562 @@ impl Mempool {
         return Mempool {
             deque: VecDeque::new(),
             tx_map: HashMap::new(),
+            spent: HashMap::new(),
+            fees: HashMap::new(),
+            sizes: HashMap::new(),
+            orphans: OrphanPool::new(),
+            held: HashMap::new(),
+            height: 0,
+            entry_heights: HashMap::new(),
+            fee_estimator: FeeEstimator::new(),
+            generation: 0,
         };
     }
 
-    pub fn insert(&mut self, t: &SignedTransaction) {
+    /// Add a transaction paying `fee`, it has been validated by the caller.
+    ///
+    /// Two transactions in the mempool never spend the same output. A transaction conflicting
+    /// with others is turned down, unless every one of them signals replacement and the new one
+    /// pays a higher fee rate than each of them and a higher fee than all they and their
+    /// descendants pay together, in which case those are evicted (BIP 125). Returns the hashes of
+    /// the evicted transactions.
+    ///
+    /// A transaction and its unconfirmed ancestors count at most `MAX_ANCESTORS` transactions and
+    /// `MAX_PACKAGE_SIZE` bytes, and so do a transaction and its descendants, which bounds the
+    /// work of evaluating packages.
+    pub fn insert(
+        &mut self,
+        t: &SignedTransaction,
+        fee: u64,
+    ) -> Result<Vec<H256>, MempoolError> {
         let t_hash = t.hash();
+        if self.tx_map.contains_key(&t_hash) {
+            return Err(MempoolError::AlreadyKnown);
+        }
 
-        // already exists
-        if (self.tx_map.contains_key(&t_hash)) {
-            return;
+        let mut conflicts = Vec::new();
+        for input in t.transcation.input.iter() {
+            if let Some(h) = self.spent.get(input) {
+                if !self.tx_map[h].transcation.signals_replacement() {
+                    return Err(MempoolError::Conflict {
+                        input: input.clone(),
+                        spent_by: *h,
+                    });
+                }
+                if !conflicts.contains(h) {
+                    conflicts.push(*h);
+                }
+            }
+        }
+        let evicted = self.with_descendants(&conflicts);
+        // a replacement cannot spend what it evicts
+        if let Some(input) = t
+            .transcation
+            .input
+            .iter()
+            .find(|i| evicted.contains(&i.prev_tx_hash))
+        {
+            return Err(MempoolError::Conflict {
+                input: input.clone(),
+                spent_by: input.prev_tx_hash,
+            });
+        }
+        let evicted_fee = evicted
+            .iter()
+            .fold(0u64, |acc, h| acc.saturating_add(self.fees[h]));
+        if !evicted.is_empty() && fee <= evicted_fee {
+            return Err(MempoolError::InsufficientFee {
+                fee,
+                required: evicted_fee + 1,
+            });
         }
+        let size = t.size();
+        for h in conflicts.iter() {
+            if !higher_fee_rate(fee, size, self.fees[h], self.sizes[h]) {
+                return Err(MempoolError::InsufficientFeeRate { replaced: *h });
+            }
+        }
+
+        let ancestors: Vec<H256> = self
+            .ancestors_of(t)
+            .into_iter()
+            .filter(|h| !evicted.contains(h))
+            .collect();
+        let count = ancestors.len() + 1;
+        let ancestor_size = ancestors.iter().fold(size, |acc, h| acc + self.sizes[h]);
+        if count > MAX_ANCESTORS || ancestor_size > MAX_PACKAGE_SIZE {
+            return Err(MempoolError::AncestorLimit {
+                count,
+                size: ancestor_size,
+            });
+        }
+        for ancestor in ancestors.iter() {
+            let descendants: Vec<H256> = self
+                .with_descendants(&[*ancestor])
+                .into_iter()
+                .filter(|h| !evicted.contains(h))
+                .collect();
+            let descendant_size = descendants.iter().fold(size, |acc, h| acc + self.sizes[h]);
+            if descendants.len() + 1 > MAX_DESCENDANTS || descendant_size > MAX_PACKAGE_SIZE {
+                return Err(MempoolError::DescendantLimit {
+                    ancestor: *ancestor,
+                });
+            }
+        }
+
+        for h in evicted.iter() {
+            self.remove_hash(h);
+        }
+        for input in t.transcation.input.iter() {
+            self.spent.insert(input.clone(), t_hash);
+        }
+        self.fees.insert(t_hash, fee);
+        self.sizes.insert(t_hash, size);
+        self.entry_heights.insert(t_hash, self.height);
         self.deque.push_back(t_hash);
         self.tx_map.insert(t_hash, t.clone());
+        self.generation += 1;
+        Ok(evicted)
     }
 
-    pub fn get_headtransactions(&self) -> Vec<SignedTransaction> {
-        let count = cmp::min(20, self.deque.len());
-        self.deque
-            .iter()
-            .take(count as usize)
-            .map(|h| self.tx_map.get(h).unwrap().clone())
+    /// Outputs of transactions in the mempool spent by `inputs`, to validate transactions that
+    /// spend them with `State::validate_with`
+    pub fn unconfirmed_outputs<'a>(
+        &self,
+        inputs: impl Iterator<Item = &'a UTXO_input>,
+    ) -> HashMap<UTXO_input, UTXO_output> {
+        inputs
+            .filter_map(|input| {
+                let parent = self.tx_map.get(&input.prev_tx_hash)?;
+                let output = parent.transcation.output.get(usize::from(input.index))?;
+                Some((input.clone(), output.clone()))
+            })
             .collect()
     }
 
+    /// Transactions in the mempool whose outputs `t` spends, directly or not
+    pub fn ancestors_of(&self, t: &SignedTransaction) -> Vec<H256> {
+        let mut res: Vec<H256> = Vec::new();
+        let mut stack = vec![t];
+        while let Some(tx) = stack.pop() {
+            for input in tx.transcation.input.iter() {
+                let h = input.prev_tx_hash;
+                if let Some(parent) = self.tx_map.get(&h) {
+                    if !res.contains(&h) {
+                        res.push(h);
+                        stack.push(parent);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// The given transactions and every transaction in the mempool spending their outputs,
+    /// directly or not
+    pub fn with_descendants(&self, hashes: &[H256]) -> Vec<H256> {
+        let mut res = hashes.to_vec();
+        let mut i = 0;
+        while i < res.len() {
+            let h = res[i];
+            for index in 0..self.tx_map[&h].transcation.output.len() {
+                let outpoint = UTXO_input {
+                    prev_tx_hash: h,
+                    index: index as u8,
+                };
+                if let Some(child) = self.spent.get(&outpoint) {
+                    if !res.contains(child) {
+                        res.push(*child);
+                    }
+                }
+            }
+            i += 1;
+        }
+        res
+    }
+
+    /// Up to `max` transactions to mine on top of `state`, in an order they can be executed in.
+    ///
+    /// Transactions are picked as packages, a transaction with its ancestors not picked yet, by
+    /// the fee rate of the whole package, so a parent paying a low fee is mined when a child pays
+    /// enough for both (child pays for parent). Packages are ranked again after each pick, and
+    /// ties go to the one that came first. A transaction that is invalid or still time locked is
+    /// skipped together with its descendants.
+    pub fn get_headtransactions(&self, state: &State, max: usize) -> Vec<SignedTransaction> {
+        let mut state = state.clone();
+        let position: HashMap<H256, usize> =
+            self.deque.iter().enumerate().map(|(i, h)| (*h, i)).collect();
+        let ancestors: HashMap<H256, Vec<H256>> = self
+            .tx_map
+            .iter()
+            .map(|(h, tx)| (*h, self.ancestors_of(tx)))
+            .collect();
+        let mut picked: HashSet<H256> = HashSet::new();
+        let mut failed: HashSet<H256> = HashSet::new();
+        let mut res = Vec::new();
+
+        while res.len() < max {
+            // best package as (fee, size, transactions)
+            let mut best: Option<(u64, u64, Vec<H256>)> = None;
+            for h in self.deque.iter() {
+                if picked.contains(h) || failed.contains(h) || !self.tx_map.contains_key(h) {
+                    continue;
+                }
+                let mut package: Vec<H256> = ancestors[h]
+                    .iter()
+                    .filter(|a| !picked.contains(*a))
+                    .cloned()
+                    .collect();
+                if package.iter().any(|a| failed.contains(a)) {
+                    continue;
+                }
+                package.push(*h);
+                if res.len() + package.len() > max {
+                    continue;
+                }
+                let fee = package.iter().fold(0u64, |acc, a| acc.saturating_add(self.fees[a]));
+                let size = package.iter().map(|a| self.sizes[a]).sum();
+                let better = match &best {
+                    None => true,
+                    Some((best_fee, best_size, _)) => {
+                        higher_fee_rate(fee, size, *best_fee, *best_size)
+                    }
+                };
+                if better {
+                    best = Some((fee, size, package));
+                }
+            }
+            let mut package = match best {
+                Some((_, _, package)) => package,
+                None => break,
+            };
+
+            // parents come before their children in the queue
+            package.sort_by_key(|h| position[h]);
+            let mut outputs = HashMap::new();
+            let mut valid = true;
+            for h in package.iter() {
+                let tx = &self.tx_map[h];
+                if state.validate_with(tx, &outputs).is_err() {
+                    failed.extend(self.with_descendants(&[*h]));
+                    valid = false;
+                    break;
+                }
+                for (index, output) in tx.transcation.output.iter().enumerate() {
+                    let outpoint = UTXO_input {
+                        prev_tx_hash: *h,
+                        index: index as u8,
+                    };
+                    outputs.insert(outpoint, output.clone());
+                }
+            }
+            if !valid {
+                continue;
+            }
+            for h in package {
+                let tx = &self.tx_map[&h];
+                state.apply(tx);
+                res.push(tx.clone());
+                picked.insert(h);
+            }
+        }
+        res
+    }
+
     pub fn remove(&mut self, t: &SignedTransaction) {
-        let t_hash = t.hash();
-        if (self.tx_map.contains_key(&t_hash)) {
-            self.tx_map.remove(&t_hash);
+        self.remove_hash(&t.hash());
+    }
+
+    fn remove_hash(&mut self, t_hash: &H256) {
+        if let Some(tx) = self.tx_map.remove(t_hash) {
+            self.deque.retain(|h| h != t_hash);
+            self.fees.remove(t_hash);
+            self.sizes.remove(t_hash);
+            self.entry_heights.remove(t_hash);
+            for input in tx.transcation.input.iter() {
+                self.spent.remove(input);
+            }
+            self.generation += 1;
+        }
+    }
+
+    /// Add back the transactions of blocks a reorganization disconnected, given oldest first,
+    /// if they are still valid on top of `state`, the state of the new tip. Time locked ones are
+    /// held, the others are dropped. They go to the front of the queue, ahead of the transactions
+    /// that may spend their outputs, and must be restored before the mempool is pruned against
+    /// `state`. Returns each transaction added, with its fee and the transactions it replaced.
+    pub fn restore(
+        &mut self,
+        state: &State,
+        transactions: &[SignedTransaction],
+    ) -> Vec<(H256, u64, Vec<H256>)> {
+        let mut res = Vec::new();
+        for tx in transactions.iter() {
+            let unconfirmed = self.unconfirmed_outputs(tx.transcation.input.iter());
+            match state.validate_with(tx, &unconfirmed) {
+                Ok(()) => {}
+                Err(e) if e.is_non_final() => {
+                    self.hold(tx);
+                    continue;
+                }
+                Err(_) => continue,
+            }
+            let fee = state.fee_with(tx, &unconfirmed);
+            if let Ok(evicted) = self.insert(tx, fee) {
+                res.push((tx.hash(), fee, evicted));
+            }
+        }
+        // insert queued them last, in order
+        for _ in 0..res.len() {
+            let hash = self.deque.pop_back().unwrap();
+            self.deque.push_front(hash);
         }
+        res
     }
+
+    /// Drop every transaction that is no longer valid on top of `state`, e.g. because it was
+    /// included in a block or one of its inputs was spent by another transaction. Transactions
+    /// whose only problem is a time lock are held until it passes. Parents come before their
+    /// children in the queue, so a child is checked against the outputs of the parents kept.
+    /// Returns the hashes of the transactions dropped.
+    pub fn prune(&mut self, state: &State) -> Vec<H256> {
+        // time locks are checked against the height, which may let held transactions in
+        if self.height != state.height {
+            self.generation += 1;
+        }
+        self.height = state.height;
+        let mut unconfirmed = HashMap::new();
+        let mut invalid = Vec::new();
+        for h in self.deque.iter() {
+            let tx = match self.tx_map.get(h) {
+                Some(tx) => tx,
+                None => {
+                    invalid.push(*h);
+                    continue;
+                }
+            };
+            match state.validate_with(tx, &unconfirmed) {
+                Err(e) if !e.is_non_final() => invalid.push(*h),
+                _ => {
+                    for (index, output) in tx.transcation.output.iter().enumerate() {
+                        let outpoint = UTXO_input {
+                            prev_tx_hash: *h,
+                            index: index as u8,
+                        };
+                        unconfirmed.insert(outpoint, output.clone());
+                    }
+                }
+            }
+        }
+        invalid.retain(|h| self.tx_map.contains_key(h));
+        for h in invalid.iter() {
+            self.remove_hash(h);
+        }
+        invalid
+    }
+
+    /// Validate again on top of `state` the orphans spending outputs of `parents`, transactions
+    /// that were just confirmed in it or released, and add those that are valid now, then their
+    /// own orphans in turn. Orphans still missing an input are kept, time locked ones are held,
+    /// the others are dropped, and so are the orphans that waited too long. Returns each
+    /// transaction added, with its fee and the transactions it replaced.
+    pub fn adopt_orphans(
+        &mut self,
+        state: &State,
+        parents: &[H256],
+    ) -> Vec<(H256, u64, Vec<H256>)> {
+        self.orphans.expire(std::time::Instant::now());
+        let mut res = Vec::new();
+        let mut pending = self.orphans.take_children(parents);
+        while !pending.is_empty() {
+            let mut added = Vec::new();
+            for tx in pending {
+                let unconfirmed = self.unconfirmed_outputs(tx.transcation.input.iter());
+                match state.validate_with(&tx, &unconfirmed) {
+                    Ok(()) => {}
+                    Err(e) if e.is_non_final() => {
+                        self.hold(&tx);
+                        continue;
+                    }
+                    Err(TransactionError::MissingInput(_)) => {
+                        let missing: Vec<UTXO_input> = tx
+                            .transcation
+                            .input
+                            .iter()
+                            .filter(|i| {
+                                !state.utxos.contains_key(i) && !unconfirmed.contains_key(i)
+                            })
+                            .cloned()
+                            .collect();
+                        self.orphans.insert(tx, &missing);
+                        continue;
+                    }
+                    Err(_) => continue,
+                }
+                let fee = state.fee_with(&tx, &unconfirmed);
+                if let Ok(evicted) = self.insert(&tx, fee) {
+                    added.push(tx.hash());
+                    res.push((tx.hash(), fee, evicted));
+                }
+            }
+            pending = self.orphans.take_children(&added);
+        }
+        res
+    }
+
+    /// Keep a transaction that is valid but for its time lock until `release_final` finds it
+    /// final. When there are too many a random one makes room for it, so a peer cannot keep a
+    /// given transaction out by flooding. Returns false if it is already known.
+    pub fn hold(&mut self, tx: &SignedTransaction) -> bool {
+        let hash = tx.hash();
+        if self.held.contains_key(&hash) || self.tx_map.contains_key(&hash) {
+            return false;
+        }
+        if self.held.len() >= MAX_HELD {
+            let evicted = *self.held.keys().choose(&mut rand::thread_rng()).unwrap();
+            self.held.remove(&evicted);
+        }
+        self.held.insert(hash, tx.clone());
+        true
+    }
+
+    /// Validate again on top of `state` the transactions held for their time lock, and add those
+    /// that are final now. The ones still locked are kept, the others are dropped. Returns each
+    /// transaction added, with its fee and the transactions it replaced.
+    pub fn release_final(&mut self, state: &State) -> Vec<(H256, u64, Vec<H256>)> {
+        let mut res = Vec::new();
+        let mut pending: Vec<SignedTransaction> = self.held.drain().map(|(_, tx)| tx).collect();
+        // a held transaction may spend the outputs of another one released in the same pass
+        let mut missing = Vec::new();
+        loop {
+            let mut added = false;
+            for tx in pending {
+                let unconfirmed = self.unconfirmed_outputs(tx.transcation.input.iter());
+                match state.validate_with(&tx, &unconfirmed) {
+                    Ok(()) => {}
+                    Err(e) if e.is_non_final() => {
+                        self.held.insert(tx.hash(), tx);
+                        continue;
+                    }
+                    Err(TransactionError::MissingInput(_)) => {
+                        missing.push(tx);
+                        continue;
+                    }
+                    Err(_) => continue,
+                }
+                let fee = state.fee_with(&tx, &unconfirmed);
+                if let Ok(evicted) = self.insert(&tx, fee) {
+                    added = true;
+                    res.push((tx.hash(), fee, evicted));
+                }
+            }
+            if !added {
+                break;
+            }
+            pending = std::mem::take(&mut missing);
+        }
+        res
+    }
+
+    /// Tell the fee estimator which of our transactions the block at `height` confirmed, before
+    /// the mempool is pruned against it. Blocks have to be recorded in order of height.
+    pub fn record_block(&mut self, hashes: &[H256], height: u64) {
+        let confirmed: Vec<(f64, u64)> = hashes
+            .iter()
+            .filter_map(|h| {
+                let entry = self.entry_heights.get(h)?;
+                let rate = fee_rate(self.fees[h], self.sizes[h]);
+                Some((rate, height.saturating_sub(*entry)))
+            })
+            .collect();
+        self.fee_estimator.process_block(height, &confirmed);
+    }
+
+    /// Fee per 1000 bytes a transaction entering now should pay to be confirmed within `blocks`
+    /// blocks, if there is enough data
+    pub fn estimate_fee_rate(&self, blocks: usize) -> Option<f64> {
+        let pending: Vec<(f64, u64)> = self
+            .entry_heights
+            .iter()
+            .map(|(h, entry)| {
+                let rate = fee_rate(self.fees[h], self.sizes[h]);
+                (rate, self.height.saturating_sub(*entry))
+            })
+            .collect();
+        self.fee_estimator.estimate(blocks, &pending)
+    }
+}
+
+/// Whether `fee` for `size` bytes is a strictly higher rate than `other_fee` for `other_size`
+fn higher_fee_rate(fee: u64, size: u64, other_fee: u64, other_size: u64) -> bool {
+    // compare fee / size without dividing
+    u128::from(fee) * u128::from(other_size) > u128::from(other_fee) * u128::from(size)
 }
 
 // According to Midterm1, impl Hashable for SignedTranscation
+// The id leaves the witnesses out: anyone relaying a transaction could pad them with elements
+// nothing checks, which would change the id of a transaction that stays valid.
 impl Hashable for SignedTransaction {
     fn hash(&self) -> H256 {
-        let serial_res = bincode::serialize(&self).unwrap();
+        let serial_res = bincode::serialize(&self.transcation).unwrap();
         let res: H256 = digest::digest(&digest::SHA256, serial_res.as_ref()).into();
         res
     }
 }
 
-/// Create digital signature of a transaction
-pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
+impl InputSignature {
+    pub fn new(digest: &H256, key: &Ed25519KeyPair) -> Self {
+        InputSignature {
+            public_key: key.public_key().as_ref().to_vec(),
+            signature: sign(digest, key).as_ref().to_vec(),
+        }
+    }
+
+    pub fn verify(&self, digest: &H256) -> bool {
+        verify(digest, &self.public_key, &self.signature)
+    }
+}
+
+impl Witness {
+    /// Add a signature of `digest` made with `key`, unless the key already signed
+    pub fn sign(&mut self, digest: &H256, key: &Ed25519KeyPair) -> bool {
+        if self
+            .signatures
+            .iter()
+            .any(|s| s.public_key.as_slice() == key.public_key().as_ref())
+        {
+            return false;
+        }
+        self.signatures.push(InputSignature::new(digest, key));
+        true
+    }
+}
+
+#[derive(Serialize)]
+struct SignatureHashPreimage<'a> {
+    transaction: &'a Transaction,
+    index: u32,
+    outpoint: &'a UTXO_input,
+    spent: &'a UTXO_output,
+}
+
+/// Digest signed to spend input `index` of `t`, which spends the output `spent`. It commits to
+/// the whole body (every input and output), the position of the input, the outpoint and the
+/// value, address and policy of the spent output, so a signature cannot be reused for another
+/// input or another transaction, and an offline signer knows exactly how much it spends.
+pub fn signature_hash(t: &Transaction, index: usize, spent: &UTXO_output) -> H256 {
+    let preimage = SignatureHashPreimage {
+        transaction: t,
+        index: index as u32,
+        outpoint: &t.input[index],
+        spent,
+    };
+    digest::digest(&digest::SHA256, &bincode::serialize(&preimage).unwrap()).into()
+}
+
+/// Create digital signature of a transaction, or of any serializable message such as a
+/// `signature_hash`
+pub fn sign<T: Serialize + ?Sized>(t: &T, key: &Ed25519KeyPair) -> Signature {
     // Because key.sign() only accept &[u8], so we need to figure out how to convert t into &[u8] type
     // Convert to Vec<u8> first
     let serial_res = bincode::serialize(t).unwrap();
@@ -108,7 +770,7 @@ pub fn sign(t: &Transaction, key: &Ed25519KeyPair) -> Signature {
 }
 
 // Verify digital signature of a transaction, using public key instead of secret key
-pub fn verify(t: &Transaction, public_key: &Vec<u8>, signature: &Vec<u8>) -> bool {
+pub fn verify<T: Serialize + ?Sized>(t: &T, public_key: &Vec<u8>, signature: &Vec<u8>) -> bool {
     // Because in UnparsedPublicKey, the verify need to accept &[u8] as parameter
     // so we need to convert it
     let serial_res = bincode::serialize(t).unwrap();
@@ -127,6 +789,7 @@ pub fn verify(t: &Transaction, public_key: &Vec<u8>, signature: &Vec<u8>) -> boo
 #[cfg(any(test, test_utilities))]
 pub fn generate_random_transaction() -> Transaction {
     use crate::types::{address, key_pair};
+    use rand::Rng;
     use std::{convert::TryInto, ops::Add};
 
     let mut rng = rand::thread_rng();
@@ -148,6 +811,7 @@ pub fn generate_random_transaction() -> Transaction {
     let fake_utxo_out = UTXO_output {
         receipient_address: fake_address,
         value: value,
+        script: None,
     };
 
     let rand_num: u8 = rng.gen();
@@ -170,6 +834,7 @@ pub fn generate_random_transaction() -> Transaction {
         value: rng.gen(),
         input: utxo_in_vec,
         output: utxo_out_vec,
+        ..Default::default()
     };
     transc
 }
@@ -180,6 +845,7 @@ pub fn generate_random_transaction() -> Transaction {
 mod tests {
     use super::*;
     use crate::types::key_pair;
+    use crate::types::state::TransactionError;
     use ring::signature::KeyPair;
     use serde_json::to_vec;
 
@@ -217,6 +883,186 @@ mod tests {
             &signature.as_ref().to_vec()
         ));
     }
+
+    /// Unsigned transaction spending `input`, the value tells transactions apart
+    fn spending(input: UTXO_input, value: u64, replaceable: bool) -> SignedTransaction {
+        let sequences = if replaceable {
+            vec![MAX_REPLACEABLE_SEQUENCE]
+        } else {
+            vec![]
+        };
+        SignedTransaction {
+            transcation: Transaction {
+                input: vec![input],
+                output: vec![UTXO_output {
+                    receipient_address: [7u8; 20].into(),
+                    value,
+                    script: None,
+                }],
+                sequences,
+                ..Default::default()
+            },
+            witnesses: vec![Witness::default()],
+        }
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let coin = UTXO_input {
+            prev_tx_hash: [1u8; 32].into(),
+            index: 0,
+        };
+        let mut mempool = Mempool::new();
+        let first = spending(coin.clone(), 90, false);
+        assert_eq!(mempool.insert(&first, 10), Ok(vec![]));
+        assert_eq!(mempool.insert(&first, 10), Err(MempoolError::AlreadyKnown));
+        // the first spend did not opt in, so even a much higher fee cannot replace it
+        assert_eq!(
+            mempool.insert(&spending(coin.clone(), 50, true), 50),
+            Err(MempoolError::Conflict {
+                input: coin.clone(),
+                spent_by: first.hash()
+            })
+        );
+        // transactions turned down leave block templates as they are
+        assert_eq!(mempool.generation, 1);
+
+        let mut mempool = Mempool::new();
+        let parent = spending(coin.clone(), 90, true);
+        let child = spending(
+            UTXO_input {
+                prev_tx_hash: parent.hash(),
+                index: 0,
+            },
+            85,
+            false,
+        );
+        mempool.insert(&parent, 10).unwrap();
+        mempool.insert(&child, 5).unwrap();
+        // the replacement has to pay for the child it evicts as well
+        let replacement = spending(coin.clone(), 86, false);
+        assert_eq!(
+            mempool.insert(&replacement, 14),
+            Err(MempoolError::InsufficientFee {
+                fee: 14,
+                required: 16
+            })
+        );
+        assert_eq!(mempool.tx_map.len(), 2);
+        assert_eq!(
+            mempool.insert(&replacement, 16),
+            Ok(vec![parent.hash(), child.hash()])
+        );
+        assert_eq!(mempool.tx_map.len(), 1);
+        assert_eq!(mempool.deque.len(), 1);
+        assert_eq!(mempool.spent[&coin], replacement.hash());
+        assert!(!mempool.fees.contains_key(&child.hash()));
+
+        // a higher fee in a much larger transaction is a lower fee rate
+        let mut mempool = Mempool::new();
+        mempool.insert(&parent, 10).unwrap();
+        let mut large = spending(coin, 80, false);
+        large.witnesses[0].stack = vec![vec![0u8; 2000]];
+        assert_eq!(
+            mempool.insert(&large, 20),
+            Err(MempoolError::InsufficientFeeRate {
+                replaced: parent.hash()
+            })
+        );
+    }
+
+    #[test]
+    fn child_pays_for_parent() {
+        use crate::types::timelock::LockTime;
+        let key = key_pair::random();
+        let owner = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut state = State::new();
+        let coin = |seed: u8| UTXO_input {
+            prev_tx_hash: [seed; 32].into(),
+            index: 0,
+        };
+        for seed in 1..=3 {
+            state.utxos.insert(
+                coin(seed),
+                UTXO_output {
+                    receipient_address: owner,
+                    value: 100,
+                    script: None,
+                },
+            );
+        }
+        // spend an output of `value`, keeping `value - fee`
+        let spend = |input: UTXO_input, value: u64, fee: u64, lock_time: u64| {
+            let spent = UTXO_output {
+                receipient_address: owner,
+                value,
+                script: None,
+            };
+            let mut t = Transaction {
+                input: vec![input],
+                output: vec![spent.clone()],
+                lock_time,
+                ..Default::default()
+            };
+            t.output[0].value = value - fee;
+            let mut witness = Witness::default();
+            witness.sign(&signature_hash(&t, 0, &spent), &key);
+            SignedTransaction {
+                transcation: t,
+                witnesses: vec![witness],
+            }
+        };
+        let child_of = |parent: &SignedTransaction, fee: u64| {
+            let input = UTXO_input {
+                prev_tx_hash: parent.hash(),
+                index: 0,
+            };
+            spend(input, parent.transcation.output[0].value, fee, 0)
+        };
+
+        let mut mempool = Mempool::new();
+        let parent = spend(coin(1), 100, 1, 0);
+        let other = spend(coin(2), 100, 10, 0);
+        let child = child_of(&parent, 30);
+        for (tx, fee) in [(&parent, 1), (&other, 10), (&child, 30)].iter() {
+            mempool.insert(tx, *fee).unwrap();
+        }
+        assert_eq!(mempool.ancestors_of(&child), vec![parent.hash()]);
+        let hashes = |txs: Vec<SignedTransaction>| -> Vec<H256> {
+            txs.iter().map(|tx| tx.hash()).collect()
+        };
+        // the parent alone pays the least, with its child it pays the most
+        assert_eq!(
+            hashes(mempool.get_headtransactions(&state, MAX_BLOCK_TRANSACTIONS)),
+            vec![parent.hash(), child.hash(), other.hash()]
+        );
+
+        // a high fee does not help the child of a transaction that cannot be mined yet
+        let locked = spend(coin(3), 100, 1, 50);
+        let locked_child = child_of(&locked, 50);
+        mempool.insert(&locked, 1).unwrap();
+        mempool.insert(&locked_child, 50).unwrap();
+        assert_eq!(
+            state.validate(&locked),
+            Err(TransactionError::NonFinal(LockTime::Height(50)))
+        );
+        assert_eq!(mempool.get_headtransactions(&state, MAX_BLOCK_TRANSACTIONS).len(), 3);
+
+        // chains of unconfirmed transactions are bounded
+        let mut tip = child;
+        for _ in 2..MAX_ANCESTORS {
+            tip = child_of(&tip, 1);
+            mempool.insert(&tip, 1).unwrap();
+        }
+        let too_long = child_of(&tip, 1);
+        assert_eq!(
+            mempool.insert(&too_long, 1),
+            Err(MempoolError::AncestorLimit {
+                count: MAX_ANCESTORS + 1,
+                size: (MAX_ANCESTORS as u64 + 1) * too_long.size()
+            })
+        );
+    }
 }
 
 // DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
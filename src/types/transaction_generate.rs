@@ -1,16 +1,19 @@
 use super::block::{self, Block};
+use super::state::State;
 use crate::blockchain::Blockchain;
+use crate::events::EventBus;
 use crate::network::message::Message;
 use crate::network::peer;
 use crate::network::server::Handle as ServerHandle;
 use crate::types::address::Address;
 use crate::types::hash::{self, Hashable, H256};
 use crate::types::transaction::{
-    sign, Mempool, SignedTransaction, Transaction, UTXO_input, UTXO_output,
+    signature_hash, Mempool, SignedTransaction, Transaction, UTXO_input, UTXO_output, Witness,
 };
+use crate::wallet::Wallet;
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use futures::AsyncWriteExt;
-use log::{debug, info, warn};
+use log::{debug, info};
 use rand::distributions::Open01;
 use rand::seq::SliceRandom;
 use rand::Rng;
@@ -18,14 +21,17 @@ use ring::digest;
 use ring::signature::{
     self, Ed25519KeyPair, EdDSAParameters, KeyPair, Signature, VerificationAlgorithm,
 };
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time;
 
+/// Number of keys the generator spends from and sends change to
+pub const GENERATOR_KEYS: usize = 4;
+
 // enum class that supports message in channel
 enum ControlSignal {
-    Start(u64), // the number controls the theta of interval between block generation
-    Update,     // change name Gen to Update
+    Start(u64, Mode), // the number controls the theta of interval between block generation
+    Update,           // change name Gen to Update
     Exit,
 }
 
@@ -35,31 +41,109 @@ enum OperatingState {
     ShutDown,
 }
 
+/// What the generator makes
+#[derive(Debug, Clone)]
+pub enum Mode {
+    /// made-up transactions that spend nothing real, to exercise relay only
+    Random,
+    /// valid transactions spending the outputs of the generator's keys
+    Spend(SpendConfig),
+}
+
+/// How values of generated payments are drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueDistribution {
+    /// any value from `min` to `max` included
+    Uniform { min: u64, max: u64 },
+    /// mostly small values with a few large ones, averaging `mean`
+    Exponential { mean: u64 },
+}
+
+impl ValueDistribution {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> u64 {
+        let value = match *self {
+            ValueDistribution::Uniform { min, max } => rng.gen_range(min..=max.max(min)),
+            ValueDistribution::Exponential { mean } => {
+                let u: f64 = rng.sample(Open01);
+                (-(mean as f64) * u.ln()) as u64
+            }
+        };
+        value.max(1)
+    }
+}
+
+/// Shape of the transactions made in `Mode::Spend`
+#[derive(Debug, Clone)]
+pub struct SpendConfig {
+    /// addresses of other nodes paid along with our own ones
+    pub peers: Vec<Address>,
+    /// payments per transaction, besides the change
+    pub fanout: usize,
+    pub values: ValueDistribution,
+    /// fee per 1000 bytes
+    pub fee_rate: u64,
+}
+
+impl Default for SpendConfig {
+    fn default() -> Self {
+        SpendConfig {
+            peers: Vec::new(),
+            fanout: 2,
+            values: ValueDistribution::Uniform { min: 1, max: 100 },
+            fee_rate: 10,
+        }
+    }
+}
+
 pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    mode: Mode,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    /// keys the generator spends from, locked after the mempool
+    wallet: Arc<Mutex<Wallet>>,
+    events: EventBus,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the generator thread
     control_chan: Sender<ControlSignal>,
+    wallet: Arc<Mutex<Wallet>>,
 }
 
-pub fn new(server: &ServerHandle, blockchain: &Arc<Mutex<Blockchain>>) -> (Context, Handle) {
+/// The generator spends from `wallet`, which gets `GENERATOR_KEYS` keys if it has fewer
+pub fn new(
+    server: &ServerHandle,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    mut wallet: Wallet,
+    events: &EventBus,
+) -> (Context, Handle) {
+    for i in wallet.addresses().len()..GENERATOR_KEYS {
+        wallet
+            .generate_key(&format!("generator-{}", i))
+            .expect("generator wallet is unlocked");
+    }
+    let wallet = Arc::new(Mutex::new(wallet));
     // bound receiver and sender to comunication in channels
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        mode: Mode::Random,
         server: server.clone(),
         blockchain: blockchain.clone(),
+        mempool: mempool.clone(),
+        wallet: wallet.clone(),
+        events: events.clone(),
     };
     let handle = Handle {
         control_chan: signal_chan_sender,
+        wallet,
     };
 
     (ctx, handle)
@@ -70,8 +154,16 @@ impl Handle {
         self.control_chan.send(ControlSignal::Exit).unwrap();
     }
 
-    pub fn start(&self, theta: u64) {
-        self.control_chan.send(ControlSignal::Start(theta)).unwrap();
+    pub fn start(&self, theta: u64, mode: Mode) {
+        self.control_chan
+            .send(ControlSignal::Start(theta, mode))
+            .unwrap();
+    }
+
+    /// Addresses the generator spends from, to be funded before it can make `Mode::Spend`
+    /// transactions
+    pub fn addresses(&self) -> Vec<Address> {
+        self.wallet.lock().unwrap().addresses()
     }
 
     pub fn update(&self) {
@@ -94,12 +186,7 @@ impl Context {
         use crate::types::{address, key_pair};
         use std::{convert::TryInto, ops::Add};
 
-        // On startup, insert and ICO for yourself into the mempool
-        info!("Executing ICO - Generating Sourceless TX");
-
-        // Share ICO TX with others - they likely won't mine it, but should have it on hand
-        // let signed_tx_hash: H256 = signed_tx.hash();
-        // self.server.broadcast(Message::NewTransactionHashes(vec![signed_tx_hash]));
+        // initial coins come from the allocations of the genesis spec, see blockchain::genesis
         loop {
             // print!("matching state");
             match self.operating_state {
@@ -111,9 +198,10 @@ impl Context {
                             info!("Generator shutting down");
                             self.operating_state = OperatingState::ShutDown;
                         }
-                        ControlSignal::Start(i) => {
+                        ControlSignal::Start(i, mode) => {
                             info!("Generator starting in continuous mode with theta {}", i);
                             self.operating_state = OperatingState::Run(i);
+                            self.mode = mode;
                         }
                         ControlSignal::Update => {
                             // in paused state, don't need to update
@@ -131,9 +219,10 @@ impl Context {
                                 info!("Generator shutting down");
                                 self.operating_state = OperatingState::ShutDown;
                             }
-                            ControlSignal::Start(i) => {
+                            ControlSignal::Start(i, mode) => {
                                 info!("Generator starting in continuous mode with theta {}", i);
                                 self.operating_state = OperatingState::Run(i);
+                                self.mode = mode;
                             }
                             ControlSignal::Update => {
                                 unimplemented!()
@@ -147,73 +236,10 @@ impl Context {
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
-            let blockchain_mtx = self.blockchain.lock().unwrap();
-            let mut mempool_locked = blockchain_mtx.mempool.lock().unwrap();
-            // println!("Generate a transaction, size of mempool 0 {}", mempool_locked.deque.len());
-            use crate::types::{address, key_pair};
-            use std::{convert::TryInto, ops::Add};
-            // assemble a fake transaction
-            let mut rng = rand::thread_rng();
-            let mut sender = Vec::<u8>::with_capacity(20);
-            let mut receiver = Vec::<u8>::with_capacity(20);
-            let mut address_array = [0u8; 20];
-            for i in 0..20 {
-                sender.push(rng.gen());
-                receiver.push(rng.gen());
-                address_array[i] = rng.gen();
+            match self.mode.clone() {
+                Mode::Random => self.generate_random(),
+                Mode::Spend(config) => self.generate_spend(&config),
             }
-            // assemble utxo_out, random
-            let fake_address = Address::new(address_array);
-            let value: u64 = rng.gen();
-            let fake_utxo_out = UTXO_output {
-                receipient_address: fake_address,
-                value: value,
-            };
-            // assemble utxo_input random
-            let rand_num: u8 = rng.gen();
-            let previous_output: H256 = [rand_num; 32].into();
-            let index: u8 = rng.gen();
-            let fake_utxo_in = UTXO_input {
-                prev_tx_hash: previous_output,
-                index: index,
-            };
-
-            let utxo_in_vec = vec![fake_utxo_in];
-            let utxo_out_vec = vec![fake_utxo_out];
-            // sender and receiver, two paras in transaction
-            let sender_addr: [u8; 20] = sender.try_into().unwrap();
-            let receiver_addr: [u8; 20] = receiver.try_into().unwrap();
-
-            let transc = Transaction {
-                sender: Address::new(sender_addr),
-                receiver: Address::new(receiver_addr),
-                value: rng.gen(),
-                input: utxo_in_vec,
-                output: utxo_out_vec,
-            };
-            // use public key to sign a transaction
-            let key = key_pair::random();
-            let signature = sign(&transc, &key);
-            // assemble to a signedtransaction
-            let signed_tx = SignedTransaction {
-                public_key: key.public_key().as_ref().to_vec(),
-                signature: signature.as_ref().to_vec(),
-                transcation: transc,
-            };
-            // add assembled random signedtransaction into mempool
-            // println!("mempool size {}", mempool_locked.deque.len());
-            println!(
-                "Generate a transaction, size of mempool 1 {}",
-                mempool_locked.deque.len()
-            );
-            mempool_locked.insert(&signed_tx);
-            let signed_tx_hash: H256 = signed_tx.hash();
-            // broadcast new signedtx inserted
-            // println!("new_transaction hash");
-            self.server
-                .broadcast(Message::Transactions(vec![signed_tx]));
-            drop(mempool_locked);
-            drop(blockchain_mtx);
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
                     let interval = time::Duration::from_millis(i / 10 as u64);
@@ -226,4 +252,249 @@ impl Context {
             // thread::sleep(time::Duration::from_millis(1000));
         }
     }
+
+    fn generate_random(&self) {
+        let mut mempool_locked = self.mempool.lock().unwrap();
+        // println!("Generate a transaction, size of mempool 0 {}", mempool_locked.deque.len());
+        use crate::types::{address, key_pair};
+        use std::{convert::TryInto, ops::Add};
+        // assemble a fake transaction
+        let mut rng = rand::thread_rng();
+        let mut sender = Vec::<u8>::with_capacity(20);
+        let mut receiver = Vec::<u8>::with_capacity(20);
+        let mut address_array = [0u8; 20];
+        for i in 0..20 {
+            sender.push(rng.gen());
+            receiver.push(rng.gen());
+            address_array[i] = rng.gen();
+        }
+        // assemble utxo_out, random
+        let fake_address = Address::new(address_array);
+        let value: u64 = rng.gen();
+        let fake_utxo_out = UTXO_output {
+            receipient_address: fake_address,
+            value: value,
+            script: None,
+        };
+        // assemble utxo_input random
+        let rand_num: u8 = rng.gen();
+        let previous_output: H256 = [rand_num; 32].into();
+        let index: u8 = rng.gen();
+        let fake_utxo_in = UTXO_input {
+            prev_tx_hash: previous_output,
+            index: index,
+        };
+
+        let utxo_in_vec = vec![fake_utxo_in];
+        let utxo_out_vec = vec![fake_utxo_out];
+        // sender and receiver, two paras in transaction
+        let sender_addr: [u8; 20] = sender.try_into().unwrap();
+        let receiver_addr: [u8; 20] = receiver.try_into().unwrap();
+
+        let transc = Transaction {
+            sender: Address::new(sender_addr),
+            receiver: Address::new(receiver_addr),
+            value: rng.gen(),
+            input: utxo_in_vec,
+            output: utxo_out_vec,
+            ..Default::default()
+        };
+        // sign the input with a random key, as if it spent an output like the one created
+        let key = key_pair::random();
+        let mut witness = Witness::default();
+        witness.sign(&signature_hash(&transc, 0, &transc.output[0]), &key);
+        // assemble to a signedtransaction
+        let signed_tx = SignedTransaction {
+            transcation: transc,
+            witnesses: vec![witness],
+        };
+        // add assembled random signedtransaction into mempool
+        // println!("mempool size {}", mempool_locked.deque.len());
+        println!(
+            "Generate a transaction, size of mempool 1 {}",
+            mempool_locked.deque.len()
+        );
+        // the made-up input is worth nothing, so is the fee
+        if let Err(e) = mempool_locked.insert(&signed_tx, 0) {
+            debug!("Generated transaction not added: {}", e);
+        }
+        let signed_tx_hash: H256 = signed_tx.hash();
+        // broadcast new signedtx inserted
+        // println!("new_transaction hash");
+        self.server
+            .broadcast(Message::Transactions(vec![signed_tx]));
+        drop(mempool_locked);
+    }
+
+    fn generate_spend(&self, config: &SpendConfig) {
+        // snapshot the tip state first, the blockchain and mempool locks are never nested
+        let state = self.blockchain.read().unwrap().tip_state().clone();
+        let mut mempool = self.mempool.lock().unwrap();
+        let wallet = self.wallet.lock().unwrap();
+        let tx = match spend(&wallet, &state, &mempool, config, &mut rand::thread_rng()) {
+            Some(tx) => tx,
+            None => {
+                debug!("Generator has nothing left to spend");
+                return;
+            }
+        };
+        drop(wallet);
+        let unconfirmed = mempool.unconfirmed_outputs(tx.transcation.input.iter());
+        let fee = state.fee_with(&tx, &unconfirmed);
+        match mempool.insert(&tx, fee) {
+            Ok(evicted) => self.events.transaction_accepted(tx.hash(), fee, &evicted),
+            Err(e) => {
+                debug!("Generated transaction not added: {}", e);
+                return;
+            }
+        }
+        drop(mempool);
+        self.server
+            .broadcast(Message::NewTransactionHashes(vec![tx.hash()]));
+    }
+}
+
+/// Build a transaction paying `config.fanout` outputs to our own and peer addresses, spending
+/// outputs of `wallet` that are confirmed in `state` or created in the mempool and not spent
+/// there yet, with the change going to one of our addresses. Coins are picked at random so the
+/// unconfirmed chains stay short. Returns `None` if the coins cannot cover the payments and fee.
+pub fn spend<R: Rng>(
+    wallet: &Wallet,
+    state: &State,
+    mempool: &Mempool,
+    config: &SpendConfig,
+    rng: &mut R,
+) -> Option<SignedTransaction> {
+    let own = wallet.addresses();
+    let mut coins = wallet.utxos(state);
+    for (tx_hash, tx) in mempool.tx_map.iter() {
+        for (index, output) in tx.transcation.output.iter().enumerate() {
+            if wallet.is_mine(&output.receipient_address) && output.script.is_none() {
+                let input = UTXO_input {
+                    prev_tx_hash: *tx_hash,
+                    index: index as u8,
+                };
+                coins.push((input, output.clone()));
+            }
+        }
+    }
+    coins.retain(|(input, _)| !mempool.spent.contains_key(input));
+    // sorted first, hash maps have no fixed order and a seeded rng must pick the same coins
+    coins.sort_by_key(|(input, _)| (input.prev_tx_hash, input.index));
+    coins.shuffle(rng);
+
+    let destinations: Vec<Address> = own.iter().chain(config.peers.iter()).cloned().collect();
+    let change_address = *own.choose(rng)?;
+    let fanout = config.fanout.clamp(1, u8::MAX as usize - 1);
+    let mut output: Vec<UTXO_output> = (0..fanout)
+        .map(|_| UTXO_output {
+            receipient_address: *destinations.choose(rng).unwrap(),
+            value: config.values.sample(rng),
+            script: None,
+        })
+        .collect();
+    let paid = output
+        .iter()
+        .fold(0u64, |acc, o| acc.saturating_add(o.value));
+    output.push(UTXO_output {
+        receipient_address: change_address,
+        value: 0,
+        script: None,
+    });
+    let mut tx = SignedTransaction {
+        transcation: Transaction {
+            sender: change_address,
+            receiver: output[0].receipient_address,
+            // legacy field from the account model, the outputs carry the real value
+            value: output[0].value.min(u32::MAX as u64) as u32,
+            output,
+            ..Default::default()
+        },
+        witnesses: Vec::new(),
+    };
+
+    // only the spent outputs are needed to sign
+    let mut spent = State::default();
+    let mut total = 0u64;
+    for (input, coin) in coins {
+        total = total.saturating_add(coin.value);
+        tx.transcation.input.push(input.clone());
+        spent.utxos.insert(input, coin);
+        if total < paid {
+            continue;
+        }
+        // the size does not depend on the change, so a draft tells the fee
+        wallet.sign_transaction(&spent, &mut tx).ok()?;
+        let fee = (config.fee_rate * tx.size()).div_ceil(1000);
+        if total < paid.saturating_add(fee) {
+            continue;
+        }
+        let change = total - paid - fee;
+        if change == 0 {
+            tx.transcation.output.pop();
+        } else {
+            tx.transcation.output.last_mut().unwrap().value = change;
+        }
+        tx.witnesses = Vec::new();
+        wallet.sign_transaction(&spent, &mut tx).ok()?;
+        return Some(tx);
+    }
+    None
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spend_real_outputs() {
+        let mut wallet = Wallet::new();
+        let me = wallet.generate_key("generator").unwrap();
+        let peer: Address = [9u8; 20].into();
+        let config = SpendConfig {
+            peers: vec![peer],
+            fanout: 3,
+            values: ValueDistribution::Uniform { min: 10, max: 20 },
+            fee_rate: 100,
+        };
+        let mut rng = rand::thread_rng();
+        let mut state = State::new();
+        let mut mempool = Mempool::new();
+        assert!(spend(&wallet, &state, &mempool, &config, &mut rng).is_none());
+
+        let coin = UTXO_input {
+            prev_tx_hash: [1u8; 32].into(),
+            index: 0,
+        };
+        state.utxos.insert(
+            coin.clone(),
+            UTXO_output {
+                receipient_address: me,
+                value: 1000,
+                script: None,
+            },
+        );
+        let tx = spend(&wallet, &state, &mempool, &config, &mut rng).unwrap();
+        assert_eq!(state.validate(&tx), Ok(()));
+        assert_eq!(tx.transcation.input, vec![coin]);
+        assert_eq!(tx.transcation.output.len(), 4);
+        let fee = state.fee(&tx);
+        assert!(fee * 1000 >= config.fee_rate * tx.size());
+        assert!(tx.transcation.output[..3].iter().all(|o| o.value >= 10 && o.value <= 20));
+        mempool.insert(&tx, fee).unwrap();
+
+        // the confirmed coin is spent in the mempool, the next one spends our unconfirmed outputs
+        let child = spend(&wallet, &state, &mempool, &config, &mut rng).unwrap();
+        let unconfirmed = mempool.unconfirmed_outputs(child.transcation.input.iter());
+        assert!(!unconfirmed.is_empty());
+        assert_eq!(unconfirmed.len(), child.transcation.input.len());
+        assert_eq!(state.validate_with(&child, &unconfirmed), Ok(()));
+
+        let values = ValueDistribution::Exponential { mean: 50 };
+        assert!((0..100).all(|_| values.sample(&mut rng) >= 1));
+    }
 }
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
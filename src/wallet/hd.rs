@@ -0,0 +1,163 @@
+use crate::types::address::Address;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+// Deterministic Ed25519 key derivation from a master seed, following SLIP-0010. Ed25519 only
+// supports hardened children, so every level of a path is hardened. Wallet addresses live at
+//
+//     m / 44' / 598' / account' / 0' / index'
+//
+// and are backed up by the seed alone: `Wallet::recover` derives them again and scans the chain.
+
+/// First index of the hardened range, every index is offset by it
+pub const HARDENED: u32 = 0x8000_0000;
+const PURPOSE: u32 = 44;
+const COIN_TYPE: u32 = 598;
+const MASTER_HMAC_KEY: &[u8] = b"ed25519 seed";
+const SEED_LEN: usize = 32;
+
+// PKCS#8 v2 layout used by ring for Ed25519 keys, the seed and public key go after each prefix
+const PKCS8_SEED_PREFIX: [u8; 16] = [
+    0x30, 0x53, 0x02, 0x01, 0x01, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+const PKCS8_PUBLIC_KEY_PREFIX: [u8; 5] = [0xa1, 0x23, 0x03, 0x21, 0x00];
+
+/// A fresh random master seed
+pub fn random_seed() -> Vec<u8> {
+    let mut seed = vec![0u8; SEED_LEN];
+    SystemRandom::new().fill(&mut seed).unwrap();
+    seed
+}
+
+/// A private key with its chain code
+#[derive(Clone)]
+pub struct ExtendedKey {
+    key: [u8; 32],
+    chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    fn from_hmac(key: &[u8], data: &[u8]) -> Self {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA512, key), data);
+        let mut res = ExtendedKey {
+            key: [0u8; 32],
+            chain_code: [0u8; 32],
+        };
+        res.key.copy_from_slice(&tag.as_ref()[..32]);
+        res.chain_code.copy_from_slice(&tag.as_ref()[32..]);
+        res
+    }
+
+    pub fn master(seed: &[u8]) -> Self {
+        Self::from_hmac(MASTER_HMAC_KEY, seed)
+    }
+
+    /// Hardened child `index`, the hardened offset is added here
+    pub fn child(&self, index: u32) -> Self {
+        let mut data = Vec::with_capacity(37);
+        data.push(0u8);
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        Self::from_hmac(&self.chain_code, &data)
+    }
+
+    pub fn derive(&self, path: &[u32]) -> Self {
+        path.iter().fold(self.clone(), |key, index| key.child(*index))
+    }
+
+    pub fn key_pair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&self.key).unwrap()
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from_public_key_bytes(self.key_pair().public_key().as_ref())
+    }
+
+    /// Encode the key the same way as `key_pair::random_pkcs8`, so it can go in the keystore
+    pub fn to_pkcs8(&self) -> Vec<u8> {
+        let mut res = PKCS8_SEED_PREFIX.to_vec();
+        res.extend_from_slice(&self.key);
+        res.extend_from_slice(&PKCS8_PUBLIC_KEY_PREFIX);
+        res.extend_from_slice(self.key_pair().public_key().as_ref());
+        res
+    }
+}
+
+/// Path of the address number `index` of `account`
+pub fn address_path(account: u32, index: u32) -> Vec<u32> {
+    vec![PURPOSE, COIN_TYPE, account, 0, index]
+}
+
+/// Format a path as `m/44'/598'/0'/0'/5'`
+pub fn format_path(path: &[u32]) -> String {
+    let mut res = "m".to_string();
+    for index in path {
+        res.push_str(&format!("/{}'", index));
+    }
+    res
+}
+
+/// Inverse of `format_path`, every level must be hardened
+pub fn parse_path(s: &str) -> Option<Vec<u32>> {
+    let mut parts = s.split('/');
+    if parts.next() != Some("m") {
+        return None;
+    }
+    parts
+        .map(|part| {
+            let index = part.strip_suffix('\'')?.parse::<u32>().ok()?;
+            if index >= HARDENED {
+                return None;
+            }
+            Some(index)
+        })
+        .collect()
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slip10_test_vector() {
+        // test vector 1 for ed25519 from SLIP-0010
+        let seed = hex!("000102030405060708090a0b0c0d0e0f");
+        let master = ExtendedKey::master(&seed);
+        assert_eq!(
+            master.key,
+            hex!("2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7")
+        );
+        assert_eq!(
+            master.chain_code,
+            hex!("90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb")
+        );
+        let child = master.derive(&[0]);
+        assert_eq!(
+            child.key,
+            hex!("68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3")
+        );
+        assert_eq!(
+            child.chain_code,
+            hex!("8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69")
+        );
+    }
+
+    #[test]
+    fn pkcs8_and_path() {
+        let key = ExtendedKey::master(&[7u8; 32]).derive(&address_path(0, 3));
+        let pair = Ed25519KeyPair::from_pkcs8(&key.to_pkcs8()).unwrap();
+        assert_eq!(
+            Address::from_public_key_bytes(pair.public_key().as_ref()),
+            key.address()
+        );
+        let path = address_path(1, 3);
+        assert_eq!(format_path(&path), "m/44'/598'/1'/0'/3'");
+        assert_eq!(parse_path(&format_path(&path)), Some(path));
+        assert_eq!(parse_path("m/44/0'"), None);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
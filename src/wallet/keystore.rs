@@ -0,0 +1,400 @@
+use crate::types::address::Address;
+use crate::types::multisig::MultisigPolicy;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+
+// On-disk format of the wallet keys. Every PKCS#8 document is encrypted with AES-256-GCM under a
+// key derived from the passphrase with PBKDF2-HMAC-SHA256, using a fresh random nonce per entry
+// and the address as associated data, so an entry cannot be moved to another address unnoticed.
+// Labels and addresses stay in clear text so a locked wallet can still show its balance.
+// The HD seed, if any, is sealed the same way with `SEED_AAD` as associated data. Multisig
+// policies only hold public keys and are stored in clear text.
+
+const VERSION: u32 = 1;
+const DEFAULT_ITERATIONS: u32 = 100_000;
+/// Keystores asking for fewer PBKDF2 iterations are refused, as the file is not authenticated
+pub(crate) const MIN_ITERATIONS: u32 = 10_000;
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const SEED_AAD: &[u8] = b"seed";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedKey {
+    label: String,
+    address: String,
+    sealed: Sealed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredPolicy {
+    label: String,
+    policy: MultisigPolicy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KeystoreFile {
+    version: u32,
+    iterations: u32,
+    salt: String,
+    // an empty plaintext sealed with the derived key, so even an empty keystore can check a
+    // passphrase
+    check: Sealed,
+    keys: Vec<EncryptedKey>,
+    // keystores written before HD derivation have neither of these
+    #[serde(default)]
+    seed: Option<Sealed>,
+    #[serde(default)]
+    next_index: u32,
+    #[serde(default)]
+    multisig: Vec<StoredPolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeystoreError {
+    Io(String),
+    Format(String),
+    WrongPassphrase,
+    Locked,
+}
+
+impl std::fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore io error: {}", e),
+            KeystoreError::Format(e) => write!(f, "malformed keystore: {}", e),
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase"),
+            KeystoreError::Locked => write!(f, "keystore is locked"),
+        }
+    }
+}
+
+impl From<std::io::Error> for KeystoreError {
+    fn from(e: std::io::Error) -> Self {
+        KeystoreError::Io(e.to_string())
+    }
+}
+
+/// A decrypted keystore entry
+pub struct KeyEntry {
+    pub label: String,
+    pub address: Address,
+    pub pkcs8: Vec<u8>,
+}
+
+/// An encrypted keystore file. The derived encryption key is only kept in memory while unlocked.
+pub struct Keystore {
+    path: PathBuf,
+    file: KeystoreFile,
+    key: Option<[u8; KEY_LEN]>,
+}
+
+fn check_iterations(iterations: u32) -> Result<(), KeystoreError> {
+    if iterations < MIN_ITERATIONS {
+        return Err(KeystoreError::Format(format!(
+            "{} key derivation iterations, at least {} are needed",
+            iterations, MIN_ITERATIONS
+        )));
+    }
+    Ok(())
+}
+
+fn derive_key(iterations: u32, salt: &[u8], passphrase: &str) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    let iterations = NonZeroU32::new(iterations).expect("iterations are checked on load");
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    key
+}
+
+fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> Sealed {
+    let rng = SystemRandom::new();
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce).unwrap();
+    let sealing_key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+    let mut in_out = plaintext.to_vec();
+    sealing_key
+        .seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut in_out,
+        )
+        .unwrap();
+    Sealed {
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(in_out),
+    }
+}
+
+fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &Sealed) -> Result<Vec<u8>, KeystoreError> {
+    let nonce = hex::decode(&sealed.nonce).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    if nonce.len() != NONCE_LEN {
+        return Err(KeystoreError::Format("bad nonce length".to_string()));
+    }
+    let mut nonce_bytes = [0u8; NONCE_LEN];
+    nonce_bytes.copy_from_slice(&nonce);
+    let mut in_out =
+        hex::decode(&sealed.ciphertext).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    let opening_key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+    let plaintext = opening_key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce_bytes),
+            Aad::from(aad),
+            &mut in_out,
+        )
+        .map_err(|_| KeystoreError::WrongPassphrase)?;
+    Ok(plaintext.to_vec())
+}
+
+fn parse_address(s: &str) -> Result<Address, KeystoreError> {
+    let bytes = hex::decode(s).map_err(|e| KeystoreError::Format(e.to_string()))?;
+    if bytes.len() != 20 {
+        return Err(KeystoreError::Format(format!("bad address {}", s)));
+    }
+    let mut buffer = [0u8; 20];
+    buffer.copy_from_slice(&bytes);
+    Ok(Address::new(buffer))
+}
+
+impl Keystore {
+    /// Create a new empty keystore at `path`, it is written immediately and left unlocked
+    pub fn create(path: &Path, passphrase: &str) -> Result<Self, KeystoreError> {
+        Self::create_with_iterations(path, passphrase, DEFAULT_ITERATIONS)
+    }
+
+    pub fn create_with_iterations(
+        path: &Path,
+        passphrase: &str,
+        iterations: u32,
+    ) -> Result<Self, KeystoreError> {
+        check_iterations(iterations)?;
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill(&mut salt).unwrap();
+        let key = derive_key(iterations, &salt, passphrase);
+        let keystore = Keystore {
+            path: path.to_path_buf(),
+            file: KeystoreFile {
+                version: VERSION,
+                iterations,
+                salt: hex::encode(salt),
+                check: seal(&key, &[], &[]),
+                keys: Vec::new(),
+                seed: None,
+                next_index: 0,
+                multisig: Vec::new(),
+            },
+            key: Some(key),
+        };
+        keystore.save()?;
+        Ok(keystore)
+    }
+
+    /// Load an existing keystore, it starts locked
+    pub fn open(path: &Path) -> Result<Self, KeystoreError> {
+        let content = fs::read_to_string(path)?;
+        let file: KeystoreFile =
+            serde_json::from_str(&content).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        if file.version != VERSION {
+            return Err(KeystoreError::Format(format!(
+                "unsupported version {}",
+                file.version
+            )));
+        }
+        check_iterations(file.iterations)?;
+        Ok(Keystore {
+            path: path.to_path_buf(),
+            file,
+            key: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Labels and addresses of every key, available while locked
+    pub fn addresses(&self) -> Result<Vec<(String, Address)>, KeystoreError> {
+        self.file
+            .keys
+            .iter()
+            .map(|k| Ok((k.label.clone(), parse_address(&k.address)?)))
+            .collect()
+    }
+
+    /// Check the passphrase and decrypt every key
+    pub fn unlock(&mut self, passphrase: &str) -> Result<Vec<KeyEntry>, KeystoreError> {
+        let salt = hex::decode(&self.file.salt).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        let key = derive_key(self.file.iterations, &salt, passphrase);
+        open(&key, &[], &self.file.check)?;
+        let mut entries = Vec::new();
+        for k in self.file.keys.iter() {
+            let address = parse_address(&k.address)?;
+            let pkcs8 = open(&key, address.as_ref(), &k.sealed)?;
+            entries.push(KeyEntry {
+                label: k.label.clone(),
+                address,
+                pkcs8,
+            });
+        }
+        self.key = Some(key);
+        Ok(entries)
+    }
+
+    pub fn has_seed(&self) -> bool {
+        self.file.seed.is_some()
+    }
+
+    /// Decrypt the HD seed, only possible while unlocked
+    pub fn seed(&self) -> Result<Option<Vec<u8>>, KeystoreError> {
+        let key = self.key.ok_or(KeystoreError::Locked)?;
+        self.file
+            .seed
+            .as_ref()
+            .map(|sealed| open(&key, SEED_AAD, sealed))
+            .transpose()
+    }
+
+    /// Encrypt the HD seed and write the keystore back to disk, only possible while unlocked
+    pub fn set_seed(&mut self, seed: &[u8]) -> Result<(), KeystoreError> {
+        let key = self.key.ok_or(KeystoreError::Locked)?;
+        self.file.seed = Some(seal(&key, SEED_AAD, seed));
+        self.save()
+    }
+
+    /// Index of the next HD address to derive
+    pub fn next_index(&self) -> u32 {
+        self.file.next_index
+    }
+
+    pub fn set_next_index(&mut self, next_index: u32) -> Result<(), KeystoreError> {
+        self.file.next_index = next_index;
+        self.save()
+    }
+
+    /// Labels and multisig policies the wallet takes part in
+    pub fn multisig_policies(&self) -> Vec<(String, MultisigPolicy)> {
+        self.file
+            .multisig
+            .iter()
+            .map(|p| (p.label.clone(), p.policy.clone()))
+            .collect()
+    }
+
+    /// Store a multisig policy, it holds no secret so this works while locked
+    pub fn add_multisig(&mut self, label: &str, policy: &MultisigPolicy) -> Result<(), KeystoreError> {
+        self.file.multisig.push(StoredPolicy {
+            label: label.to_string(),
+            policy: policy.clone(),
+        });
+        self.save()
+    }
+
+    /// Forget the derived encryption key
+    pub fn lock(&mut self) {
+        self.key = None;
+    }
+
+    /// Encrypt a new key and write the keystore back to disk, only possible while unlocked
+    pub fn add(&mut self, label: &str, address: &Address, pkcs8: &[u8]) -> Result<(), KeystoreError> {
+        let key = self.key.ok_or(KeystoreError::Locked)?;
+        self.file.keys.push(EncryptedKey {
+            label: label.to_string(),
+            address: hex::encode(address),
+            sealed: seal(&key, address.as_ref(), pkcs8),
+        });
+        self.save()
+    }
+
+    /// Write to a temporary file first so a crash never leaves a truncated keystore behind
+    fn save(&self) -> Result<(), KeystoreError> {
+        let content = serde_json::to_string_pretty(&self.file)
+            .map_err(|e| KeystoreError::Format(e.to_string()))?;
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!("{}-{}.json", name, std::process::id()));
+        path
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("keystore-round-trip");
+        let pkcs8 = key_pair::random_pkcs8();
+        let address: Address = [3u8; 20].into();
+        let mut keystore =
+            Keystore::create_with_iterations(&path, "hunter2", MIN_ITERATIONS).unwrap();
+        keystore.add("savings", &address, &pkcs8).unwrap();
+
+        let mut reopened = Keystore::open(&path).unwrap();
+        assert_eq!(
+            reopened.addresses().unwrap(),
+            vec![("savings".to_string(), address)]
+        );
+        assert_eq!(
+            reopened.add("other", &address, &pkcs8),
+            Err(KeystoreError::Locked)
+        );
+        assert_eq!(
+            reopened.unlock("wrong").err(),
+            Some(KeystoreError::WrongPassphrase)
+        );
+        let entries = reopened.unlock("hunter2").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].pkcs8, pkcs8);
+        assert_eq!(reopened.seed(), Ok(None));
+        reopened.set_seed(&[5u8; 32]).unwrap();
+        let mut reopened = Keystore::open(&path).unwrap();
+        assert!(reopened.has_seed());
+        assert_eq!(reopened.seed(), Err(KeystoreError::Locked));
+        reopened.unlock("hunter2").unwrap();
+        assert_eq!(reopened.seed(), Ok(Some(vec![5u8; 32])));
+
+        // a file edited to derive its key too cheaply is refused
+        let mut file: KeystoreFile =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        file.iterations = 0;
+        fs::write(&path, serde_json::to_string(&file).unwrap()).unwrap();
+        assert!(matches!(
+            Keystore::open(&path),
+            Err(KeystoreError::Format(_))
+        ));
+        assert!(Keystore::create_with_iterations(&path, "hunter2", 1).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
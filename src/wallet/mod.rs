@@ -0,0 +1,923 @@
+pub mod hd;
+pub mod keystore;
+
+use crate::types::address::Address;
+use crate::types::key_pair;
+use crate::types::multisig::MultisigPolicy;
+use crate::types::script::{Script, ScriptType};
+use crate::types::state::State;
+use crate::types::timelock::MAX_REPLACEABLE_SEQUENCE;
+use crate::types::transaction::{
+    signature_hash, Mempool, SignedTransaction, Transaction, UTXO_input, UTXO_output, Witness,
+};
+use hd::ExtendedKey;
+use keystore::{Keystore, KeystoreError};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::cmp;
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Number of consecutive unused addresses after which `Wallet::recover` stops looking
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+// every address is derived in the first account for now
+const ACCOUNT: u32 = 0;
+
+/// A key owned by the wallet, together with the address it controls
+struct Key {
+    label: String,
+    address: Address,
+    // PKCS#8 document of the key pair, dropped while the wallet is locked
+    pkcs8: Option<Vec<u8>>,
+}
+
+enum LockState {
+    /// unlocked until the given deadline, or for good
+    Unlocked(Option<Instant>),
+    Locked,
+}
+
+/// Keys owned by this node. The wallet does not keep its own copy of the UTXO set, it reads the
+/// outputs paying to its addresses from the chain state and the mempool every time.
+///
+/// A wallet backed by a keystore file starts locked: addresses and balances are available, but
+/// signing and exporting keys need the passphrase. An in-memory wallet is always unlocked.
+///
+/// New keys are derived from the HD seed (see `hd`), so the seed alone is enough to recover every
+/// address. Keystores created before HD derivation have no seed and keep using random keys.
+pub struct Wallet {
+    keys: Vec<Key>,
+    keystore: Option<Keystore>,
+    lock_state: LockState,
+    // HD seed, dropped while the wallet is locked like the keys
+    seed: Option<Vec<u8>>,
+    // index of the next HD address to derive
+    next_index: u32,
+    gap_limit: u32,
+    // multisig policies with their labels, public data kept in clear text in the keystore
+    multisig: Vec<(String, MultisigPolicy)>,
+}
+
+/// How `Wallet::create_payment` builds a transaction besides its inputs and outputs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PaymentOptions {
+    /// left to the miner
+    pub fee: u64,
+    /// height or time the transaction cannot be mined before, see `timelock::LockTime`
+    pub lock_time: u64,
+    /// opt in to replacing the transaction with one paying a higher fee while it is unconfirmed
+    pub replaceable: bool,
+}
+
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    /// value of the unspent outputs in the tip state
+    pub confirmed: u64,
+    /// confirmed balance after applying the transactions currently in the mempool
+    pub unconfirmed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    NoKeys,
+    ZeroAmount,
+    InsufficientFunds { available: u64, required: u64 },
+    Locked,
+    InvalidKey,
+    NoSeed,
+    InvalidPolicy,
+    UnknownAddress(Address),
+    Keystore(KeystoreError),
+}
+
+impl From<KeystoreError> for WalletError {
+    fn from(e: KeystoreError) -> Self {
+        WalletError::Keystore(e)
+    }
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WalletError::NoKeys => write!(f, "wallet has no keys"),
+            WalletError::ZeroAmount => write!(f, "amount must be positive"),
+            WalletError::InsufficientFunds {
+                available,
+                required,
+            } => write!(
+                f,
+                "insufficient funds: {} available, {} required",
+                available, required
+            ),
+            WalletError::Locked => write!(f, "wallet is locked"),
+            WalletError::InvalidKey => write!(f, "not a valid PKCS#8 Ed25519 key"),
+            WalletError::NoSeed => write!(f, "wallet has no HD seed"),
+            WalletError::InvalidPolicy => write!(f, "invalid multisig policy"),
+            WalletError::UnknownAddress(a) => write!(f, "address {} is not in the wallet", a),
+            WalletError::Keystore(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Outputs already spent by a transaction waiting in the mempool
+fn spent_in_mempool(mempool: &Mempool) -> HashSet<UTXO_input> {
+    mempool.spent.keys().cloned().collect()
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Wallet {
+    /// An in-memory wallet with a random seed, its keys are lost when the node stops
+    pub fn new() -> Self {
+        Self::from_seed(&hd::random_seed())
+    }
+
+    /// An in-memory wallet deriving its keys from `seed`
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Wallet {
+            keys: Vec::new(),
+            keystore: None,
+            lock_state: LockState::Unlocked(None),
+            seed: Some(seed.to_vec()),
+            next_index: 0,
+            gap_limit: DEFAULT_GAP_LIMIT,
+            multisig: Vec::new(),
+        }
+    }
+
+    /// Create a new keystore file at `path` with a random seed and a wallet backed by it
+    pub fn create(path: &Path, passphrase: &str) -> Result<Self, WalletError> {
+        Self::restore(path, passphrase, &hd::random_seed())
+    }
+
+    /// Create a new keystore file at `path` holding an existing seed, use `recover` afterwards to
+    /// find the addresses already in use
+    pub fn restore(path: &Path, passphrase: &str, seed: &[u8]) -> Result<Self, WalletError> {
+        let mut keystore = Keystore::create(path, passphrase)?;
+        keystore.set_seed(seed)?;
+        Self::with_keystore(keystore)
+    }
+
+    /// Load the wallet from an existing keystore file, the wallet starts locked
+    pub fn open(path: &Path) -> Result<Self, WalletError> {
+        Self::with_keystore(Keystore::open(path)?)
+    }
+
+    pub fn with_keystore(keystore: Keystore) -> Result<Self, WalletError> {
+        let keys = keystore
+            .addresses()?
+            .into_iter()
+            .map(|(label, address)| Key {
+                label,
+                address,
+                pkcs8: None,
+            })
+            .collect();
+        let lock_state = if keystore.is_unlocked() {
+            LockState::Unlocked(None)
+        } else {
+            LockState::Locked
+        };
+        let seed = if keystore.is_unlocked() {
+            keystore.seed()?
+        } else {
+            None
+        };
+        let keystore_multisig = keystore.multisig_policies();
+        Ok(Wallet {
+            keys,
+            next_index: keystore.next_index(),
+            keystore: Some(keystore),
+            lock_state,
+            seed,
+            gap_limit: DEFAULT_GAP_LIMIT,
+            multisig: keystore_multisig,
+        })
+    }
+
+    pub fn gap_limit(&self) -> u32 {
+        self.gap_limit
+    }
+
+    pub fn set_gap_limit(&mut self, gap_limit: u32) {
+        self.gap_limit = gap_limit;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        match self.lock_state {
+            LockState::Locked => true,
+            LockState::Unlocked(Some(deadline)) => Instant::now() >= deadline,
+            LockState::Unlocked(None) => false,
+        }
+    }
+
+    /// Decrypt the keys with `passphrase`, they are forgotten again after `timeout` if given
+    pub fn unlock(&mut self, passphrase: &str, timeout: Option<Duration>) -> Result<(), WalletError> {
+        let keystore = match self.keystore.as_mut() {
+            Some(keystore) => keystore,
+            None => return Ok(()),
+        };
+        for entry in keystore.unlock(passphrase)? {
+            if let Some(key) = self.keys.iter_mut().find(|k| k.address == entry.address) {
+                key.pkcs8 = Some(entry.pkcs8);
+            }
+        }
+        self.seed = keystore.seed()?;
+        self.lock_state = LockState::Unlocked(timeout.map(|t| Instant::now() + t));
+        Ok(())
+    }
+
+    /// Drop every decrypted key, an in-memory wallet cannot be locked since it has no other copy
+    pub fn lock(&mut self) {
+        let keystore = match self.keystore.as_mut() {
+            Some(keystore) => keystore,
+            None => return,
+        };
+        keystore.lock();
+        for key in self.keys.iter_mut() {
+            key.pkcs8 = None;
+        }
+        self.seed = None;
+        self.lock_state = LockState::Locked;
+    }
+
+    /// Actually drop the keys once the unlock timeout has passed
+    fn expire(&mut self) {
+        if self.is_locked() {
+            self.lock();
+        }
+    }
+
+    /// The seed is needed to derive keys, so a locked wallet with a seed cannot make new keys
+    fn unlocked_seed(&mut self) -> Result<Option<Vec<u8>>, WalletError> {
+        self.expire();
+        match (&self.seed, &self.keystore) {
+            (Some(seed), _) => Ok(Some(seed.clone())),
+            (None, Some(keystore)) if keystore.has_seed() => Err(WalletError::Locked),
+            (None, _) => Ok(None),
+        }
+    }
+
+    fn set_next_index(&mut self, next_index: u32) -> Result<(), WalletError> {
+        if let Some(keystore) = self.keystore.as_mut() {
+            keystore.set_next_index(next_index)?;
+        }
+        self.next_index = next_index;
+        Ok(())
+    }
+
+    /// Generate a new key and return the address it controls
+    pub fn generate_key(&mut self, label: &str) -> Result<Address, WalletError> {
+        let seed = match self.unlocked_seed()? {
+            Some(seed) => seed,
+            None => return self.import_key(label, &key_pair::random_pkcs8()),
+        };
+        let key =
+            ExtendedKey::master(&seed).derive(&hd::address_path(ACCOUNT, self.next_index));
+        let address = self.import_key(label, &key.to_pkcs8())?;
+        self.set_next_index(self.next_index + 1)?;
+        Ok(address)
+    }
+
+    /// Derive addresses from the seed and add those found in `used`, until `gap_limit` addresses
+    /// in a row are unused. Returns the addresses that were not in the wallet yet; their coins show
+    /// up in the balance right away since it is read from the chain state.
+    pub fn recover(&mut self, used: &HashSet<Address>) -> Result<Vec<Address>, WalletError> {
+        let seed = self.unlocked_seed()?.ok_or(WalletError::NoSeed)?;
+        let master = ExtendedKey::master(&seed);
+        let mut recovered = Vec::new();
+        let mut next_index = self.next_index;
+        let mut gap = 0;
+        let mut index = 0;
+        while gap < self.gap_limit {
+            let path = hd::address_path(ACCOUNT, index);
+            let key = master.derive(&path);
+            let address = key.address();
+            if used.contains(&address) {
+                if !self.is_mine(&address) {
+                    self.import_key(&hd::format_path(&path), &key.to_pkcs8())?;
+                    recovered.push(address);
+                }
+                next_index = cmp::max(next_index, index + 1);
+                gap = 0;
+            } else {
+                gap += 1;
+            }
+            index += 1;
+        }
+        if next_index != self.next_index {
+            self.set_next_index(next_index)?;
+        }
+        Ok(recovered)
+    }
+
+    /// The HD seed, only while unlocked
+    pub fn export_seed(&mut self) -> Result<Vec<u8>, WalletError> {
+        self.unlocked_seed()?.ok_or(WalletError::NoSeed)
+    }
+
+    /// Add a PKCS#8 encoded Ed25519 key, it is written to the keystore if there is one
+    pub fn import_key(&mut self, label: &str, pkcs8: &[u8]) -> Result<Address, WalletError> {
+        self.expire();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| WalletError::InvalidKey)?;
+        let address = Address::from_public_key_bytes(key_pair.public_key().as_ref());
+        if self.is_mine(&address) {
+            return Ok(address);
+        }
+        let locked = self.is_locked();
+        if let Some(keystore) = self.keystore.as_mut() {
+            if locked {
+                return Err(WalletError::Locked);
+            }
+            keystore.add(label, &address, pkcs8)?;
+        }
+        self.keys.push(Key {
+            label: label.to_string(),
+            address,
+            pkcs8: Some(pkcs8.to_vec()),
+        });
+        Ok(address)
+    }
+
+    /// The PKCS#8 document of one of our keys, only while unlocked
+    pub fn export_key(&self, address: &Address) -> Result<Vec<u8>, WalletError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.address == *address)
+            .ok_or(WalletError::UnknownAddress(*address))?;
+        if self.is_locked() {
+            return Err(WalletError::Locked);
+        }
+        key.pkcs8.clone().ok_or(WalletError::Locked)
+    }
+
+    fn key_pair(&self, key: &Key) -> Result<Ed25519KeyPair, WalletError> {
+        if self.is_locked() {
+            return Err(WalletError::Locked);
+        }
+        let pkcs8 = key.pkcs8.as_ref().ok_or(WalletError::Locked)?;
+        Ed25519KeyPair::from_pkcs8(pkcs8).map_err(|_| WalletError::InvalidKey)
+    }
+
+    /// Addresses of all keys, in the order they were added
+    pub fn addresses(&self) -> Vec<Address> {
+        self.keys.iter().map(|k| k.address).collect()
+    }
+
+    /// Labels and addresses of all keys, in the order they were added
+    pub fn labeled_addresses(&self) -> Vec<(String, Address)> {
+        self.keys
+            .iter()
+            .map(|k| (k.label.clone(), k.address))
+            .collect()
+    }
+
+    pub fn is_mine(&self, address: &Address) -> bool {
+        self.keys.iter().any(|k| k.address == *address)
+    }
+
+    /// Unspent outputs in `state` paying to any of our addresses
+    pub fn utxos(&self, state: &State) -> Vec<(UTXO_input, UTXO_output)> {
+        self.keys
+            .iter()
+            .flat_map(|k| state.utxos_of(&k.address))
+            .collect()
+    }
+
+    pub fn balance(&self, state: &State, mempool: &Mempool) -> Balance {
+        let spent = spent_in_mempool(mempool);
+        let mut balance = Balance::default();
+        for (input, output) in self.utxos(state) {
+            balance.confirmed += output.value;
+            if !spent.contains(&input) {
+                balance.unconfirmed += output.value;
+            }
+        }
+        // outputs of pending transactions paying to us
+        for (tx_hash, tx) in mempool.tx_map.iter() {
+            for (index, output) in tx.transcation.output.iter().enumerate() {
+                let input = UTXO_input {
+                    prev_tx_hash: *tx_hash,
+                    index: index as u8,
+                };
+                if self.is_mine(&output.receipient_address) && !spent.contains(&input) {
+                    balance.unconfirmed += output.value;
+                }
+            }
+        }
+        balance
+    }
+
+    /// Build and sign a transaction paying `amount` to `to`, leaving `fee` to the miner.
+    pub fn create_transaction(
+        &self,
+        state: &State,
+        mempool: &Mempool,
+        to: Address,
+        amount: u64,
+        fee: u64,
+    ) -> Result<SignedTransaction, WalletError> {
+        let output = UTXO_output {
+            receipient_address: to,
+            value: amount,
+            script: None,
+        };
+        let options = PaymentOptions {
+            fee,
+            ..Default::default()
+        };
+        self.create_payment(state, mempool, output, &options)
+    }
+
+    /// Build and sign a transaction creating `output`.
+    ///
+    /// Inputs may come from any of our addresses since each one is signed separately, the change
+    /// goes back to the address of the first one. Coins are selected largest first and outputs
+    /// already spent in the mempool are skipped.
+    ///
+    /// A time-locked payment cannot be mined before its lock time, nodes hold it aside until
+    /// then. A replaceable one signals it in the sequence of every input.
+    pub fn create_payment(
+        &self,
+        state: &State,
+        mempool: &Mempool,
+        output: UTXO_output,
+        options: &PaymentOptions,
+    ) -> Result<SignedTransaction, WalletError> {
+        let fee = options.fee;
+        if self.keys.is_empty() {
+            return Err(WalletError::NoKeys);
+        }
+        if output.value == 0 {
+            return Err(WalletError::ZeroAmount);
+        }
+        if self.is_locked() {
+            return Err(WalletError::Locked);
+        }
+        let required = output.value.saturating_add(fee);
+        let (inputs, total) = select_coins(self.utxos(state), mempool, required);
+        if total < required {
+            return Err(WalletError::InsufficientFunds {
+                available: total,
+                required,
+            });
+        }
+        let change = UTXO_output {
+            receipient_address: state.utxos[&inputs[0]].receipient_address,
+            value: total - required,
+            script: None,
+        };
+        let mut transaction = payment(output, change, inputs);
+        transaction.lock_time = options.lock_time;
+        if options.replaceable {
+            transaction.sequences = vec![MAX_REPLACEABLE_SEQUENCE; transaction.input.len()];
+        }
+        let mut tx = SignedTransaction {
+            witnesses: vec![Witness::default(); transaction.input.len()],
+            transcation: transaction,
+        };
+        self.sign_transaction(state, &mut tx)?;
+        Ok(tx)
+    }
+
+    /// Public key of one of our addresses, only while unlocked since only the PKCS#8 document
+    /// holds it
+    pub fn public_key(&self, address: &Address) -> Result<Vec<u8>, WalletError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.address == *address)
+            .ok_or(WalletError::UnknownAddress(*address))?;
+        Ok(self.key_pair(key)?.public_key().as_ref().to_vec())
+    }
+
+    /// Keep track of a multisig policy, usually one that some of our keys are part of, and return
+    /// the address its outputs are paid to
+    pub fn add_multisig(
+        &mut self,
+        label: &str,
+        policy: MultisigPolicy,
+    ) -> Result<Address, WalletError> {
+        if !policy.is_valid() {
+            return Err(WalletError::InvalidPolicy);
+        }
+        let address = policy.address();
+        if self.multisig.iter().any(|(_, p)| *p == policy) {
+            return Ok(address);
+        }
+        if let Some(keystore) = self.keystore.as_mut() {
+            keystore.add_multisig(label, &policy)?;
+        }
+        self.multisig.push((label.to_string(), policy));
+        Ok(address)
+    }
+
+    /// Labels and addresses of the multisig policies, in the order they were added
+    pub fn multisig_addresses(&self) -> Vec<(String, Address)> {
+        self.multisig
+            .iter()
+            .map(|(label, policy)| (label.clone(), policy.address()))
+            .collect()
+    }
+
+    /// Build a transaction spending from the multisig address `from`, with the change going back
+    /// to it, and add the signatures of our keys. Other signers complete it with `sign_transaction`.
+    pub fn create_multisig_transaction(
+        &self,
+        state: &State,
+        mempool: &Mempool,
+        from: Address,
+        to: Address,
+        amount: u64,
+        fee: u64,
+    ) -> Result<SignedTransaction, WalletError> {
+        if amount == 0 {
+            return Err(WalletError::ZeroAmount);
+        }
+        let policy = self
+            .multisig
+            .iter()
+            .map(|(_, policy)| policy)
+            .find(|policy| policy.address() == from)
+            .ok_or(WalletError::UnknownAddress(from))?;
+        let required = amount.saturating_add(fee);
+        let (inputs, total) = select_coins(state.utxos_of(&from), mempool, required);
+        if total < required {
+            return Err(WalletError::InsufficientFunds {
+                available: total,
+                required,
+            });
+        }
+        let to = UTXO_output {
+            receipient_address: to,
+            value: amount,
+            script: None,
+        };
+        let change = UTXO_output {
+            receipient_address: from,
+            value: total - required,
+            script: Some(Script::multisig(policy)),
+        };
+        let transaction = payment(to, change, inputs);
+        let mut tx = SignedTransaction {
+            witnesses: vec![Witness::default(); transaction.input.len()],
+            transcation: transaction,
+        };
+        self.sign_transaction(state, &mut tx)?;
+        Ok(tx)
+    }
+
+    /// Add the signatures of our keys to every input of `tx` we can sign: inputs paid to one of
+    /// our addresses and multisig inputs whose policy includes our keys. Inputs of other owners
+    /// are left for them, which is how multi-party transactions are put together. Returns how
+    /// many signatures were added.
+    pub fn sign_transaction(
+        &self,
+        state: &State,
+        tx: &mut SignedTransaction,
+    ) -> Result<usize, WalletError> {
+        if self.is_locked() {
+            return Err(WalletError::Locked);
+        }
+        let key_pairs = self
+            .keys
+            .iter()
+            .map(|k| Ok((k.address, self.key_pair(k)?)))
+            .collect::<Result<Vec<(Address, Ed25519KeyPair)>, WalletError>>()?;
+        tx.witnesses
+            .resize(tx.transcation.input.len(), Witness::default());
+        let mut added = 0;
+        for (i, witness) in tx.witnesses.iter_mut().enumerate() {
+            let spent = match state.utxos.get(&tx.transcation.input[i]) {
+                Some(spent) => spent,
+                None => continue,
+            };
+            let digest = signature_hash(&tx.transcation, i, spent);
+            for (address, key_pair) in key_pairs.iter() {
+                let public_key = key_pair.public_key().as_ref();
+                let can_sign = match spent.script_type() {
+                    ScriptType::PayToAddress(a) => a == *address,
+                    ScriptType::Multisig(policy) => {
+                        policy.public_keys.iter().any(|k| k.as_slice() == public_key)
+                    }
+                    // any script mentioning one of our keys, the caller adds the rest of the
+                    // witness stack
+                    ScriptType::NonStandard => {
+                        let script = spent.locking_script();
+                        script.pushes(public_key) || script.pushes(address.as_ref())
+                    }
+                };
+                if can_sign && witness.sign(&digest, key_pair) {
+                    added += 1;
+                }
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Coins not yet spent in the mempool, largest first, until `required` is covered. Returns the
+/// inputs and their total value, which is short if funds are lacking.
+fn select_coins(
+    mut coins: Vec<(UTXO_input, UTXO_output)>,
+    mempool: &Mempool,
+    required: u64,
+) -> (Vec<UTXO_input>, u64) {
+    let spent = spent_in_mempool(mempool);
+    coins.retain(|(input, _)| !spent.contains(input));
+    coins.sort_by_key(|(_, output)| cmp::Reverse(output.value));
+
+    let mut inputs = Vec::new();
+    let mut total = 0u64;
+    for (input, output) in coins {
+        if total >= required {
+            break;
+        }
+        total = total.saturating_add(output.value);
+        inputs.push(input);
+    }
+    (inputs, total)
+}
+
+/// Unsigned transaction creating `to`, with the rest of the inputs going back to `change`
+fn payment(to: UTXO_output, change: UTXO_output, inputs: Vec<UTXO_input>) -> Transaction {
+    let sender = change.receipient_address;
+    let receiver = to.receipient_address;
+    // legacy field from the account model, the outputs carry the real value
+    let value = cmp::min(to.value, u32::MAX as u64) as u32;
+    let mut output = vec![to];
+    if change.value > 0 {
+        output.push(change);
+    }
+    Transaction {
+        sender,
+        receiver,
+        value,
+        input: inputs,
+        output,
+        ..Default::default()
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. BEFORE TEST
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::Hashable;
+    use crate::types::state::TransactionError;
+    use crate::types::timelock::LockTime;
+    use crate::types::transaction::MAX_BLOCK_TRANSACTIONS;
+
+    fn fund(state: &mut State, address: Address, seed: u8, value: u64) {
+        state.utxos.insert(
+            UTXO_input {
+                prev_tx_hash: [seed; 32].into(),
+                index: 0,
+            },
+            UTXO_output {
+                receipient_address: address,
+                value,
+                script: None,
+            },
+        );
+    }
+
+    #[test]
+    fn send_with_change() {
+        let mut wallet = Wallet::new();
+        let me = wallet.generate_key("default").unwrap();
+        let to: Address = [9u8; 20].into();
+        let mut state = State::new();
+        fund(&mut state, me, 1, 30);
+        fund(&mut state, me, 2, 50);
+        let mut mempool = Mempool::new();
+        assert_eq!(
+            wallet.balance(&state, &mempool),
+            Balance {
+                confirmed: 80,
+                unconfirmed: 80
+            }
+        );
+
+        let tx = wallet
+            .create_transaction(&state, &mempool, to, 60, 5)
+            .unwrap();
+        assert_eq!(state.validate(&tx), Ok(()));
+        assert_eq!(tx.transcation.input.len(), 2);
+        assert_eq!(tx.transcation.output[0].value, 60);
+        assert_eq!(tx.transcation.output[1].receipient_address, me);
+        assert_eq!(tx.transcation.output[1].value, 15);
+
+        mempool.insert(&tx, state.fee(&tx)).unwrap();
+        assert_eq!(
+            wallet.balance(&state, &mempool),
+            Balance {
+                confirmed: 80,
+                unconfirmed: 15
+            }
+        );
+        // both coins are pending, nothing left to spend until the change confirms
+        assert_eq!(
+            wallet
+                .create_transaction(&state, &mempool, to, 1, 0)
+                .unwrap_err(),
+            WalletError::InsufficientFunds {
+                available: 0,
+                required: 1
+            }
+        );
+    }
+
+    #[test]
+    fn time_locked_payment() {
+        let mut wallet = Wallet::new();
+        let me = wallet.generate_key("default").unwrap();
+        let mut state = State::new();
+        fund(&mut state, me, 1, 30);
+        state.height = 8;
+        let output = UTXO_output {
+            receipient_address: [9u8; 20].into(),
+            value: 20,
+            script: None,
+        };
+        let options = PaymentOptions {
+            fee: 1,
+            lock_time: 10,
+            ..Default::default()
+        };
+        let tx = wallet
+            .create_payment(&state, &Mempool::new(), output, &options)
+            .unwrap();
+        assert_eq!(
+            state.validate(&tx),
+            Err(TransactionError::NonFinal(LockTime::Height(10)))
+        );
+
+        // it is held, and only enters the mempool to be mined in the block after height 10
+        let mut mempool = Mempool::new();
+        assert!(mempool.hold(&tx));
+        assert!(mempool.release_final(&state).is_empty());
+        assert!(mempool.tx_map.is_empty());
+        assert_eq!(mempool.held.len(), 1);
+        state.height = 10;
+        assert_eq!(state.validate(&tx), Ok(()));
+        assert_eq!(mempool.release_final(&state), vec![(tx.hash(), 1, vec![])]);
+        assert!(mempool.held.is_empty());
+        assert_eq!(mempool.get_headtransactions(&state, MAX_BLOCK_TRANSACTIONS).len(), 1);
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let mut wallet = Wallet::new();
+        let me = wallet.generate_key("default").unwrap();
+        let mut state = State::new();
+        fund(&mut state, me, 1, 10);
+        let mempool = Mempool::new();
+        assert_eq!(
+            wallet
+                .create_transaction(&state, &mempool, me, 10, 1)
+                .unwrap_err(),
+            WalletError::InsufficientFunds {
+                available: 10,
+                required: 11
+            }
+        );
+    }
+
+    #[test]
+    fn lock_unlock_and_reload() {
+        let mut path = std::env::temp_dir();
+        path.push(format!("wallet-lock-{}.json", std::process::id()));
+        let keystore =
+            Keystore::create_with_iterations(&path, "pass", keystore::MIN_ITERATIONS).unwrap();
+        let mut wallet = Wallet::with_keystore(keystore).unwrap();
+        let me = wallet.generate_key("default").unwrap();
+        let pkcs8 = wallet.export_key(&me).unwrap();
+        let mut state = State::new();
+        fund(&mut state, me, 1, 10);
+        let mempool = Mempool::new();
+
+        // an expired unlock behaves like a locked wallet
+        wallet.unlock("pass", Some(Duration::from_secs(0))).unwrap();
+        assert!(wallet.is_locked());
+        assert_eq!(
+            wallet
+                .create_transaction(&state, &mempool, me, 1, 0)
+                .unwrap_err(),
+            WalletError::Locked
+        );
+
+        // keys survive a restart and are readable again after unlocking
+        let mut reloaded = Wallet::open(&path).unwrap();
+        assert_eq!(
+            reloaded.labeled_addresses(),
+            vec![("default".to_string(), me)]
+        );
+        assert_eq!(reloaded.balance(&state, &mempool).confirmed, 10);
+        assert_eq!(reloaded.export_key(&me).unwrap_err(), WalletError::Locked);
+        assert_eq!(
+            reloaded.unlock("wrong", None).unwrap_err(),
+            WalletError::Keystore(KeystoreError::WrongPassphrase)
+        );
+        reloaded.unlock("pass", None).unwrap();
+        assert_eq!(reloaded.export_key(&me).unwrap(), pkcs8);
+        assert!(reloaded
+            .create_transaction(&state, &mempool, me, 1, 0)
+            .is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn two_of_three_multisig() {
+        let mut wallets: Vec<Wallet> = (0..3).map(|_| Wallet::new()).collect();
+        let public_keys: Vec<Vec<u8>> = wallets
+            .iter_mut()
+            .map(|w| {
+                let address = w.generate_key("default").unwrap();
+                w.public_key(&address).unwrap()
+            })
+            .collect();
+        let policy = MultisigPolicy::new(2, public_keys);
+        let shared = wallets[0].add_multisig("shared", policy.clone()).unwrap();
+        assert_eq!(
+            wallets[0].add_multisig("bad", MultisigPolicy::new(3, vec![])),
+            Err(WalletError::InvalidPolicy)
+        );
+
+        let mut state = State::new();
+        state.utxos.insert(
+            UTXO_input {
+                prev_tx_hash: [1u8; 32].into(),
+                index: 0,
+            },
+            UTXO_output {
+                receipient_address: shared,
+                value: 50,
+                script: Some(Script::multisig(&policy)),
+            },
+        );
+        let to: Address = [9u8; 20].into();
+        let mut tx = wallets[0]
+            .create_multisig_transaction(&state, &Mempool::new(), shared, to, 30, 1)
+            .unwrap();
+        assert_eq!(
+            tx.transcation.output[1].script_type(),
+            ScriptType::Multisig(policy)
+        );
+        assert_eq!(
+            state.validate(&tx),
+            Err(TransactionError::NotEnoughSignatures {
+                input: tx.transcation.input[0].clone(),
+                required: 2,
+                valid: 1
+            })
+        );
+        // a second signer completes the transaction, signing again adds nothing
+        assert_eq!(wallets[2].sign_transaction(&state, &mut tx), Ok(1));
+        assert_eq!(wallets[2].sign_transaction(&state, &mut tx), Ok(0));
+        assert_eq!(state.validate(&tx), Ok(()));
+    }
+
+    #[test]
+    fn recover_from_seed() {
+        let seed = [42u8; 32];
+        let mut original = Wallet::from_seed(&seed);
+        let addresses: Vec<Address> = (0..4)
+            .map(|_| original.generate_key("").unwrap())
+            .collect();
+        let mut state = State::new();
+        fund(&mut state, addresses[0], 1, 10);
+        fund(&mut state, addresses[2], 2, 20);
+        let used: HashSet<Address> = state
+            .utxos
+            .values()
+            .map(|output| output.receipient_address)
+            .collect();
+        let mempool = Mempool::new();
+
+        // the gap between the two used addresses is too large
+        let mut short = Wallet::from_seed(&seed);
+        short.set_gap_limit(1);
+        assert_eq!(short.recover(&used).unwrap(), vec![addresses[0]]);
+
+        let mut restored = Wallet::from_seed(&seed);
+        restored.set_gap_limit(2);
+        assert_eq!(
+            restored.recover(&used).unwrap(),
+            vec![addresses[0], addresses[2]]
+        );
+        assert_eq!(restored.balance(&state, &mempool).confirmed, 30);
+        // a second scan finds nothing new, and derivation continues after the last used address
+        assert!(restored.recover(&used).unwrap().is_empty());
+        assert_eq!(restored.generate_key("").unwrap(), addresses[3]);
+    }
+}
+
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST